@@ -0,0 +1,413 @@
+
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::globals::GLOBALS;
+
+
+pub enum CommandOutcome {
+	Done,
+	ClearScreen,
+	Quit,
+}
+
+// runs one command line, as typed into the REPL or sent over the control
+// socket, writing its output to out. interactive is set when the command
+// comes from the REPL, which owns the terminal
+pub fn run_command(line: &str, out: &mut dyn Write, interactive: bool) -> Result<CommandOutcome> {
+	let tokens = crate::CommandTokenIter::new(line).collect::<Vec<std::borrow::Cow<str>>>();
+	let mut token_iterator = tokens.iter().map(|token| token.as_ref());
+
+
+	match token_iterator.next() {
+		Some("quit") => {
+			if let Some(_) = token_iterator.next() {
+				writeln!(out, "\rError: quit does not take any arguments")?;
+			}else {
+				return Ok(CommandOutcome::Quit);
+			}
+		},
+		Some("clear") => {
+			if let Some(_) = token_iterator.next() {
+				writeln!(out, "\rError: clear does not take any arguments")?;
+			}else {
+				return Ok(CommandOutcome::ClearScreen);
+			}
+		},
+		Some("help") => {
+			if let Some(_) = token_iterator.next() {
+				writeln!(out, "\rWARN: help does not currently process arguments")?;
+			}
+			writeln!(out, "\
+\rLocalShare - sharing files locally
+\r----------------------------------
+\r
+\rquit / Ctrl-C               - quit the program / clear line then quit program
+\rCtrl-W                      - clear line
+\rshow [files/playlists/folders/peers]
+\r                            - show the currently hosted files, playlists and folders,
+\r                              or the peers and how they answer
+\radd <path> [--include <glob>] [--exclude <glob>]
+\r                            - add a file to the host list, or share a whole directory
+\r                              tree (optionally only the files matching the globs)
+\radd <file_path> --as <name> - add a file, hosted under name instead of its path
+\rremove <name> ...           - stop hosting files or folders
+\rrename <name> <new_name>    - change the name a file or folder is hosted under
+\rremove_playlist <name> ...  - remove playlists
+\rremove_peer <ip>[:port]/<name> ...
+\r                            - remove peers, by address or by the name they announce
+\radd_playlist <playlist_dir> - add playlist_dir to playlists
+\radd_peer <ip>[:port]/<name> - add a peer, the port defaults to this instance's port,
+\r                              or confirm a discovered peer so its files are listed
+\rdownload_playlist <name> <playlist url> [audio format]
+\r                            - download a playlist with default audio format being flac\
+			")?;
+		},
+		Some("show") => {
+			match token_iterator.next() {
+				Some("files") => {
+					if let Some(_) = token_iterator.next() { writeln!(out, "\rToo many args to \"show\"")?; }
+					else {
+						for entry in GLOBALS.read_file_entries().iter() {
+							writeln!(out, "\r-> file {}", entry.name)?;
+						}
+					}
+				},
+				Some("playlists") => {
+					if let Some(_) = token_iterator.next() { writeln!(out, "\rToo many args to \"show\"")?; }
+					else {
+						for playlist in GLOBALS.read_playlists().iter() {
+							writeln!(out, "\r-> playlist {}", playlist.name)?;
+						}
+					}
+				},
+				Some("folders") => {
+					if let Some(_) = token_iterator.next() { writeln!(out, "\rToo many args to \"show\"")?; }
+					else {
+						for directory in GLOBALS.read_shared_directories().iter() {
+							writeln!(out, "\r-> folder {} ({})", directory.name, directory.root.display())?;
+						}
+					}
+				},
+				Some("peers") => {
+					if let Some(_) = token_iterator.next() { writeln!(out, "\rToo many args to \"show\"")?; }
+					else {
+						for peer in GLOBALS.read_peers().iter() {
+							writeln!(out, "\r-> peer {}", describe_peer(peer))?;
+						}
+					}
+				},
+				Some(arg) => {
+					writeln!(out, "\rError: unrecognized argument to \"show\": {}", arg)?;
+				},
+				None => {
+					for entry in GLOBALS.read_file_entries().iter() {
+						writeln!(out, "\r-> file     {}", entry.name)?;
+					}
+					for playlist in GLOBALS.read_playlists().iter() {
+						writeln!(out, "\r-> playlist {}", playlist.name)?;
+					}
+					for directory in GLOBALS.read_shared_directories().iter() {
+						writeln!(out, "\r-> folder   {} ({})", directory.name, directory.root.display())?;
+					}
+					for peer in GLOBALS.read_peers().iter() {
+						match (peer.origin, &peer.name) {
+							(crate::globals::PeerOrigin::Discovered, Some(name)) => {
+								writeln!(out, "\r-> peer     {} ({}, discovered)", peer.addr, name)?;
+							},
+							_ => writeln!(out, "\r-> peer     {}", peer.addr)?,
+						}
+					}
+				}
+			}
+		},
+		Some("add_playlist") => {
+			match token_iterator.next() {
+				Some(playlist_dir) => {
+					if let Some(_) = token_iterator.next() {
+						writeln!(out, "\rError: too many argument to add_playlist")?;
+						return Ok(CommandOutcome::Done);
+					}
+					if std::path::Path::is_dir(playlist_dir.as_ref()) {
+						writeln!(out, "\rINFO: adding playlist")?;
+						if let Err(e) = GLOBALS.push_playlist_directory(playlist_dir) {
+							writeln!(out, "\rError: failed to add playlist {} -> {}", playlist_dir, e)?;
+						}
+					}
+					else if std::path::Path::exists(playlist_dir.as_ref()) {
+						writeln!(out, "\rError: playlist {} is not a directory", playlist_dir)?;
+					} else {
+						writeln!(out, "\rError: directory {} does not exist", playlist_dir)?;
+					}
+				},
+				None => {
+					writeln!(out, "\rError: add_playlist requires an argument (playlist directory)")?;
+				}
+			}
+		},
+		Some("add") => {
+			let mut paths = Vec::<&str>::new();
+			let mut include = Vec::<crate::glob::GlobPattern>::new();
+			let mut exclude = Vec::<crate::glob::GlobPattern>::new();
+			let mut alias: Option<&str> = None;
+			while let Some(token) = token_iterator.next() {
+				match token {
+					"--as" => match token_iterator.next() {
+						Some(name) => alias = Some(name),
+						None => {
+							writeln!(out, "\rError: --as expects a name")?;
+							return Ok(CommandOutcome::Done);
+						}
+					},
+					"--include" | "--exclude" => {
+						let pattern = match token_iterator.next().map(crate::glob::GlobPattern::new) {
+							Some(Ok(pattern)) => pattern,
+							Some(Err(e)) => {
+								writeln!(out, "\rError: {}", e)?;
+								return Ok(CommandOutcome::Done);
+							},
+							None => {
+								writeln!(out, "\rError: {} expects a glob pattern", token)?;
+								return Ok(CommandOutcome::Done);
+							}
+						};
+						if token == "--include" { include.push(pattern); } else { exclude.push(pattern); }
+					},
+					path => paths.push(path),
+				}
+			}
+			if alias.is_some() && paths.len() != 1 {
+				writeln!(out, "\rError: --as names a single file")?;
+				return Ok(CommandOutcome::Done);
+			}
+
+			for filename in paths {
+				if std::path::Path::is_file(filename.as_ref()) {
+					let name = alias.unwrap_or(filename);
+					writeln!(out, "\rINFO: adding file {} to database as {}", filename, name)?;
+					if let Err(e) = GLOBALS.push_file_entry( name, filename ) {
+						writeln!(out, "\rError: failed to add file {} | {}", filename, e)?;
+					}
+				}else if std::path::Path::is_dir(filename.as_ref()) {
+					let directory = match crate::globals::SharedDirectory::new(filename, include.clone(), exclude.clone()) {
+						Ok(directory) => directory,
+						Err(e) => {
+							writeln!(out, "\rError: {}", e)?;
+							continue;
+						}
+					};
+					writeln!(out, "\rINFO: sharing directory {} as {} ({} files)",
+						filename, directory.name, directory.count_files())?;
+					if let Err(e) = GLOBALS.push_shared_directory(directory) {
+						writeln!(out, "\rError: {}", e)?;
+					}
+				}
+				else {
+					writeln!(out, "\rError: unable to locate file {}", filename)?;
+				}
+			}
+		},
+		Some("remove") => {
+			let mut any_names = false;
+			for name in token_iterator {
+				any_names = true;
+				if GLOBALS.remove_entry(name) {
+					writeln!(out, "\rINFO: no longer hosting {}", name)?;
+				}else {
+					writeln!(out, "\rError: nothing hosted as {}", name)?;
+				}
+			}
+			if !any_names { writeln!(out, "\rError: remove expects the names of hosted files or folders")?; }
+		},
+		Some("rename") => {
+			let (old_name, new_name) = match (token_iterator.next(), token_iterator.next(), token_iterator.next()) {
+				(Some(old_name), Some(new_name), None) => (old_name, new_name),
+				_ => {
+					writeln!(out, "\rError: rename expects the current name and the new name")?;
+					return Ok(CommandOutcome::Done);
+				}
+			};
+			match GLOBALS.rename_entry(old_name, new_name) {
+				Ok(true) => writeln!(out, "\rINFO: renamed {} to {}", old_name, new_name)?,
+				Ok(false) => writeln!(out, "\rError: nothing hosted as {}", old_name)?,
+				Err(e) => writeln!(out, "\rError: {}", e)?,
+			}
+		},
+		Some("remove_playlist") => {
+			let mut any_names = false;
+			for name in token_iterator {
+				any_names = true;
+				if GLOBALS.remove_playlist(name) {
+					writeln!(out, "\rINFO: removed playlist {}", name)?;
+				}else {
+					writeln!(out, "\rError: no playlist named {}", name)?;
+				}
+			}
+			if !any_names { writeln!(out, "\rError: remove_playlist expects playlist names")?; }
+		},
+		Some("remove_peer") => {
+			let mut any_peers = false;
+			for peer in token_iterator {
+				any_peers = true;
+				let addr = match GLOBALS.find_peer(peer) {
+					Ok(addr) => addr,
+					Err(e) => {
+						writeln!(out, "\rError: failed to parse address of peer -> {}", e)?;
+						continue;
+					}
+				};
+				match GLOBALS.remove_peer(addr) {
+					Some(removed) if removed.origin == crate::globals::PeerOrigin::Discovered => {
+						writeln!(out, "\rINFO: removed peer {}, it comes back the next time it is discovered", addr)?;
+					},
+					Some(_) => writeln!(out, "\rINFO: removed peer {}", addr)?,
+					None => writeln!(out, "\rError: {} is not a peer", addr)?,
+				}
+			}
+			if !any_peers { writeln!(out, "\rError: remove_peer expects peer addresses")?; }
+		},
+		Some("add_peer") => {
+			let peer_addr = match token_iterator.next() {
+				Some(addr_string) => {
+					if let Some(_) = token_iterator.next() {
+						writeln!(out, "\rError: too many arguments to add_peer")?;
+						return Ok(CommandOutcome::Done);
+					}
+					// NOTE a discovered peer can be confirmed by its name
+					match GLOBALS.find_peer(addr_string) {
+						Ok(addr) => addr,
+						Err(e) => {
+							writeln!(out, "\rError: failed to parse address of peer -> {}", e)?;
+							return Ok(CommandOutcome::Done);
+						}
+					}
+				},
+				None => {
+					writeln!(out, "\rError: add_peer expect an address")?;
+					return Ok(CommandOutcome::Done);
+				}
+			};
+			GLOBALS.push_peer(peer_addr);
+		}
+		Some("download_playlist") => {
+			let playlist_name = match token_iterator.next() {
+				Some(name) => name,
+				None => {
+					writeln!(out, "\rError: \"download_playlist\" requires a name argument and then a url argument")?;
+					return Ok(CommandOutcome::Done);
+				}
+			};
+			let playlist_url = match token_iterator.next() {
+				Some(token) => token,
+				None => {
+					writeln!(out, "\rError: \"download_playlist\" requires a name argument and then a url argument")?;
+					return Ok(CommandOutcome::Done);
+				}
+			};
+			let audio_format = token_iterator.next().unwrap_or("flac");
+
+			let mut playlist_dirname = match std::path::absolute(&GLOBALS.config.download_dir) {
+				Ok(dir) => dir,
+				Err(e) => {
+					writeln!(out, "\rError: unable to resolve playlist download directory -> {}", e)?;
+					return Ok(CommandOutcome::Done);
+				}
+			};
+			match std::fs::exists(&playlist_dirname) {
+				Ok(true) => {},
+				_ => {
+					if let Err(e) = std::fs::DirBuilder::new().recursive(true).create(&playlist_dirname) {
+						writeln!(out, "\rError: failed to create playlists directory -> {}", e)?;
+						return Ok(CommandOutcome::Done);
+					}
+				}
+			};
+			playlist_dirname.push(playlist_name);
+
+			// if let Ok(true) = std::fs::exists(playlist_dirname) {}
+			match std::fs::exists(&playlist_dirname) {
+				Ok(true) => {},
+				_ => {
+					if let Err(e) = std::fs::DirBuilder::new().create(&playlist_dirname) {
+						writeln!(out, "\rError: failed to create directory (non-recursive) from playlist name -> {}", e)?;
+						return Ok(CommandOutcome::Done);
+					}
+				}
+			};
+
+			let mut command = std::process::Command::new("yt-dlp");
+			command.arg(playlist_url)
+				.arg("-x")
+				.arg("--audio-format")
+				.arg(audio_format)
+				.arg("--audio-quality")
+				.arg("0"); // in ffmpeg 0 -> highest quality, 10 -> lowest
+
+			command.current_dir(&playlist_dirname);
+			// command.stdout(std::process::Stdio::inherit());
+			// command.stderr(std::process::Stdio::inherit())
+
+			let mut subproc = match command.spawn() {
+				Ok(child) => child,
+				Err(e) => {
+					writeln!(out, "\rError: failed to spawn yt-dlp downloader backend -> {}", e)?;
+					return Ok(CommandOutcome::Done);
+				}
+			};
+
+			if interactive { crossterm::terminal::disable_raw_mode().unwrap(); }
+
+			let exit_status = match subproc.wait() {
+				Ok(status) => status,
+				Err(e) => {
+					writeln!(out, "\rError: failed to wait for command -> {}", e)?;
+					return Ok(CommandOutcome::Done);
+				}
+			};
+
+			if interactive { crossterm::terminal::enable_raw_mode().unwrap(); }
+
+			if exit_status.success() {
+				writeln!(out, "\ryt-dlp exited successfully with code 0")?;
+			}else {
+				writeln!(out, "\rError: yt-dlp failed -> {}", exit_status)?;
+			}
+
+			;
+			if let Err(e) = GLOBALS.push_playlist_directory(
+				playlist_dirname.as_os_str().to_str().expect("failed to convert OsStr to str")
+			) {
+				writeln!(out, "Error: failed to add newly downloaded playlist to playlist database -> {}", e)?;
+			}
+		},
+		Some(_) => { writeln!(out, "\rError: unrecognized command")?; },
+		None => {},
+	}
+
+	return Ok(CommandOutcome::Done);
+}
+
+// NOTE `<addr> [name] (<origin>) <health>`, the health as of the last request
+fn describe_peer(peer: &crate::globals::Peer) -> String {
+	let mut description = peer.addr.to_string();
+	if let Some(name) = &peer.name { description += &format!(" {}", name); }
+	description += match peer.origin {
+		crate::globals::PeerOrigin::Added => " (added)",
+		crate::globals::PeerOrigin::Discovered => " (discovered, not added)",
+	};
+
+	let health = &peer.health;
+	let answered = match health.last_success.map(|time| time.elapsed().unwrap_or_default()) {
+		Some(elapsed) => format!("answered {}s ago", elapsed.as_secs()),
+		None => "never answered".to_owned(),
+	};
+	if !health.is_reachable() {
+		description += &format!(" unreachable after {} failed requests, {}", health.failures, answered);
+	}else if let Some(latency) = health.latency {
+		description += &format!(" reachable in {}ms, {}", latency.as_millis(), answered);
+	}else {
+		description += " not checked yet";
+	}
+	return description;
+}
@@ -0,0 +1,480 @@
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+
+pub const DEFAULT_PORT: u16 = 8000;
+// NOTE an administratively scoped group, it does not leave the local network
+pub const DEFAULT_DISCOVERY_GROUP: std::net::SocketAddrV4 =
+	std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(239, 255, 76, 83), 48053);
+
+const USAGE: &str = "\
+usage: localshare [options]
+       localshare [options] ctl <command> [arguments...]
+
+options:
+  --config <path>        read settings from a config file
+  --port <port>          port to serve on (default 8000)
+  --bind <address>       address to bind to (default 0.0.0.0)
+  --state <path>         file the hosted files, folders, playlists and peers
+                         are saved to (default state.json)
+  --entries <path>       file list of older versions, imported when the state
+                         file does not exist yet (default entries.txt)
+  --playlists <path>     playlist list of older versions, imported along with
+                         the entries file (default playlists.txt)
+  --static-dir <path>    directory whose pages replace the built-in ones, read
+                         again on every request while developing them
+  --favicon <path>       icon served at /favicon.ico instead of the built-in one
+  --upload-dir <path>    directory uploaded files are saved to (default uploads)
+  --download-dir <path>  directory playlists are downloaded to (default playlists)
+  --daemon               run without the interactive prompt, taking commands
+                         over the control socket instead
+  --control-socket <path>
+                         control socket of the daemon (default
+                         $XDG_RUNTIME_DIR/localshare-<port>.sock)
+  --rescan-interval <seconds>
+                         how often playlists and shared folders are polled
+                         for changes when inotify is unavailable, 0 turns
+                         watching them off (default 10)
+  --map-files            map hosted files into memory instead of opening them
+                         for each request, a file truncated while it is
+                         being served then crashes the server
+  --lazy-open            open hosted files for each request (the default)
+  --admin-token <token>  enables the /admin endpoints, authenticated with
+                         an `Authorization: Bearer <token>` header
+  --name <name>          name announced to other instances (default the
+                         host name)
+  --no-discovery         neither announce this instance on the local network
+                         nor look for others there
+  --no-mdns              neither advertise this instance over mDNS / DNS-SD
+                         (as <host name>.local) nor browse for others with it
+  --set <key>=<value>    set any config file key
+  --help                 show this message
+
+ctl sends a command (as typed at the interactive prompt) to a running
+daemon, finding it by the same settings the daemon was started with.
+
+settings are applied in the order: defaults, config file, command line.
+without --config, $XDG_CONFIG_HOME/localshare/localshare.toml (or
+~/.config/localshare/localshare.toml) is read if it exists.";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+	pub bind_address: std::net::IpAddr,
+	pub port: u16,
+	pub state_file: PathBuf,
+	// NOTE the legacy text files, only read to import them into the state file
+	pub entries_file: PathBuf,
+	pub playlists_file: PathBuf,
+	// NOTE the pages and favicon are built in, these override them from disk
+	pub static_dir: Option<PathBuf>,
+	pub favicon_file: Option<PathBuf>,
+	pub upload_dir: PathBuf,
+	pub download_dir: PathBuf,
+	pub request_limits: crate::http::RequestLimits,
+	pub connection_limits: crate::http::ConnectionLimits,
+	pub daemon: bool,
+	pub lazy_open: bool,
+	// NOTE zero disables the watcher
+	pub rescan_interval: std::time::Duration,
+	pub control_socket: Option<PathBuf>,
+	// NOTE the /admin endpoints are disabled without one
+	pub admin_token: Option<String>,
+	// NOTE the host name is announced without one, see instance_name
+	pub name: Option<String>,
+	pub discovery: bool,
+	pub discovery_group: std::net::SocketAddrV4,
+	// how often this instance is announced, discovered peers are forgotten
+	// after missing three announcements
+	pub discovery_interval: std::time::Duration,
+	// NOTE advertises _localshare._tcp and _http._tcp on 224.0.0.251:5353
+	pub mdns: bool,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		return Self {
+			bind_address: std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+			port: DEFAULT_PORT,
+			state_file: PathBuf::from("state.json"),
+			entries_file: PathBuf::from("entries.txt"),
+			playlists_file: PathBuf::from("playlists.txt"),
+			static_dir: None,
+			favicon_file: None,
+			upload_dir: PathBuf::from("uploads"),
+			download_dir: PathBuf::from("playlists"),
+			request_limits: crate::http::RequestLimits::default(),
+			connection_limits: crate::http::ConnectionLimits::default(),
+			daemon: false,
+			// NOTE reading a mapped file that another process truncates raises
+			// SIGBUS, which no check can fully rule out
+			lazy_open: true,
+			rescan_interval: std::time::Duration::from_secs(10),
+			control_socket: None,
+			admin_token: None,
+			name: None,
+			discovery: true,
+			discovery_group: DEFAULT_DISCOVERY_GROUP,
+			discovery_interval: std::time::Duration::from_secs(5),
+			mdns: true,
+		};
+	}
+}
+
+pub enum Invocation {
+	Serve(Config),
+	// a command line to send to a running daemon
+	Control(Config, String),
+	Help(&'static str),
+}
+
+impl Config {
+	// applies a single setting, relative paths are taken relative to base
+	// when one is given (the directory of the config file)
+	pub fn set(&mut self, key: &str, value: &str, base: Option<&Path>) -> Result<()> {
+		let path = || -> PathBuf {
+			match base {
+				Some(base) if Path::new(value).is_relative() => base.join(value),
+				_ => PathBuf::from(value),
+			}
+		};
+		let seconds = || -> Result<std::time::Duration> {
+			return Ok(std::time::Duration::from_secs(value.parse::<u64>()?));
+		};
+
+		match key {
+			"port" => self.port = value.parse::<u16>()
+				.map_err(|e| anyhow!("invalid port {} -> {}", value, e))?,
+			"bind_address" => self.bind_address = value.parse::<std::net::IpAddr>()
+				.map_err(|e| anyhow!("invalid bind address {} -> {}", value, e))?,
+			"state_file" => self.state_file = path(),
+			"entries_file" => self.entries_file = path(),
+			"playlists_file" => self.playlists_file = path(),
+			"static_dir" => self.static_dir = Some(path()),
+			"favicon_file" => self.favicon_file = Some(path()),
+			"upload_dir" => self.upload_dir = path(),
+			"download_dir" => self.download_dir = path(),
+			"max_header_size" => self.request_limits.max_header_size = value.parse()?,
+			"max_body_size" => self.request_limits.max_body_size = value.parse()?,
+			"max_upload_size" => self.request_limits.max_upload_size = value.parse()?,
+			"idle_timeout" => self.connection_limits.idle_timeout = seconds()?,
+			"request_timeout" => self.connection_limits.request_timeout = seconds()?,
+			"max_requests" => self.connection_limits.max_requests = value.parse()?,
+			"daemon" => self.daemon = value.parse::<bool>()
+				.map_err(|_| anyhow!("daemon must be true or false, got {}", value))?,
+			"lazy_open" => self.lazy_open = value.parse::<bool>()
+				.map_err(|_| anyhow!("lazy_open must be true or false, got {}", value))?,
+			"rescan_interval" => self.rescan_interval = seconds()?,
+			"control_socket" => self.control_socket = Some(path()),
+			"admin_token" => self.admin_token = match value {
+				"" => None,
+				token => Some(token.to_owned()),
+			},
+			"name" => self.name = match value {
+				"" => None,
+				name => Some(name.to_owned()),
+			},
+			"discovery" => self.discovery = value.parse::<bool>()
+				.map_err(|_| anyhow!("discovery must be true or false, got {}", value))?,
+			"discovery_group" => {
+				let group = value.parse::<std::net::SocketAddrV4>()
+					.map_err(|e| anyhow!("invalid discovery group {} -> {}", value, e))?;
+				if !group.ip().is_multicast() { bail!("discovery group {} is not a multicast address", value); }
+				self.discovery_group = group;
+			},
+			"discovery_interval" => {
+				self.discovery_interval = seconds()?;
+				if self.discovery_interval.is_zero() { bail!("discovery_interval must be at least 1 second"); }
+			},
+			"mdns" => self.mdns = value.parse::<bool>()
+				.map_err(|_| anyhow!("mdns must be true or false, got {}", value))?,
+			_ => bail!("unknown setting {}", key),
+		}
+		return Ok(());
+	}
+
+	// NOTE accepts the flat `key = value` subset shared by toml and ini files,
+	// with # or ; comments and optionally quoted values
+	pub fn apply_config_text(&mut self, text: &str, base: Option<&Path>) -> Result<()> {
+		for (line_number, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line == "" || line.starts_with('#') || line.starts_with(';') { continue; }
+
+			let (key, value) = line.split_once('=')
+				.ok_or(anyhow!("line {}: expected key = value", line_number + 1))?;
+			let value = value.trim();
+			let value = match value.strip_prefix('"') {
+				Some(quoted) => quoted.strip_suffix('"')
+					.ok_or(anyhow!("line {}: unterminated string", line_number + 1))?,
+				None => value.split(" #").next().unwrap_or(value).trim(),
+			};
+
+			self.set(key.trim(), value, base)
+				.map_err(|e| anyhow!("line {}: {}", line_number + 1, e))?;
+		}
+		return Ok(());
+	}
+
+	pub fn control_socket_path(&self) -> PathBuf {
+		if let Some(path) = &self.control_socket { return path.clone(); }
+		let runtime_dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+			Some(dir) if dir.len() > 0 => PathBuf::from(dir),
+			_ => std::env::temp_dir(),
+		};
+		return runtime_dir.join(format!("localshare-{}.sock", self.port));
+	}
+
+	// the name announced to other instances
+	pub fn instance_name(&self) -> String {
+		if let Some(name) = &self.name { return name.clone(); }
+		return host_name();
+	}
+
+	pub fn file_access(&self) -> crate::content::FileAccess {
+		if self.lazy_open { return crate::content::FileAccess::Lazy; }
+		return crate::content::FileAccess::Mapped;
+	}
+
+	pub fn apply_config_file(&mut self, path: &Path) -> Result<()> {
+		let text = std::fs::read_to_string(path)
+			.map_err(|e| anyhow!("failed to read config file {} -> {}", path.display(), e))?;
+		return self.apply_config_text(&text, path.parent())
+			.map_err(|e| anyhow!("{}: {}", path.display(), e));
+	}
+}
+
+// the name of this machine, "localshare" when it has none
+pub fn host_name() -> String {
+	return std::fs::read_to_string("/proc/sys/kernel/hostname")
+		.or_else(|_| std::fs::read_to_string("/etc/hostname"))
+		.ok()
+		.or_else(|| std::env::var("HOSTNAME").ok())
+		.map(|name| name.trim().to_owned())
+		.filter(|name| name != "")
+		.unwrap_or_else(|| "localshare".to_owned());
+}
+
+fn default_config_path() -> Option<PathBuf> {
+	let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+		Some(dir) if dir.len() > 0 => PathBuf::from(dir),
+		_ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+	};
+	return Some(config_home.join("localshare").join("localshare.toml"));
+}
+
+// joins the arguments of a ctl command into a line the daemon tokenizes
+// back into the same arguments. NOTE the daemon has its own working
+// directory, so the paths add and add_playlist take are made absolute here
+fn control_command_line(args: Vec<String>) -> Result<String> {
+	let mut arguments = Vec::<String>::new();
+	let mut takes_paths = false;
+	let mut after_option = false;
+	for (index, arg) in args.into_iter().enumerate() {
+		let arg = if index == 0 {
+			takes_paths = arg == "add" || arg == "add_playlist";
+			arg
+		}else if takes_paths && !after_option && !arg.starts_with("--") {
+			std::path::absolute(&arg)
+				.map_err(|e| anyhow!("unable to resolve {} -> {}", arg, e))?
+				.to_string_lossy().into_owned()
+		}else { arg };
+		after_option = matches!(arg.as_str(), "--as" | "--include" | "--exclude");
+
+		if arg == "" || arg.contains([' ', '"', '\\']) {
+			arguments.push(format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\"")));
+		}else {
+			arguments.push(arg);
+		}
+	}
+	return Ok(arguments.join(" "));
+}
+
+// parses the command line (without the program name)
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Invocation> {
+	let mut config_path: Option<PathBuf> = None;
+	let mut settings = Vec::<(String, String)>::new();
+	let mut control_command: Option<String> = None;
+
+	while let Some(arg) = args.next() {
+		let key = match arg.as_str() {
+			"--help" | "-h" => return Ok(Invocation::Help(USAGE)),
+			"--daemon" => {
+				settings.push(("daemon".to_owned(), "true".to_owned()));
+				continue;
+			},
+			"--lazy-open" => {
+				settings.push(("lazy_open".to_owned(), "true".to_owned()));
+				continue;
+			},
+			"--map-files" => {
+				settings.push(("lazy_open".to_owned(), "false".to_owned()));
+				continue;
+			},
+			"--no-discovery" => {
+				settings.push(("discovery".to_owned(), "false".to_owned()));
+				continue;
+			},
+			"--no-mdns" => {
+				settings.push(("mdns".to_owned(), "false".to_owned()));
+				continue;
+			},
+			"ctl" => {
+				let command = control_command_line(args.by_ref().collect())?;
+				if command == "" { bail!("ctl expects a command\n\n{}", USAGE); }
+				control_command = Some(command);
+				break;
+			},
+			"--config" => {
+				let path = args.next().ok_or(anyhow!("--config expects a path"))?;
+				config_path = Some(PathBuf::from(path));
+				continue;
+			},
+			"--set" => {
+				let setting = args.next().ok_or(anyhow!("--set expects key=value"))?;
+				let (key, value) = setting.split_once('=')
+					.ok_or(anyhow!("--set expects key=value, got {}", setting))?;
+				settings.push((key.to_owned(), value.to_owned()));
+				continue;
+			},
+			"--port" => "port",
+			"--bind" => "bind_address",
+			"--state" => "state_file",
+			"--entries" => "entries_file",
+			"--playlists" => "playlists_file",
+			"--static-dir" => "static_dir",
+			"--favicon" => "favicon_file",
+			"--upload-dir" => "upload_dir",
+			"--download-dir" => "download_dir",
+			"--control-socket" => "control_socket",
+			"--admin-token" => "admin_token",
+			"--name" => "name",
+			"--rescan-interval" => "rescan_interval",
+			_ => bail!("unrecognized argument {}\n\n{}", arg, USAGE),
+		};
+		let value = args.next().ok_or(anyhow!("{} expects a value", arg))?;
+		settings.push((key.to_owned(), value));
+	}
+
+	let mut config = Config::default();
+	match config_path {
+		Some(path) => config.apply_config_file(&path)?,
+		None => {
+			if let Some(path) = default_config_path().filter(|path| path.is_file()) {
+				config.apply_config_file(&path)?;
+			}
+		}
+	}
+	for (key, value) in settings.iter() {
+		config.set(key, value, None)?;
+	}
+
+	return Ok(match control_command {
+		Some(command) => Invocation::Control(config, command),
+		None => Invocation::Serve(config),
+	});
+}
+
+static STARTUP_CONFIG: std::sync::OnceLock<Config> = std::sync::OnceLock::new();
+
+// NOTE must be called before GLOBALS is first used to have any effect
+pub fn set_startup_config(config: Config) {
+	if STARTUP_CONFIG.set(config).is_err() {
+		println!("\rWARN: startup config was already set");
+	}
+}
+
+pub fn startup_config() -> Config {
+	return STARTUP_CONFIG.get().cloned().unwrap_or_default();
+}
+
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn test_config_text() {
+		let mut config = super::Config::default();
+		config.apply_config_text("\
+			# localshare settings\n\
+			port = 8123\n\
+			bind_address = \"127.0.0.1\"\n\
+			\n\
+			entries_file = state/entries.txt # relative to the config file\n\
+			upload_dir = \"/srv/uploads\"\n\
+			idle_timeout = 9\n\
+		", Some(std::path::Path::new("/etc/localshare"))).unwrap();
+
+		assert_eq!(config.port, 8123);
+		assert_eq!(config.bind_address, std::net::IpAddr::from([127, 0, 0, 1]));
+		assert_eq!(config.entries_file, std::path::PathBuf::from("/etc/localshare/state/entries.txt"));
+		assert_eq!(config.upload_dir, std::path::PathBuf::from("/srv/uploads"));
+		assert_eq!(config.connection_limits.idle_timeout, std::time::Duration::from_secs(9));
+
+		assert!(config.apply_config_text("colour = blue", None).is_err());
+		assert!(config.apply_config_text("discovery_group = 192.168.1.5:48053", None).is_err());
+		assert!(config.apply_config_text("discovery_interval = 0", None).is_err());
+		assert!(config.apply_config_text("port = eighty", None).is_err());
+		assert!(config.apply_config_text("just words", None).is_err());
+	}
+
+	#[test]
+	fn test_command_line_precedence() {
+		let config_path = std::env::temp_dir().join(format!("localshare-test-{}.toml", std::process::id()));
+		std::fs::write(&config_path, "port = 8123\nplaylists_file = lists.txt\n").unwrap();
+
+		let args = [
+			"--port", "9000",
+			"--config", config_path.to_str().unwrap(),
+			"--set", "max_requests=3",
+		].into_iter().map(String::from);
+		let config = match super::parse_args(args).unwrap() {
+			super::Invocation::Serve(config) => config,
+			_ => panic!("expected a config"),
+		};
+		std::fs::remove_file(&config_path).unwrap();
+
+		// NOTE the command line wins even though --port came before --config
+		assert_eq!(config.port, 9000);
+		assert_eq!(config.playlists_file, std::env::temp_dir().join("lists.txt"));
+		assert_eq!(config.connection_limits.max_requests, 3);
+
+		assert!(super::parse_args(["--port"].into_iter().map(String::from)).is_err());
+		assert!(super::parse_args(["--bogus"].into_iter().map(String::from)).is_err());
+	}
+
+	#[test]
+	fn test_control_invocation() {
+		let args = ["--port", "9001", "ctl", "add", "my song.flac", "--as", "say \"hi\"", "--exclude", "*.bak"].into_iter().map(String::from);
+		match super::parse_args(args).unwrap() {
+			super::Invocation::Control(config, command) => {
+				assert_eq!(config.port, 9001);
+				// NOTE relative paths are resolved here, not by the daemon
+				let path = std::env::current_dir().unwrap().join("my song.flac");
+				assert_eq!(command, format!("add \"{}\" --as \"say \\\"hi\\\"\" --exclude *.bak", path.display()));
+				let tokens = crate::CommandTokenIter::new(&command).collect::<Vec<std::borrow::Cow<str>>>();
+				assert_eq!(tokens, [std::borrow::Cow::Borrowed("add"), path.to_string_lossy(), "--as".into(), "say \"hi\"".into(), "--exclude".into(), "*.bak".into()]);
+				assert!(config.control_socket_path().ends_with("localshare-9001.sock"));
+			},
+			_ => panic!("expected a control command"),
+		}
+
+		let args = ["--daemon", "--no-discovery", "--no-mdns", "--control-socket", "/run/ls.sock"].into_iter().map(String::from);
+		match super::parse_args(args).unwrap() {
+			super::Invocation::Serve(config) => {
+				assert!(config.daemon);
+				assert!(!config.discovery);
+				assert!(!config.mdns);
+				assert_eq!(config.file_access(), crate::content::FileAccess::Lazy);
+				assert_eq!(config.control_socket_path(), std::path::PathBuf::from("/run/ls.sock"));
+			},
+			_ => panic!("expected a config"),
+		}
+
+		match super::parse_args(["--map-files"].into_iter().map(String::from)).unwrap() {
+			super::Invocation::Serve(config) => assert_eq!(config.file_access(), crate::content::FileAccess::Mapped),
+			_ => panic!("expected a config"),
+		}
+
+		assert!(super::parse_args(["ctl"].into_iter().map(String::from)).is_err());
+	}
+}
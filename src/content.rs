@@ -0,0 +1,213 @@
+
+use std::{
+	io::Write,
+	os::unix::fs::{FileExt, MetadataExt},
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+
+// NOTE responses are copied out in chunks of this size, the file length is
+// checked before each one
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileAccess {
+	// opened and mapped when added, remapped when the file changes
+	Mapped,
+	// opened for each request and read with positioned reads, nothing is
+	// kept open or mapped in between
+	Lazy,
+}
+
+#[derive(Clone)]
+struct Snapshot {
+	map: Option<Arc<memmap2::Mmap>>,
+	size: u64,
+	modified: Option<std::time::SystemTime>,
+	// NOTE device and inode tell a file replaced by a rename from one
+	// changed in place
+	identity: (u64, u64),
+}
+
+impl Snapshot {
+	fn from_file(path: &Path, file: &std::fs::File, metadata: &std::fs::Metadata, access: FileAccess) -> Self {
+		let map = match access {
+			FileAccess::Lazy => None,
+			FileAccess::Mapped => match unsafe{ memmap2::Mmap::map(file) } {
+				Ok(map) => Some(Arc::new(map)),
+				Err(e) => {
+					println!("\rWARN: unable to map {}, reading it instead -> {}", path.display(), e);
+					None
+				}
+			},
+		};
+
+		return Self {
+			map,
+			size: metadata.len(),
+			modified: metadata.modified().ok(),
+			identity: (metadata.dev(), metadata.ino()),
+		};
+	}
+
+	fn matches(&self, metadata: &std::fs::Metadata) -> bool {
+		return self.size == metadata.len()
+			&& self.modified == metadata.modified().ok()
+			&& self.identity == (metadata.dev(), metadata.ino());
+	}
+}
+
+fn open_file(path: &Path) -> Result<(std::fs::File, std::fs::Metadata)> {
+	let file = std::fs::File::open(path)
+		.map_err(|e| anyhow!("Failed to open file: {} -> {}", path.display(), e))?;
+	let metadata = file.metadata()?;
+	if !metadata.is_file() { bail!("{} is not a file", path.display()); }
+	return Ok((file, metadata));
+}
+
+// a hosted file, checked against the disk whenever it is served so that
+// changes are picked up and a shrinking file cannot crash the server
+// NOTE no file descriptor is kept between responses, a map does not need one
+pub struct FileContent {
+	path: PathBuf,
+	access: FileAccess,
+	snapshot: Mutex<Snapshot>,
+}
+
+impl FileContent {
+	pub fn open(path: &Path, access: FileAccess) -> Result<Self> {
+		let (file, metadata) = open_file(path)?;
+		let snapshot = Snapshot::from_file(path, &file, &metadata, access);
+		return Ok(Self { path: path.to_path_buf(), access, snapshot: Mutex::new(snapshot) });
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, Snapshot> {
+		return self.snapshot.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	}
+
+	// opens the file for one response, remapping it first if it changed
+	// since it was last served
+	pub fn reader(&self) -> Result<ContentReader> {
+		let (file, metadata) = open_file(&self.path)
+			.map_err(|e| anyhow!("{} is no longer available -> {}", self.path.display(), e))?;
+
+		let mut snapshot = self.lock();
+		if !snapshot.matches(&metadata) {
+			if self.access == FileAccess::Mapped {
+				println!("\rINFO: {} changed on disk, remapping it", self.path.display());
+			}
+			// NOTE responses still using the old mapping keep it alive
+			*snapshot = Snapshot::from_file(&self.path, &file, &metadata, self.access);
+		}
+		let map = snapshot.map.clone();
+		drop(snapshot);
+
+		let len = map.as_ref().map(|map| map.len()).unwrap_or(metadata.len() as usize);
+		return Ok(ContentReader { path: self.path.clone(), file, map, len });
+	}
+}
+
+// the content of a file for one response
+pub struct ContentReader {
+	path: PathBuf,
+	file: std::fs::File,
+	map: Option<Arc<memmap2::Mmap>>,
+	len: usize,
+}
+
+impl ContentReader {
+	// reads a file that is not hosted, like one inside a shared folder
+	pub fn open(path: &Path) -> Result<Self> {
+		let (file, metadata) = open_file(path)?;
+		return Ok(Self { path: path.to_path_buf(), file, map: None, len: metadata.len() as usize });
+	}
+
+	pub fn len(&self) -> usize { return self.len; }
+
+	pub fn path(&self) -> &Path { return &self.path; }
+
+	// the first limit bytes, or the whole file when it is shorter
+	pub fn read_head(&self, limit: usize) -> Result<Vec<u8>> {
+		let mut head = Vec::<u8>::with_capacity(limit.min(self.len));
+		if self.len > 0 && limit > 0 {
+			self.write_range(&mut head, 0, limit.min(self.len) - 1)?;
+		}
+		return Ok(head);
+	}
+
+	// writes bytes start..=end, failing (instead of faulting) when the file
+	// got shorter than that
+	pub fn write_range(&self, sink: &mut dyn Write, start: usize, end: usize) -> Result<()> {
+		if end < start { return Ok(()); }
+		if end >= self.len { bail!("range {}-{} is past the end of {}", start, end, self.path.display()); }
+
+		let mut buffer = vec![0u8; CHUNK_SIZE.min(end - start + 1)];
+		let mut offset = start;
+		while offset <= end {
+			let chunk_end = (offset + CHUNK_SIZE).min(end + 1);
+			let chunk = &mut buffer[..chunk_end - offset];
+			match &self.map {
+				Some(map) => {
+					// NOTE touching mapped pages past the end of the file raises
+					// SIGBUS, so the length is checked right before each chunk
+					// and the chunk is copied out before the (possibly slow)
+					// write to the client. a truncation landing between the
+					// check and the copy can still fault, which is why mapping
+					// is opt-in (--map-files)
+					if self.file.metadata()?.len() < chunk_end as u64 {
+						bail!("{} shrank while it was being served", self.path.display());
+					}
+					chunk.copy_from_slice(&map[offset..chunk_end]);
+				},
+				None => {
+					self.file.read_exact_at(chunk, offset as u64).map_err(|e| match e.kind() {
+						std::io::ErrorKind::UnexpectedEof => anyhow!("{} shrank while it was being served", self.path.display()),
+						_ => anyhow!("failed to read {} -> {}", self.path.display(), e),
+					})?;
+				}
+			}
+			sink.write_all(chunk)?;
+			offset = chunk_end;
+		}
+		return Ok(());
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::{FileAccess, FileContent};
+
+	#[test]
+	fn test_changed_and_truncated_files() {
+		let path = std::env::temp_dir().join(format!("localshare-content-{}.txt", std::process::id()));
+		std::fs::write(&path, b"0123456789").unwrap();
+
+		for access in [FileAccess::Mapped, FileAccess::Lazy] {
+			std::fs::write(&path, b"0123456789").unwrap();
+			let content = FileContent::open(&path, access).unwrap();
+
+			let reader = content.reader().unwrap();
+			let mut output = Vec::new();
+			reader.write_range(&mut output, 2, 5).unwrap();
+			assert_eq!(output, b"2345");
+
+			// NOTE a reader from before the truncation fails instead of faulting
+			std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(4).unwrap();
+			assert!(reader.write_range(&mut Vec::new(), 0, 9).is_err());
+
+			// NOTE the next reader sees the new length
+			let reader = content.reader().unwrap();
+			assert_eq!(reader.len(), 4);
+			let mut output = Vec::new();
+			reader.write_range(&mut output, 0, 3).unwrap();
+			assert_eq!(output, b"0123");
+
+			std::fs::remove_file(&path).unwrap();
+			assert!(content.reader().is_err());
+		}
+	}
+}
@@ -0,0 +1,120 @@
+
+use std::{
+	io::{BufRead, Read, Write},
+	os::unix::{fs::{DirBuilderExt, PermissionsExt}, net::{UnixListener, UnixStream}},
+	path::Path,
+	sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result;
+
+use crate::{commands::CommandOutcome, globals::GLOBALS};
+
+
+static QUIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// NOTE a command is one line, the reply is everything written back
+// before the daemon closes the connection
+const MAX_COMMAND_LENGTH: u64 = 64 * 1024;
+
+// serves commands on a unix socket at path until one of them is quit
+pub fn serve_control_socket(path: &Path) -> Result<()> {
+	if path.exists() {
+		if UnixStream::connect(path).is_ok() {
+			bail!("another instance is already listening on {}", path.display());
+		}
+		// NOTE left behind by an instance that did not shut down cleanly
+		std::fs::remove_file(path)?;
+	}
+
+	let listener = bind_private(path)?;
+	listener.set_nonblocking(true)?;
+	println!("INFO: listening for commands on {}", path.display());
+
+	while !QUIT_REQUESTED.load(Ordering::Relaxed) {
+		match listener.accept() {
+			Ok((stream, _addr)) => {
+				GLOBALS.push_thread(move || {
+					if let Err(e) = handle_control_connection(stream) {
+						println!("Error: control connection failed -> {}", e);
+					}
+					return Ok(());
+				});
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+				std::thread::sleep(std::time::Duration::from_millis(50));
+			},
+			Err(e) => {
+				println!("Error: failed to accept control connection -> {}", e);
+				std::thread::sleep(std::time::Duration::from_millis(50));
+			}
+		}
+	}
+
+	let _ = std::fs::remove_file(path);
+	return Ok(());
+}
+
+// binds a socket only its owner can connect to. NOTE it is bound inside a
+// directory no one else can enter and moved to path once it is 0600, as
+// it would otherwise be open to anyone allowed by the umask until then
+fn bind_private(path: &Path) -> Result<UnixListener> {
+	let file_name = path.file_name().ok_or(anyhow!("{} does not name a socket", path.display()))?;
+	let directory = path.with_file_name(format!(".{}.{}", file_name.to_string_lossy(), std::process::id()));
+	std::fs::DirBuilder::new().mode(0o700).create(&directory)
+		.map_err(|e| anyhow!("failed to create {} -> {}", directory.display(), e))?;
+
+	let private_path = directory.join(file_name);
+	let bind_result = (|| -> Result<UnixListener> {
+		let listener = UnixListener::bind(&private_path)?;
+		std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+		std::fs::rename(&private_path, path)?;
+		return Ok(listener);
+	})();
+
+	let _ = std::fs::remove_file(&private_path);
+	let _ = std::fs::remove_dir(&directory);
+	return bind_result;
+}
+
+fn handle_control_connection(mut stream: UnixStream) -> Result<()> {
+	stream.set_nonblocking(false)?;
+	stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+
+	let mut line = String::new();
+	std::io::BufReader::new(stream.try_clone()?.take(MAX_COMMAND_LENGTH))
+		.read_line(&mut line)?;
+	let line = line.trim_end_matches(['\r', '\n']);
+	println!("INFO: control command -> {}", line);
+
+	match crate::commands::run_command(line, &mut stream, false)? {
+		CommandOutcome::Done => {},
+		CommandOutcome::ClearScreen => {
+			writeln!(stream, "Error: clear is only available at the interactive prompt")?;
+		},
+		CommandOutcome::Quit => {
+			writeln!(stream, "INFO: shutting down")?;
+			QUIT_REQUESTED.store(true, Ordering::Relaxed);
+		}
+	}
+	stream.flush()?;
+
+	return Ok(());
+}
+
+// sends a command line to the daemon listening at path and prints its reply
+pub fn send_command(path: &Path, line: &str) -> Result<()> {
+	let mut stream = UnixStream::connect(path).map_err(|e| anyhow!(
+		"failed to connect to {} (is localshare running with --daemon?) -> {}", path.display(), e
+	))?;
+	stream.write_all(line.as_bytes())?;
+	stream.write_all(b"\n")?;
+	stream.shutdown(std::net::Shutdown::Write)?;
+
+	let mut reply = String::new();
+	stream.read_to_string(&mut reply)?;
+	// NOTE command output is formatted for the raw mode terminal of the REPL
+	print!("{}", reply.replace('\r', ""));
+
+	return Ok(());
+}
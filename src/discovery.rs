@@ -0,0 +1,243 @@
+
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+
+use anyhow::Result;
+
+use crate::{globals::GLOBALS, json::JsonValue};
+
+pub use shared_socket::bind_shared;
+
+
+// NOTE announcements of other versions are ignored, bump this when their
+// format changes
+pub const PROTOCOL_VERSION: u64 = 1;
+// NOTE announcements are small, anything larger is not one
+const MAX_ANNOUNCEMENT_SIZE: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Announcement {
+	// NOTE random per process, so an instance can tell its own announcements
+	// (looped back by the group) from those of others
+	pub id: String,
+	pub name: String,
+	pub port: u16,
+	pub version: u64,
+}
+
+impl Announcement {
+	pub fn to_bytes(&self) -> Vec<u8> {
+		return JsonValue::Object(vec![
+			("service".to_owned(), "localshare".into()),
+			("version".to_owned(), self.version.into()),
+			("id".to_owned(), self.id.as_str().into()),
+			("name".to_owned(), self.name.as_str().into()),
+			("port".to_owned(), (self.port as u64).into()),
+		]).to_json_string(false).into_bytes();
+	}
+
+	pub fn parse(datagram: &[u8]) -> Result<Self> {
+		let json = JsonValue::parse(std::str::from_utf8(datagram)?)?;
+		if json.get("service").and_then(JsonValue::as_str) != Some("localshare") {
+			bail!("not a localshare announcement");
+		}
+		let field = |key: &str| json.get(key).ok_or(anyhow!("announcement without {}", key));
+		return Ok(Self {
+			id: field("id")?.as_str().ok_or(anyhow!("announcement id is not a string"))?.to_owned(),
+			name: field("name")?.as_str().ok_or(anyhow!("announcement name is not a string"))?.to_owned(),
+			port: field("port")?.as_u64().and_then(|port| u16::try_from(port).ok())
+				.ok_or(anyhow!("announcement port is not a port"))?,
+			version: field("version")?.as_u64().ok_or(anyhow!("announcement version is not a number"))?,
+		});
+	}
+}
+
+fn random_id() -> String {
+	use std::hash::{BuildHasher, Hasher};
+	let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+	hasher.write_u32(std::process::id());
+	hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+	return format!("{:016x}", hasher.finish());
+}
+
+// a socket joined to the discovery group, sending this instance's
+// announcements and receiving everyone else's
+pub struct Discovery {
+	socket: UdpSocket,
+	group: SocketAddrV4,
+	announcement: Announcement,
+}
+
+impl Discovery {
+	pub fn new(group: SocketAddrV4, name: &str, port: u16) -> Result<Self> {
+		let socket = shared_socket::bind_shared(group.port())
+			.map_err(|e| anyhow!("unable to bind the discovery port {} -> {}", group.port(), e))?;
+		socket.join_multicast_v4(group.ip(), &std::net::Ipv4Addr::UNSPECIFIED)
+			.map_err(|e| anyhow!("unable to join the discovery group {} -> {}", group.ip(), e))?;
+		// NOTE looped back so that instances on the same machine find each other
+		socket.set_multicast_loop_v4(true)?;
+		socket.set_multicast_ttl_v4(1)?;
+
+		let announcement = Announcement { id: random_id(), name: name.to_owned(), port, version: PROTOCOL_VERSION };
+		return Ok(Self { socket, group, announcement });
+	}
+
+	pub fn announce(&self) -> Result<()> {
+		self.socket.send_to(&self.announcement.to_bytes(), self.group)?;
+		return Ok(());
+	}
+
+	// waits up to timeout for an announcement of another instance, returning
+	// the address its http server is at. None when nothing (usable) arrived
+	pub fn receive(&self, timeout: std::time::Duration) -> Result<Option<(SocketAddr, Announcement)>> {
+		self.socket.set_read_timeout(Some(timeout.max(std::time::Duration::from_millis(1))))?;
+		let mut datagram = [0u8; MAX_ANNOUNCEMENT_SIZE];
+		let (size, source) = match self.socket.recv_from(&mut datagram) {
+			Ok(pair) => pair,
+			Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => return Ok(None),
+			Err(e) => return Err(e.into()),
+		};
+
+		let announcement = match Announcement::parse(&datagram[..size]) {
+			Ok(announcement) => announcement,
+			Err(e) => {
+				println!("\rWARN: ignoring discovery datagram from {} -> {}", source, e);
+				return Ok(None);
+			}
+		};
+		if announcement.id == self.announcement.id || announcement.version != PROTOCOL_VERSION {
+			return Ok(None);
+		}
+		return Ok(Some((SocketAddr::new(source.ip(), announcement.port), announcement)));
+	}
+}
+
+// announces this instance every interval and keeps the discovered peers in
+// GLOBALS, forgetting the ones that miss three announcements in a row
+pub fn run_discovery(group: SocketAddrV4, interval: std::time::Duration) -> Result<()> {
+	let name = GLOBALS.config.instance_name();
+	let discovery = Discovery::new(group, &name, GLOBALS.config.port)?;
+	println!("\rINFO: announcing {} on {}", name, group);
+
+	let mut next_announcement = std::time::Instant::now();
+	loop {
+		let now = std::time::Instant::now();
+		if now >= next_announcement {
+			if let Err(e) = discovery.announce() {
+				println!("\rWARN: failed to announce this instance -> {}", e);
+			}
+			next_announcement = now + interval;
+
+			for peer in GLOBALS.expire_peers(now) {
+				println!("\rINFO: peer {} ({}) went quiet", peer.addr, peer.name.unwrap_or_default());
+			}
+		}
+
+		if let Some((addr, announcement)) = discovery.receive(next_announcement.saturating_duration_since(now))? {
+			if GLOBALS.saw_peer(addr, &announcement.name, interval * 3) {
+				println!("\rINFO: discovered peer {} at {}", announcement.name, addr);
+			}
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod shared_socket {
+	use std::os::fd::FromRawFd;
+
+	// NOTE declared by hand, the crate does not depend on libc
+	unsafe extern "C" {
+		fn socket(domain: i32, kind: i32, protocol: i32) -> i32;
+		fn setsockopt(fd: i32, level: i32, name: i32, value: *const std::ffi::c_void, length: u32) -> i32;
+		fn bind(fd: i32, addr: *const SockAddrIn, length: u32) -> i32;
+	}
+
+	const AF_INET: i32 = 2;
+	const SOCK_DGRAM: i32 = 2;
+	const SOCK_CLOEXEC: i32 = 0o2000000;
+	const SOL_SOCKET: i32 = 1;
+	const SO_REUSEADDR: i32 = 2;
+
+	#[repr(C)]
+	struct SockAddrIn {
+		family: u16,
+		// NOTE port and address are in network byte order
+		port: u16,
+		addr: u32,
+		zero: [u8; 8],
+	}
+
+	// a udp socket on port of every interface, which other instances on this
+	// machine can bind as well (std binds without SO_REUSEADDR)
+	pub fn bind_shared(port: u16) -> std::io::Result<std::net::UdpSocket> {
+		let fd = unsafe{ socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+		if fd < 0 { return Err(std::io::Error::last_os_error()); }
+		// NOTE owns the descriptor from here on, closing it on drop
+		let socket = unsafe{ std::net::UdpSocket::from_raw_fd(fd) };
+
+		let enable: i32 = 1;
+		let result = unsafe{ setsockopt(
+			fd, SOL_SOCKET, SO_REUSEADDR,
+			&enable as *const i32 as *const std::ffi::c_void, std::mem::size_of::<i32>() as u32
+		) };
+		if result < 0 { return Err(std::io::Error::last_os_error()); }
+
+		let addr = SockAddrIn { family: AF_INET as u16, port: port.to_be(), addr: 0, zero: [0; 8] };
+		let result = unsafe{ bind(fd, &addr, std::mem::size_of::<SockAddrIn>() as u32) };
+		if result < 0 { return Err(std::io::Error::last_os_error()); }
+		return Ok(socket);
+	}
+}
+
+// NOTE elsewhere only one instance per machine can listen for announcements
+#[cfg(not(target_os = "linux"))]
+mod shared_socket {
+	pub fn bind_shared(port: u16) -> std::io::Result<std::net::UdpSocket> {
+		return std::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, port));
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::{Announcement, Discovery};
+
+	#[test]
+	fn test_announcement() {
+		let announcement = Announcement { id: "00ff".to_owned(), name: "den \"pc\"".to_owned(), port: 8001, version: 1 };
+		assert_eq!(Announcement::parse(&announcement.to_bytes()).unwrap(), announcement);
+
+		assert!(Announcement::parse(b"{\"service\":\"other\",\"id\":\"a\",\"name\":\"b\",\"port\":1,\"version\":1}").is_err());
+		assert!(Announcement::parse(b"{\"service\":\"localshare\",\"id\":\"a\",\"name\":\"b\",\"port\":70000,\"version\":1}").is_err());
+		assert!(Announcement::parse(b"\xff\xfe").is_err());
+	}
+
+	// NOTE two instances on one machine, each hearing the other but not itself
+	#[test]
+	fn test_discovery_loopback() {
+		let port = 40000 + (std::process::id() % 20000) as u16;
+		let group = std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(239, 255, 76, 84), port);
+		let first = Discovery::new(group, "first", 8001).unwrap();
+		let second = Discovery::new(group, "second", 8002).unwrap();
+
+		let timeout = std::time::Duration::from_millis(500);
+		let receive_one = |discovery: &Discovery| {
+			let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+			while std::time::Instant::now() < deadline {
+				if let Some(found) = discovery.receive(timeout).unwrap() { return Some(found); }
+			}
+			return None;
+		};
+
+		first.announce().unwrap();
+		let (addr, announcement) = receive_one(&second).expect("second did not hear first");
+		assert_eq!(announcement.name, "first");
+		assert_eq!(addr.port(), 8001);
+		// NOTE first only receives its own announcement, which is skipped
+		assert!(first.receive(timeout).unwrap().is_none());
+
+		second.announce().unwrap();
+		let (addr, announcement) = receive_one(&first).expect("first did not hear second");
+		assert_eq!(announcement.name, "second");
+		assert_eq!(addr.port(), 8002);
+	}
+}
@@ -0,0 +1,150 @@
+
+use anyhow::Result;
+
+
+// a shell style pattern, `*` matches within one path component, `**`
+// across components, `?` one character and `[a-z]`/`[!a-z]` a set
+#[derive(Clone, Debug)]
+pub struct GlobPattern {
+	pattern: String,
+	chars: Vec<char>,
+}
+
+impl GlobPattern {
+	pub fn new(pattern: &str) -> Result<Self> {
+		if pattern == "" { bail!("empty glob pattern"); }
+		let chars = pattern.chars().collect::<Vec<char>>();
+
+		let mut index = 0;
+		while index < chars.len() {
+			if chars[index] == '[' {
+				match class_end(&chars, index) {
+					Some(end) => index = end,
+					None => bail!("unterminated [ in glob pattern {}", pattern),
+				}
+			}
+			index += 1;
+		}
+
+		return Ok(Self { pattern: pattern.to_owned(), chars });
+	}
+
+	pub fn as_str(&self) -> &str { return &self.pattern; }
+
+	// NOTE like .gitignore, a pattern without a slash is matched against
+	// the last component only, so `*.log` matches `logs/today.log`
+	pub fn matches(&self, relative_path: &str) -> bool {
+		let relative_path = relative_path.trim_start_matches('/');
+		let text = if self.pattern.contains('/') {
+			relative_path
+		}else {
+			relative_path.rsplit('/').next().unwrap_or(relative_path)
+		};
+		return glob_match(&self.chars, &text.chars().collect::<Vec<char>>());
+	}
+}
+
+// index of the ] closing the set opened at start
+fn class_end(pattern: &[char], start: usize) -> Option<usize> {
+	let mut index = start + 1;
+	if pattern.get(index) == Some(&'!') { index += 1; }
+	// NOTE a leading ] is part of the set
+	if pattern.get(index) == Some(&']') { index += 1; }
+	while index < pattern.len() {
+		if pattern[index] == ']' { return Some(index); }
+		index += 1;
+	}
+	return None;
+}
+
+fn class_matches(set: &[char], chr: char) -> bool {
+	let (negated, set) = match set.first() {
+		Some('!') => (true, &set[1..]),
+		_ => (false, set),
+	};
+
+	let mut index = 0;
+	let mut found = false;
+	while index < set.len() {
+		if index + 2 < set.len() && set[index + 1] == '-' {
+			if set[index] <= chr && chr <= set[index + 2] { found = true; }
+			index += 3;
+		}else {
+			if set[index] == chr { found = true; }
+			index += 1;
+		}
+	}
+	return found != negated;
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+	match pattern.first() {
+		None => text.is_empty(),
+		Some('*') if pattern.get(1) == Some(&'*') => {
+			let rest = &pattern[2..];
+			// NOTE `**/` also matches no directories at all
+			if rest.first() == Some(&'/') && glob_match(&rest[1..], text) { return true; }
+			(0..=text.len()).any(|skip| glob_match(rest, &text[skip..]))
+		},
+		Some('*') => {
+			let rest = &pattern[1..];
+			for skip in 0..=text.len() {
+				if glob_match(rest, &text[skip..]) { return true; }
+				if skip < text.len() && text[skip] == '/' { break; }
+			}
+			false
+		},
+		Some('?') => {
+			!text.is_empty() && text[0] != '/' && glob_match(&pattern[1..], &text[1..])
+		},
+		Some('[') => {
+			let end = match class_end(pattern, 0) {
+				Some(end) => end,
+				None => return text.first() == Some(&'[') && glob_match(&pattern[1..], &text[1..]),
+			};
+			!text.is_empty() && text[0] != '/'
+				&& class_matches(&pattern[1..end], text[0])
+				&& glob_match(&pattern[end + 1..], &text[1..])
+		},
+		Some(chr) => {
+			text.first() == Some(chr) && glob_match(&pattern[1..], &text[1..])
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::GlobPattern;
+
+	#[test]
+	fn test_glob_matching() {
+		let cases: &[(&str, &str, bool)] = &[
+			("*.rs", "main.rs", true),
+			("*.rs", "src/http.rs", true),
+			("*.rs", "main.rso", false),
+			("src/*.rs", "src/http.rs", true),
+			("src/*.rs", "src/bin/tool.rs", false),
+			("src/**/*.rs", "src/bin/tool.rs", true),
+			("src/**/*.rs", "src/http.rs", true),
+			("**/target", "crate/target", true),
+			("target", "crate/target", true),
+			("?.txt", "a.txt", true),
+			("?.txt", "ab.txt", false),
+			("[a-c]*", "beta", true),
+			("[!a-c]*", "beta", false),
+			("[]]", "]", true),
+			("caf?", "café", true),
+		];
+
+		for (pattern, path, expected) in cases {
+			assert_eq!(
+				GlobPattern::new(pattern).unwrap().matches(path), *expected,
+				"{} against {}", pattern, path
+			);
+		}
+
+		assert!(GlobPattern::new("[abc").is_err());
+		assert!(GlobPattern::new("").is_err());
+	}
+}
@@ -1,57 +1,149 @@
 
+use std::borrow::Cow;
 use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 
 use anyhow::Result;
 
+use crate::content::{FileAccess, FileContent};
 
+
+pub struct FileEntry {
+	pub name: Arc<str>,
+	// NOTE where the entry was mapped from, differs from its name when aliased
+	pub path: std::path::PathBuf,
+	pub content: Arc<FileContent>,
+}
+
+impl FileEntry {
+	pub fn open<P: AsRef<std::path::Path>>(name: &str, path: P, access: FileAccess) -> Result<Self> {
+		let path = path.as_ref();
+		return Ok(Self {
+			name: Arc::from(name),
+			path: path.to_path_buf(),
+			content: Arc::new(FileContent::open(path, access)?),
+		});
+	}
+}
+
+// the files directly inside a directory, sorted
+pub fn directory_files(directory: &std::path::Path) -> Result<Vec<std::path::PathBuf>> {
+	let mut paths = std::fs::read_dir(directory)?.into_iter()
+		.filter_map(|entry| entry.ok())
+		.filter(|entry| entry.file_type().map(|filetype| filetype.is_file()).unwrap_or(false))
+		.map(|entry| entry.path())
+		.collect::<Vec<std::path::PathBuf>>();
+	// NOTE read_dir order is arbitrary, sorting keeps song numbers stable
+	paths.sort();
+	return Ok(paths);
+}
+
+// entries in insertion order, indexed by name and by (canonical) path so
+// neither can be hosted twice
+// NOTE a removed entry leaves an empty slot behind, so removing is O(1) and
+// the other entries keep their order and index. the slots are compacted
+// once they outnumber the entries
 pub struct FileDatabase {
-	pub filenames: Vec<Arc<str>>,
-	pub file_contents: Vec<Arc<memmap2::Mmap>>
+	entries: Vec<Option<StoredEntry>>,
+	removed: usize,
+	by_name: std::collections::HashMap<Arc<str>, usize>,
+	by_path: std::collections::HashMap<std::path::PathBuf, usize>,
+	access: FileAccess,
+}
+
+// NOTE the path key is kept as it was when the entry was added, the file may
+// since have been deleted or moved, or a symlink on its way changed
+struct StoredEntry {
+	entry: FileEntry,
+	path_key: std::path::PathBuf,
 }
 
 impl FileDatabase {
-	pub fn new() -> Self {
+	pub fn with_access(access: FileAccess) -> Self {
 		return Self {
-			filenames: Vec::new(),
-			file_contents: Vec::new()
+			entries: Vec::new(),
+			removed: 0,
+			by_name: std::collections::HashMap::new(),
+			by_path: std::collections::HashMap::new(),
+			access,
 		};
 	}
+	fn path_key(path: &std::path::Path) -> std::path::PathBuf {
+		return std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+	}
 	pub fn add_file(&mut self, filename: &str) -> Result<()> {
-		let file = std::fs::File::open(filename);
-		if let Err(_) = file { bail!("Failed to open file: {}", filename); }
-		let file = file.unwrap();
-
-		let filemap = unsafe{ memmap2::Mmap::map(&file) };
-		if let Err(_) = filemap { bail!("Unable to map file to memory: {}", filename); }
-		let filemap = filemap.unwrap();
-
-		self.push_entry(Arc::from(filename), Arc::new(filemap));
+		self.push_entry(FileEntry::open(filename, filename, self.access)?)?;
 		return Ok(());
 	}
 	pub fn add_directory_nonrecursive(&mut self, dirname: &str) -> Result<()> {
-		for direntry in std::fs::read_dir(dirname)?.into_iter()
-			.filter(|entry| entry.is_ok())
-			.map(|entry| entry.unwrap())
-			.filter(|entry| {
-				if let Ok(filetype) = entry.file_type() {
-					filetype.is_file()
-				}else {
-					false
+		for path in directory_files(std::path::Path::new(dirname))? {
+			let filename = match path.to_str() {
+				Some(filename) => filename,
+				None => {
+					println!("\rWARN: skipping {}, the path is not valid utf-8", path.display());
+					continue;
 				}
-			})
-		{
-			if let Err(_) = self.add_file(
-				direntry.path().as_os_str().to_str()
-					.expect("Failed to convert C string to utf-8")
-			) {
-				// TODO maybe add messages here
+			};
+			if let Err(e) = self.add_file(filename) {
+				println!("\rWARN: skipping {} -> {}", filename, e);
 			}
 		}
 		return Ok(());
 	}
-	pub fn push_entry(&mut self, filename: Arc<str>, filemap: Arc<memmap2::Mmap>) {
-		self.filenames.push(Arc::from(filename));
-		self.file_contents.push(Arc::from(filemap));
+	// adds an entry, refusing a name or a file that is already present
+	pub fn push_entry(&mut self, entry: FileEntry) -> Result<usize> {
+		if self.by_name.contains_key(&entry.name) {
+			bail!("a file named {} is already hosted", entry.name);
+		}
+		let path_key = Self::path_key(&entry.path);
+		if let Some(index) = self.by_path.get(&path_key) {
+			bail!("{} is already hosted as {}", entry.path.display(), self.entries[*index].as_ref().map_or("", |stored| &stored.entry.name));
+		}
+
+		let index = self.entries.len();
+		self.by_name.insert(entry.name.clone(), index);
+		self.by_path.insert(path_key.clone(), index);
+		self.entries.push(Some(StoredEntry { entry, path_key }));
+		return Ok(index);
+	}
+	pub fn iter(&self) -> impl DoubleEndedIterator<Item = &FileEntry> {
+		return self.entries.iter().flatten().map(|stored| &stored.entry);
+	}
+	pub fn get(&self, name: &str) -> Option<&FileEntry> {
+		return self.by_name.get(name).and_then(|index| self.get_index(*index));
+	}
+	pub fn get_index(&self, index: usize) -> Option<&FileEntry> {
+		return self.entries.get(index).and_then(Option::as_ref).map(|stored| &stored.entry);
+	}
+	pub fn position(&self, filename: &str) -> Option<usize> {
+		return self.by_name.get(filename).copied();
+	}
+	pub fn remove(&mut self, name: &str) -> Option<FileEntry> {
+		let index = self.by_name.remove(name)?;
+		let stored = self.entries[index].take()?;
+		self.by_path.remove(&stored.path_key);
+		self.removed += 1;
+		if self.removed * 2 > self.entries.len() { self.compact(); }
+		return Some(stored.entry);
+	}
+	// drops the empty slots, moving every entry after one down
+	fn compact(&mut self) {
+		self.entries.retain(Option::is_some);
+		self.removed = 0;
+		for (index, stored) in self.entries.iter().flatten().enumerate() {
+			self.by_name.insert(stored.entry.name.clone(), index);
+			self.by_path.insert(stored.path_key.clone(), index);
+		}
+	}
+	pub fn rename(&mut self, old_name: &str, new_name: &str) -> Result<bool> {
+		if self.by_name.contains_key(new_name) { bail!("a file named {} is already hosted", new_name); }
+		let index = match self.by_name.remove(old_name) {
+			Some(index) => index,
+			None => return Ok(false),
+		};
+		let new_name: Arc<str> = Arc::from(new_name);
+		if let Some(stored) = self.entries[index].as_mut() { stored.entry.name = new_name.clone(); }
+		self.by_name.insert(new_name, index);
+		return Ok(true);
 	}
 }
 
@@ -62,24 +154,316 @@ pub struct Playlist {
 }
 
 impl Playlist {
-	pub fn from_directory(playlist_dir: &str) -> Result<Self> {
-		let mut playlist_files = FileDatabase::new();
+	pub fn from_directory(playlist_dir: &str, access: FileAccess) -> Result<Self> {
+		let mut playlist_files = FileDatabase::with_access(access);
 		playlist_files.add_directory_nonrecursive(playlist_dir)?;
 
-		let playlist_name = playlist_dir.split('/').rev().next()
-			.unwrap_or(playlist_dir);
+		return Ok(Playlist{ directory: Arc::from(playlist_dir), name: Arc::from(playlist_name(playlist_dir)), files: playlist_files });
+	}
+}
+
+fn playlist_name(playlist_dir: &str) -> &str {
+	return playlist_dir.split('/').rev().next().unwrap_or(playlist_dir);
+}
+
+pub struct DirectoryEntry {
+	pub name: String,
+	pub is_dir: bool,
+	pub size: u64,
+	pub modified: Option<std::time::SystemTime>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeerOrigin {
+	// added with add_peer (or POST /peers), saved to the state file
+	Added,
+	// announced on the lan, forgotten once it goes quiet. anyone can announce
+	// anything, so these are only listed until add_peer confirms them
+	Discovered,
+}
+
+// NOTE announcements are unauthenticated, so a flood of made up ones must not
+// grow the peer list without bound
+const MAX_DISCOVERED_PEERS: usize = 32;
+
+#[derive(Clone, Debug)]
+pub struct Peer {
+	pub addr: std::net::SocketAddr,
+	pub origin: PeerOrigin,
+	// NOTE the name a discovered instance announced itself with
+	pub name: Option<String>,
+	// NOTE when the last announcement arrived, None for a peer that was
+	// only ever added by hand
+	pub last_seen: Option<std::time::Instant>,
+	// NOTE when a discovered peer is forgotten unless it is announced again
+	pub expires: Option<std::time::Instant>,
+	pub health: PeerHealth,
+}
+
+impl Peer {
+	// NOTE only peers the user added (or confirmed) are sent requests
+	pub fn is_trusted(&self) -> bool {
+		return self.origin == PeerOrigin::Added;
+	}
+
+	pub fn added(addr: std::net::SocketAddr) -> Self {
+		return Self {
+			addr, origin: PeerOrigin::Added, name: None, last_seen: None, expires: None,
+			health: PeerHealth::default(),
+		};
+	}
+}
+
+// NOTE a reachable peer is checked this often, an unreachable one waits twice
+// as long after every failed check, up to PEER_CHECK_MAX_BACKOFF
+pub const PEER_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+const PEER_CHECK_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// how a peer answered the last health checks (and file list requests)
+#[derive(Clone, Debug, Default)]
+pub struct PeerHealth {
+	pub last_success: Option<std::time::SystemTime>,
+	// NOTE how long the last successful request took to be answered
+	pub latency: Option<std::time::Duration>,
+	// NOTE failed requests since the last success
+	pub failures: u32,
+	// NOTE None until the first check
+	pub next_check: Option<std::time::Instant>,
+}
+
+impl PeerHealth {
+	// NOTE a peer that was never checked is assumed to be reachable
+	pub fn is_reachable(&self) -> bool {
+		return self.failures == 0;
+	}
+
+	pub fn is_due(&self, now: std::time::Instant) -> bool {
+		return self.next_check.is_none_or(|next_check| next_check <= now);
+	}
+
+	fn succeeded(&mut self, latency: std::time::Duration, now: std::time::Instant) {
+		self.last_success = Some(std::time::SystemTime::now());
+		self.latency = Some(latency);
+		self.failures = 0;
+		self.next_check = Some(now + PEER_CHECK_INTERVAL);
+	}
+
+	fn failed(&mut self, now: std::time::Instant) {
+		self.failures = self.failures.saturating_add(1);
+		let backoff = PEER_CHECK_INTERVAL.saturating_mul(1 << (self.failures - 1).min(16));
+		self.next_check = Some(now + backoff.min(PEER_CHECK_MAX_BACKOFF));
+	}
+}
+
+// a directory tree shared as a whole, its contents are read from disk
+// whenever it is browsed so it never goes stale
+pub struct SharedDirectory {
+	pub name: Arc<str>,
+	pub root: std::path::PathBuf,
+	pub include: Vec<crate::glob::GlobPattern>,
+	pub exclude: Vec<crate::glob::GlobPattern>,
+}
+
+impl SharedDirectory {
+	pub fn new(
+		dirname: &str,
+		include: Vec<crate::glob::GlobPattern>,
+		exclude: Vec<crate::glob::GlobPattern>,
+	) -> Result<Self> {
+		let root = std::fs::canonicalize(dirname)
+			.map_err(|e| anyhow!("unable to locate directory {} -> {}", dirname, e))?;
+		if !root.is_dir() { bail!("{} is not a directory", dirname); }
+
+		let name = root.file_name()
+			.and_then(|name| name.to_str())
+			.ok_or(anyhow!("unable to name shared directory {}", root.display()))?;
+
+		return Ok(Self { name: Arc::from(name), root, include, exclude });
+	}
+
+	// relative_path uses / separators and no leading slash, directories only
+	// have to escape the exclude patterns so matching files below them show
+	pub fn is_shared(&self, relative_path: &str, is_dir: bool) -> bool {
+		if self.exclude.iter().any(|pattern| pattern.matches(relative_path)) { return false; }
+		if is_dir || self.include.is_empty() { return true; }
+		return self.include.iter().any(|pattern| pattern.matches(relative_path));
+	}
+
+	// whether a canonical path is inside the root and every directory on the
+	// way to it is shared, so a symlink can not reach what its target's own
+	// path would not
+	fn is_shared_target(&self, path: &std::path::Path) -> bool {
+		let relative_path = match path.strip_prefix(&self.root) {
+			Ok(relative_path) => relative_path,
+			Err(_) => return false,
+		};
+		let mut relative = String::new();
+		let mut components = relative_path.components().peekable();
+		while let Some(component) = components.next() {
+			let component = match component.as_os_str().to_str() {
+				Some(component) => component,
+				None => return false,
+			};
+			if relative.len() > 0 { relative.push('/'); }
+			relative.push_str(component);
+			let is_dir = components.peek().is_some() || path.is_dir();
+			if !self.is_shared(&relative, is_dir) { return false; }
+		}
+		return true;
+	}
+
+	// maps a relative path onto the disk, None when it leaves the root
+	// (through .. or a symlink) or passes through anything not shared
+	pub fn resolve(&self, relative_path: &str) -> Option<std::path::PathBuf> {
+		let mut path = self.root.clone();
+		let mut relative = String::new();
+		for component in relative_path.split('/').filter(|component| *component != "") {
+			if component == "." || component == ".." { return None; }
+			if relative.len() > 0 { relative.push('/'); }
+			relative.push_str(component);
+			path.push(component);
+
+			let is_dir = path.is_dir();
+			if !self.is_shared(&relative, is_dir) { return None; }
+		}
+
+		// NOTE the patterns are matched against where the path really leads
+		// as well, or a symlink to an excluded file would share it
+		let path = std::fs::canonicalize(path).ok()?;
+		if !self.is_shared_target(&path) { return None; }
+		return Some(path);
+	}
+
+	// the shared entries of a directory, subdirectories first
+	pub fn list(&self, relative_path: &str) -> Result<Vec<DirectoryEntry>> {
+		let directory = self.resolve(relative_path)
+			.ok_or(anyhow!("{} is not shared", relative_path))?;
+		let prefix = relative_path.trim_matches('/');
+
+		let mut entries = Vec::<DirectoryEntry>::new();
+		for entry in std::fs::read_dir(directory)? {
+			let entry = match entry { Ok(entry) => entry, Err(_) => continue };
+			let name = match entry.file_name().into_string() {
+				Ok(name) => name,
+				Err(_) => continue,
+			};
+			// NOTE symlinks are followed as long as they lead to something shared
+			if entry.file_type().map(|filetype| filetype.is_symlink()).unwrap_or(true) {
+				match std::fs::canonicalize(entry.path()) {
+					Ok(target) if self.is_shared_target(&target) => {},
+					_ => continue,
+				}
+			}
+			let metadata = match std::fs::metadata(entry.path()) {
+				Ok(metadata) => metadata,
+				Err(_) => continue,
+			};
+			if !metadata.is_dir() && !metadata.is_file() { continue; }
+
+			let relative = if prefix == "" { name.clone() } else { format!("{}/{}", prefix, name) };
+			if !self.is_shared(&relative, metadata.is_dir()) { continue; }
+
+			entries.push(DirectoryEntry {
+				name,
+				is_dir: metadata.is_dir(),
+				size: metadata.len(),
+				modified: metadata.modified().ok(),
+			});
+		}
+		entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+		return Ok(entries);
+	}
+
+	// every shared file of the whole tree with its relative path, and the
+	// directories that were walked to find them
+	pub fn walk(&self) -> (Vec<(String, DirectoryEntry)>, Vec<std::path::PathBuf>) {
+		let mut files = Vec::new();
+		let mut directories = Vec::new();
+		let mut pending = vec![String::new()];
+		while let Some(relative) = pending.pop() {
+			// NOTE a symlink back up the tree would otherwise be walked forever
+			match self.resolve(&relative) {
+				Some(path) if !directories.contains(&path) => directories.push(path),
+				_ => continue,
+			}
+			for entry in self.list(&relative).unwrap_or_default() {
+				let entry_path = if relative == "" { entry.name.clone() } else { format!("{}/{}", relative, entry.name) };
+				if entry.is_dir { pending.push(entry_path); }
+				else { files.push((entry_path, entry)); }
+			}
+		}
+		return (files, directories);
+	}
+
+	// number of shared files in the whole tree
+	pub fn count_files(&self) -> usize {
+		return self.walk().0.len();
+	}
+}
+
+// the file of a static directory (or the favicon) overriding filename, or
+// else the built-in copy
+fn static_file(config: &crate::config::Config, filename: &str) -> Option<Cow<'static, [u8]>> {
+	if filename.contains('/') { return None; }
 
-		return Ok(Playlist{ directory: Arc::from(playlist_dir), name: Arc::from(playlist_name), files: playlist_files });
+	let override_path = match (filename, &config.favicon_file, &config.static_dir) {
+		("favicon.ico", Some(path), _) => Some(path.clone()),
+		(_, _, Some(directory)) => Some(directory.join(filename)),
+		_ => None,
+	};
+	if let Some(path) = override_path {
+		match std::fs::read(&path) {
+			Ok(content) => return Some(Cow::Owned(content)),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+			Err(e) => println!("\rWARN: unable to read {}, using the built-in copy -> {}", path.display(), e),
+		}
 	}
+
+	return BUILTIN_STATIC_FILES.iter()
+		.find(|(name, _content)| *name == filename)
+		.map(|(_name, content)| Cow::Borrowed(*content));
+}
+
+// NOTE partials are looked up the same way, so a page from the static
+// directory can include a built-in partial and the other way around
+pub fn parse_template(config: &crate::config::Config, filename: &str) -> Result<crate::http::Template> {
+	let load = |name: &str| -> Result<Vec<u8>> {
+		return static_file(config, name)
+			.map(|content| content.into_owned())
+			.ok_or(anyhow!("no static file named {}", name));
+	};
+	return crate::http::Template::parse(&load(filename)?, &load)
+		.map_err(|e| anyhow!("failed to parse template {} -> {}", filename, e));
 }
 
+// NOTE compiled in so the binary runs from any directory, a static directory
+// given with --static-dir overrides them file by file
+const BUILTIN_STATIC_FILES: &[(&str, &[u8])] = &[
+	("index.html", include_bytes!("../static/index.html")),
+	("playlist.html", include_bytes!("../static/playlist.html")),
+	("browse.html", include_bytes!("../static/browse.html")),
+	("view.html", include_bytes!("../static/view.html")),
+	("head.html", include_bytes!("../static/head.html")),
+	("favicon.ico", include_bytes!("../favicon.ico")),
+];
+
 pub struct Globals {
 	file_entries: RwLock<FileDatabase>,
 	playlists: RwLock<Vec<Playlist>>,
-	peers: RwLock<Vec<std::net::IpAddr>>,
+	shared_directories: RwLock<Vec<SharedDirectory>>,
+	peers: RwLock<Vec<Peer>>,
+	// NOTE files, folders and playlists of the state file that could not be
+	// loaded at startup (on a drive that is not mounted, say), saved along
+	// with the rest so a restart while they are away does not forget them
+	offline: Mutex<crate::state::State>,
+	// NOTE serializes saves so an older snapshot never replaces a newer one
+	state_lock: Mutex<()>,
 	pub thread_pool:  Mutex<crate::ThreadPool<()>>,
-	pub static_files: FileDatabase,
-	pub favicon: memmap2::Mmap,
+	// NOTE parsed on first use, keyed by the static file name, and never
+	// cached while pages are read from a static directory
+	templates: RwLock<std::collections::HashMap<String, Arc<crate::http::Template>>>,
+	pub config: crate::config::Config,
 }
 
 impl Globals {
@@ -93,79 +477,204 @@ impl Globals {
 			.expect("Failed to get read guard from playlists RwLock");
 	}
 
-	pub fn read_peers(&self) -> RwLockReadGuard<Vec<std::net::IpAddr>> {
+	pub fn read_shared_directories(&self) -> RwLockReadGuard<Vec<SharedDirectory>> {
+		return self.shared_directories.read()
+			.expect("Failed to get read guard from shared directories RwLock");
+	}
+
+	pub fn push_shared_directory(&self, directory: SharedDirectory) -> Result<()> {
+		let entries = self.file_entries.read().expect("Failed to lock file entries for reading");
+		let mut directories = self.shared_directories.write()
+			.expect("Failed to lock shared directories for writing");
+		if directories.iter().any(|shared| shared.name == directory.name) {
+			bail!("a directory named {} is already shared", directory.name);
+		}
+		if entries.position(&directory.name).is_some() {
+			bail!("a file named {} is already hosted", directory.name);
+		}
+		directories.push(directory);
+		drop((entries, directories));
+
+		self.save_state();
+		return Ok(());
+	}
+
+	pub fn read_peers(&self) -> RwLockReadGuard<Vec<Peer>> {
 		return self.peers.read().expect("Failed to lock global peers for reading");
 	}
-	
-	pub fn get_file_entry_names(&self) -> Vec<Arc<str>> {
-		// let entries = self.file_entries;
-		let filenames = self.file_entries.read().unwrap().filenames.clone();
 
-		return filenames;
+	// NOTE unreachable peers are left to the health checks until they answer
+	pub fn reachable_peer_addresses(&self) -> Vec<std::net::SocketAddr> {
+		return self.read_peers().iter()
+			.filter(|peer| peer.is_trusted() && peer.health.is_reachable())
+			.map(|peer| peer.addr)
+			.collect();
+	}
+
+	// the peers due for a check, which are not due again until that check
+	// is recorded (or PEER_CHECK_INTERVAL passed without it)
+	pub fn claim_due_peers(&self, now: std::time::Instant) -> Vec<std::net::SocketAddr> {
+		let mut peers = self.peers.write().expect("failed to lock peers for writing");
+		return peers.iter_mut()
+			.filter(|peer| peer.is_trusted() && peer.health.is_due(now))
+			.map(|peer| {
+				peer.health.next_check = Some(now + PEER_CHECK_INTERVAL);
+				return peer.addr;
+			})
+			.collect();
 	}
 
-	pub fn get_file_entry_by_name(&self, name: &str) -> Option<Arc<memmap2::Mmap>> {
+	// records how a request to addr went, its latency when it was answered,
+	// returning the failures before it. None when addr is no longer a peer
+	pub fn record_peer_check(&self, addr: std::net::SocketAddr, latency: Option<std::time::Duration>) -> Option<u32> {
+		let mut peers = self.peers.write().expect("failed to lock peers for writing");
+		let peer = peers.iter_mut().find(|peer| peer.addr == addr)?;
+		let previous_failures = peer.health.failures;
+		let now = std::time::Instant::now();
+		match latency {
+			Some(latency) => peer.health.succeeded(latency, now),
+			None => peer.health.failed(now),
+		}
+		return Some(previous_failures);
+	}
+	
+	pub fn get_file_entry_by_name(&self, name: &str) -> Option<Arc<FileContent>> {
 		let entries = self.file_entries.read().unwrap();
+		return entries.get(name).map(|entry| entry.content.clone());
+	}
 
-		let (index, _filename) = match entries.filenames
-			.iter()
-			.enumerate()
-			.find(|(_iter, filename)| filename.as_ref() == name) {
-			Some(pair) => pair,
-			None => return None
+	pub fn push_file_entry<P: AsRef<std::path::Path>>(&self, name: &str, fpath: P) -> Result<()> {
+		let entry = FileEntry::open(name, fpath, self.config.file_access())?;
+
+		let mut entries = self.file_entries.write().unwrap();
+		if self.read_shared_directories().iter().any(|directory| directory.name.as_ref() == name) {
+			bail!("a directory named {} is already shared", name);
+		}
+		entries.push_entry(entry)?;
+		drop(entries);
+
+		self.save_state();
+		return Ok(());
+	}
+
+	// removes a hosted file or shared directory by name, false when there
+	// is nothing by that name
+	pub fn remove_entry(&self, name: &str) -> bool {
+		let removed = self.file_entries.write().expect("Failed to lock file entries for writing")
+			.remove(name).is_some() || {
+			let mut directories = self.shared_directories.write()
+				.expect("Failed to lock shared directories for writing");
+			let count = directories.len();
+			directories.retain(|directory| directory.name.as_ref() != name);
+			directories.len() != count
+		} || {
+			let mut offline = self.offline.lock().expect("Failed to lock offline entries");
+			let count = offline.files.len() + offline.folders.len();
+			offline.files.retain(|file| file.name != name);
+			offline.folders.retain(|folder| folder.name != name);
+			offline.files.len() + offline.folders.len() != count
 		};
 
-		return entries.file_contents.get(index).cloned();
+		if removed { self.save_state(); }
+		return removed;
 	}
 
-	pub fn push_file_entry<P: AsRef<std::path::Path>>(&self, name: &str, fpath: P) -> Result<()> {
+	// renames a hosted file or shared directory, false when old_name is
+	// not hosted
+	pub fn rename_entry(&self, old_name: &str, new_name: &str) -> Result<bool> {
+		if new_name == "" || new_name.contains('\n') || new_name.contains('\t') {
+			bail!("invalid name {:?}", new_name);
+		}
 
-		let file = std::fs::File::open(fpath.as_ref())?;
-		let filemap = unsafe{ memmap2::Mmap::map(&file) }?;
+		let renamed = self.rename_entry_locked(old_name, new_name)?;
+		if renamed { self.save_state(); }
+		return Ok(renamed);
+	}
 
-		let mut entries = self.file_entries.write().unwrap();
+	fn rename_entry_locked(&self, old_name: &str, new_name: &str) -> Result<bool> {
+		// NOTE both locks are held so the new name cannot be taken in between
+		let mut entries = self.file_entries.write().expect("Failed to lock file entries for writing");
+		let mut directories = self.shared_directories.write()
+			.expect("Failed to lock shared directories for writing");
 
-		entries.filenames.push(Arc::from(name));
-		entries.file_contents.push(Arc::from(filemap));
+		// NOTE names are unique across files and directories so remove and
+		// rename are never ambiguous
+		if entries.position(new_name).is_some() { bail!("a file named {} is already hosted", new_name); }
+		if directories.iter().any(|directory| directory.name.as_ref() == new_name) {
+			bail!("a directory named {} is already shared", new_name);
+		}
 
-		return Ok(());
+		if entries.rename(old_name, new_name)? { return Ok(true); }
+		if let Some(directory) = directories.iter().position(|directory| directory.name.as_ref() == old_name) {
+			if new_name.contains('/') { bail!("shared directory names cannot contain /"); }
+			directories[directory].name = Arc::from(new_name);
+			return Ok(true);
+		}
+		return Ok(false);
 	}
 
 	pub fn push_playlist_directory(&self, dirname: &str) -> Result<()> {
-		
-		let playlist_name = dirname.split('/').rev().next()
-			.unwrap_or(dirname);
-
-		let mut playlist = FileDatabase::new();
-		
-		for entry in std::fs::read_dir(dirname)? {
-			if let Ok(entry) = entry {
-				if entry.file_type()?.is_file() {
-					match std::fs::File::open(entry.path()) {
-						Ok(file) => {
-							playlist.file_contents.push(Arc::from(unsafe{ memmap2::Mmap::map(&file) }?));
-						},
-						Err(e) => {
-							println!("WARN: failed to add {} to playlist files", e);
-						}
-					}
+		let playlist = Playlist::from_directory(dirname, self.config.file_access())?;
 
-					playlist.filenames.push(Arc::from(
-						entry.file_name().to_str().expect("Failed to convert OsString to &str")
-					));
-				}
-			}
+		let mut playlists = self.playlists.write().unwrap();
+		if playlists.iter().any(|existing| existing.name == playlist.name) {
+			bail!("a playlist named {} already exists", playlist.name);
 		}
+		playlists.push(playlist);
+		drop(playlists);
 
-		let mut playlists = self.playlists.write().unwrap();
+		self.save_state();
+		return Ok(());
+	}
 
+	// brings a playlist in line with the files now in its directory, the
+	// files that are still there keep their entries (and mappings)
+	pub fn refresh_playlist(&self, name: &str, paths: &[std::path::PathBuf]) {
+		let access = self.config.file_access();
+		let mut playlists = self.playlists.write().expect("Failed to lock playlists for writing");
+		let playlist = match playlists.iter_mut().find(|playlist| playlist.name.as_ref() == name) {
+			Some(playlist) => playlist,
+			None => return,
+		};
 
-		playlists.push(Playlist{ directory: Arc::from(dirname), name: Arc::from(playlist_name), files: playlist });
+		let mut files = FileDatabase::with_access(access);
+		for path in paths {
+			// NOTE skipped without a warning, that was given when it was added
+			let filename = match path.to_str() { Some(filename) => filename, None => continue };
+			let entry = match playlist.files.get(filename) {
+				Some(entry) => FileEntry { name: entry.name.clone(), path: entry.path.clone(), content: entry.content.clone() },
+				None => match FileEntry::open(filename, path, access) {
+					Ok(entry) => entry,
+					Err(e) => {
+						println!("\rWARN: skipping {} -> {}", filename, e);
+						continue;
+					}
+				},
+			};
+			if let Err(e) = files.push_entry(entry) {
+				println!("\rWARN: skipping {} -> {}", filename, e);
+			}
+		}
+		playlist.files = files;
+	}
 
-		return Ok(());
+	pub fn remove_playlist(&self, name: &str) -> bool {
+		let mut playlists = self.playlists.write().expect("Failed to lock playlists for writing");
+		let count = playlists.len();
+		playlists.retain(|playlist| playlist.name.as_ref() != name);
+		let removed = playlists.len() != count || {
+			let mut offline = self.offline.lock().expect("Failed to lock offline entries");
+			let count = offline.playlists.len();
+			offline.playlists.retain(|directory| playlist_name(&directory.to_string_lossy()) != name);
+			offline.playlists.len() != count
+		};
+		drop(playlists);
+
+		if removed { self.save_state(); }
+		return removed;
 	}
 
-	pub fn get_song_by_playlist_and_index(&self, playlist_name: &str, song_number: u32) -> Option<Arc<memmap2::Mmap>> {
+	pub fn get_song_by_playlist_and_index(&self, playlist_name: &str, song_number: u32) -> Option<Arc<FileContent>> {
 		let playlists = self.playlists.read().unwrap();
 
 		let index = match playlists.iter()
@@ -182,27 +691,161 @@ impl Globals {
 			None => return None
 		};
 
-		let songmap = playlist.files.file_contents.get(song_number as usize);
+		return playlist.files.get_index(song_number as usize).map(|song| song.content.clone());
+	}
 
-		return songmap.cloned();
+	// a page or the favicon, read from the override directory or file when
+	// one is configured and has it, the built-in copy otherwise
+	pub fn get_static_file(&self, filename: &str) -> Option<Cow<'static, [u8]>> {
+		return static_file(&self.config, filename);
 	}
 
-	pub fn get_static_file(&self, filename: &str) -> Option<Arc<memmap2::Mmap>> {
+	// the page template in the static directory named filename, partials it
+	// includes come from the static directory as well
+	pub fn get_template(&self, filename: &str) -> Result<Arc<crate::http::Template>> {
+		// NOTE pages in a static directory are parsed for every request so
+		// edits show up on reload
+		let cache = self.config.static_dir.is_none();
+		if cache {
+			if let Some(template) = self.templates.read().expect("Failed to get read guard from templates RwLock").get(filename) {
+				return Ok(template.clone());
+			}
+		}
 
-		let index = match self.static_files
-			.filenames.iter()
-			.enumerate()
-			.find(|(_iter, fpath)| fpath.ends_with(filename))
-		{
-			Some((index, _fname)) => index,
-			None => return None
+		let template = Arc::new(parse_template(&self.config, filename)?);
+		if cache {
+			self.templates.write().expect("Failed to get write guard from templates RwLock")
+				.insert(filename.to_owned(), template.clone());
+		}
+		return Ok(template);
+	}
+
+	// parses `ip` or `ip:port` (`[ip]:port` for ipv6), a peer without a port
+	// is assumed to use the same port as this instance
+	pub fn parse_peer_address(&self, source: &str) -> Result<std::net::SocketAddr> {
+		let mut addr = match source.parse::<std::net::SocketAddr>() {
+			Ok(addr) => addr,
+			Err(_) => std::net::SocketAddr::new(
+				source.parse::<std::net::IpAddr>().map_err(|e| anyhow!("invalid peer address {} -> {}", source, e))?,
+				self.config.port
+			),
+		};
+		// NOTE so ::ffff:192.168.1.20 and 192.168.1.20 are the same peer
+		addr.set_ip(addr.ip().to_canonical());
+		return Ok(addr);
+	}
+
+	// the peer named source, or else at the address it parses to
+	pub fn find_peer(&self, source: &str) -> Result<std::net::SocketAddr> {
+		let named = self.read_peers().iter()
+			.filter(|peer| peer.name.as_deref() == Some(source))
+			.map(|peer| peer.addr)
+			.collect::<Vec<std::net::SocketAddr>>();
+		return match named.as_slice() {
+			[addr] => Ok(*addr),
+			[] => self.parse_peer_address(source),
+			_ => Err(anyhow!("{} peers are named {}, give an address instead", named.len(), source)),
 		};
+	}
+
+	// NOTE adding a discovered peer keeps it after it goes quiet
+	pub fn push_peer(&self, addr: std::net::SocketAddr) {
+		let mut peers = self.peers.write().expect("failed to lock peers for writing");
+		match peers.iter_mut().find(|peer| peer.addr == addr) {
+			Some(peer) if peer.origin == PeerOrigin::Added => return,
+			Some(peer) => peer.origin = PeerOrigin::Added,
+			None => peers.push(Peer::added(addr)),
+		}
+		drop(peers);
+
+		self.save_state();
+	}
+
+	// NOTE a discovered peer is found again by its next announcement
+	pub fn remove_peer(&self, addr: std::net::SocketAddr) -> Option<Peer> {
+		let mut peers = self.peers.write().expect("failed to lock peers for writing");
+		let position = peers.iter().position(|peer| peer.addr == addr);
+		let removed = position.map(|position| peers.remove(position));
+		drop(peers);
 
-		return self.static_files.file_contents.get(index).cloned();
+		if removed.as_ref().is_some_and(|peer| peer.origin == PeerOrigin::Added) { self.save_state(); }
+		return removed;
 	}
 
-	pub fn push_peer(&self, peer: std::net::IpAddr) {
-		self.peers.write().expect("failed to lock peers for writing").push(peer);
+	// records an announcement from addr, which holds for valid_for, returning
+	// whether the peer is new. NOTE new peers past MAX_DISCOVERED_PEERS are
+	// ignored until others expire
+	pub fn saw_peer(&self, addr: std::net::SocketAddr, name: &str, valid_for: std::time::Duration) -> bool {
+		let mut peers = self.peers.write().expect("failed to lock peers for writing");
+		let now = std::time::Instant::now();
+		match peers.iter_mut().find(|peer| peer.addr == addr) {
+			Some(peer) => {
+				peer.name = Some(name.to_owned());
+				peer.last_seen = Some(now);
+				peer.expires = Some(now + valid_for);
+				return false;
+			},
+			None => {
+				let discovered = peers.iter().filter(|peer| peer.origin == PeerOrigin::Discovered).count();
+				if discovered >= MAX_DISCOVERED_PEERS { return false; }
+				peers.push(Peer {
+					addr, origin: PeerOrigin::Discovered, name: Some(name.to_owned()),
+					last_seen: Some(now), expires: Some(now + valid_for), health: PeerHealth::default(),
+				});
+				return true;
+			}
+		}
+	}
+
+	// NOTE added peers stay, only the user removes them
+	pub fn forget_discovered_peer(&self, addr: std::net::SocketAddr) -> Option<Peer> {
+		let mut peers = self.peers.write().expect("failed to lock peers for writing");
+		let position = peers.iter().position(|peer| peer.addr == addr && peer.origin == PeerOrigin::Discovered)?;
+		return Some(peers.remove(position));
+	}
+
+	// forgets the discovered peers whose announcements ran out, returning them
+	pub fn expire_peers(&self, now: std::time::Instant) -> Vec<Peer> {
+		let mut peers = self.peers.write().expect("failed to lock peers for writing");
+		let (expired, kept) = std::mem::take(&mut *peers).into_iter()
+			.partition(|peer| peer.origin == PeerOrigin::Discovered
+				&& peer.expires.is_none_or(|expires| expires <= now));
+		*peers = kept;
+		return expired;
+	}
+
+	pub fn snapshot_state(&self) -> crate::state::State {
+		let mut state = crate::state::State::default();
+		for entry in self.read_file_entries().iter() {
+			state.files.push(crate::state::FileState { name: entry.name.to_string(), path: entry.path.clone() });
+		}
+		for directory in self.read_shared_directories().iter() {
+			state.folders.push(crate::state::FolderState {
+				name: directory.name.to_string(),
+				path: directory.root.clone(),
+				include: directory.include.iter().map(|glob| glob.as_str().to_owned()).collect(),
+				exclude: directory.exclude.iter().map(|glob| glob.as_str().to_owned()).collect(),
+			});
+		}
+		for playlist in self.read_playlists().iter() {
+			state.playlists.push(std::path::PathBuf::from(playlist.directory.as_ref()));
+		}
+		state.peers = self.read_peers().iter()
+			.filter(|peer| peer.origin == PeerOrigin::Added)
+			.map(|peer| peer.addr)
+			.collect();
+		keep_offline(&mut state, &self.offline.lock().expect("Failed to lock offline entries"));
+		return state;
+	}
+
+	// writes the state file, called after every change so nothing is lost
+	// when the process is killed
+	pub fn save_state(&self) {
+		let _guard = self.state_lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+		let json = self.snapshot_state().to_json();
+		if let Err(e) = crate::state::write_atomically(&self.config.state_file, json.as_bytes()) {
+			println!("\rError: failed to save state to {} -> {}", self.config.state_file.display(), e);
+		}
 	}
 
 	pub fn push_thread<T: FnOnce() -> Result<()> + Send + 'static>(&self, closure: T) {
@@ -210,59 +853,309 @@ impl Globals {
 	}
 }
 
+// adds the entries that could not be loaded to a snapshot of the loaded
+// ones, unless one of those took their name (or directory) since
+fn keep_offline(state: &mut crate::state::State, offline: &crate::state::State) {
+	for file in offline.files.iter() {
+		if state.files.iter().any(|other| other.name == file.name) { continue; }
+		state.files.push(crate::state::FileState { name: file.name.clone(), path: file.path.clone() });
+	}
+	for folder in offline.folders.iter() {
+		if state.folders.iter().any(|other| other.name == folder.name) { continue; }
+		state.folders.push(crate::state::FolderState {
+			name: folder.name.clone(),
+			path: folder.path.clone(),
+			include: folder.include.clone(),
+			exclude: folder.exclude.clone(),
+		});
+	}
+	for directory in offline.playlists.iter() {
+		if state.playlists.contains(directory) { continue; }
+		state.playlists.push(directory.clone());
+	}
+}
+
 pub static GLOBALS: std::sync::LazyLock<Globals> = std::sync::LazyLock::new(|| {
-	let favicon_file = std::fs::File::open("favicon.ico").expect("Failed to open favicon.ico");
-	let favicon = unsafe{ memmap2::Mmap::map(&favicon_file) }.expect("Failed to map favicon.ico into memory");
-
-	let file_entries = match std::fs::read("entries.txt") {
-		Ok(filestring) => {
-			let mut file_entries = FileDatabase::new();
-			for line in unsafe{ filestring.as_ascii_unchecked() }.as_str().split('\n') {
-				if line == "" { continue; }
-				if let Err(e) = file_entries.add_file(line) {
-					println!("File mapping failed -> {}", e);
-					continue;
-				}
-			}
-			file_entries
-		},
+	let config = crate::config::startup_config();
+
+	let state = match crate::state::State::load(&config) {
+		Ok(state) => state,
 		Err(e) => {
-			println!("WARN: Failed to open entries file");
-			FileDatabase::new()
+			// NOTE refusing to start keeps the next save from overwriting it
+			eprintln!("\rError: unable to load state -> {}", e);
+			std::process::exit(1);
 		}
 	};
+	let imported = !std::fs::exists(&config.state_file).unwrap_or(true)
+		&& (config.entries_file.exists() || config.playlists_file.exists());
 
-	let playlists = match std::fs::read("playlists.txt") {
-		Ok(filestring) => {
-			let mut playlists = Vec::<Playlist>::new();
-			for line in unsafe{ filestring.as_ascii_unchecked() }.as_str().split('\n') {
-				if line == "" { continue; }
-				match Playlist::from_directory(line) {
-					Ok(playlist) => playlists.push(playlist),
-					Err(e) => println!("WARN: unable to create playlist from {} -> {}", line, e)
-				}
+	let mut offline = crate::state::State::default();
+	let mut file_entries = FileDatabase::with_access(config.file_access());
+	for file in state.files.into_iter() {
+		let entry = match FileEntry::open(&file.name, &file.path, config.file_access()) {
+			Ok(entry) => entry,
+			Err(e) => {
+				println!("\rWARN: unable to host {}, keeping it in the state file -> {}", file.name, e);
+				offline.files.push(file);
+				continue;
 			}
-			playlists
-		},
-		Err(_) => {
-			println!("WARN: Failed to open playlists file");
-			Vec::<Playlist>::new()
+		};
+		if let Err(e) = file_entries.push_entry(entry) {
+			println!("\rWARN: unable to host {} -> {}", file.name, e);
 		}
+	}
+
+	let patterns = |globs: &[String]| -> Result<Vec<crate::glob::GlobPattern>> {
+		return globs.iter().map(|glob| crate::glob::GlobPattern::new(glob)).collect();
 	};
+	let mut shared_directories = Vec::<SharedDirectory>::new();
+	for folder in state.folders.into_iter() {
+		let directory = patterns(&folder.include).and_then(|include| {
+			SharedDirectory::new(&folder.path.to_string_lossy(), include, patterns(&folder.exclude)?)
+		});
+		match directory {
+			Ok(mut directory) => {
+				directory.name = Arc::from(folder.name.as_str());
+				shared_directories.push(directory);
+			},
+			Err(e) => {
+				println!("\rWARN: unable to share directory {}, keeping it in the state file -> {}", folder.path.display(), e);
+				offline.folders.push(folder);
+			},
+		}
+	}
 
-	let mut static_files = FileDatabase::new();
-	static_files.add_directory_nonrecursive("./static")
-		.expect("Failed to map static files directory into memory");
+	let mut playlists = Vec::<Playlist>::new();
+	for directory in state.playlists.into_iter() {
+		match Playlist::from_directory(&directory.to_string_lossy(), config.file_access()) {
+			Ok(playlist) if playlists.iter().any(|existing| existing.name == playlist.name) => {
+				println!("\rWARN: skipping playlist {}, a playlist named {} already exists", directory.display(), playlist.name);
+			},
+			Ok(playlist) => playlists.push(playlist),
+			Err(e) => {
+				println!("\rWARN: unable to create playlist from {}, keeping it in the state file -> {}", directory.display(), e);
+				offline.playlists.push(directory);
+			},
+		}
+	}
+
+	if let Some(directory) = &config.static_dir {
+		if directory.is_dir() {
+			println!("\rINFO: serving pages from {}, falling back to the built-in ones", directory.display());
+		}else {
+			println!("\rWARN: static directory {} does not exist, serving the built-in pages", directory.display());
+		}
+	}
 	
-	return Globals {
+	// NOTE older state files could list a peer more than once
+	let mut peers = Vec::<Peer>::new();
+	for addr in state.peers {
+		if !peers.iter().any(|peer| peer.addr == addr) { peers.push(Peer::added(addr)); }
+	}
+
+	let globals = Globals {
 		file_entries: RwLock::new(file_entries),
 		playlists: RwLock::new(playlists),
-		peers: RwLock::new(vec![
-			// std::net::IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 12, 182)),
-		]),
+		shared_directories: RwLock::new(shared_directories),
+		peers: RwLock::new(peers),
+		offline: Mutex::new(offline),
+		state_lock: Mutex::new(()),
 		thread_pool: Mutex::new(crate::ThreadPool::new()),
-		static_files,
-		favicon
+		templates: RwLock::new(std::collections::HashMap::new()),
+		config,
 	};
+	if imported { globals.save_state(); }
+
+	return globals;
 });
 
+
+
+#[cfg(test)]
+mod tests {
+	use crate::content::FileAccess;
+
+	#[test]
+	fn test_file_database_index() {
+		let directory = std::env::temp_dir().join(format!("localshare-catalog-{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		for name in ["a.txt", "b.txt", "c.txt"] {
+			std::fs::write(directory.join(name), name).unwrap();
+		}
+		let path = |name: &str| directory.join(name);
+		let read = |entry: &super::FileEntry| {
+			let reader = entry.content.reader().unwrap();
+			let mut content = Vec::<u8>::new();
+			reader.write_range(&mut content, 0, reader.len() - 1).unwrap();
+			return content;
+		};
+
+		let mut database = super::FileDatabase::with_access(FileAccess::Mapped);
+		for name in ["a.txt", "b.txt", "c.txt"] {
+			database.push_entry(super::FileEntry::open(name, path(name), FileAccess::Mapped).unwrap()).unwrap();
+		}
+		assert_eq!(database.get("b.txt").unwrap().content.reader().unwrap().len(), 5);
+
+		// NOTE the same name, or the same file under another name, is refused
+		assert!(database.push_entry(super::FileEntry::open("a.txt", path("b.txt"), FileAccess::Mapped).unwrap()).is_err());
+		assert!(database.push_entry(super::FileEntry::open("alias", path("a.txt"), FileAccess::Lazy).unwrap()).is_err());
+		assert!(database.push_entry(super::FileEntry::open("alias", directory.join(".").join("a.txt"), FileAccess::Mapped).unwrap()).is_err());
+
+		// NOTE the other entries keep their index until the empty slots are
+		// compacted, which happens once they outnumber the entries
+		assert!(database.remove("a.txt").is_some());
+		assert!(database.remove("a.txt").is_none());
+		assert_eq!(database.position("c.txt"), Some(2));
+		assert_eq!(read(database.get("c.txt").unwrap()), b"c.txt");
+		assert!(database.get_index(0).is_none());
+		assert_eq!(database.get_index(1).unwrap().name.as_ref(), "b.txt");
+
+		assert!(database.rename("b.txt", "c.txt").is_err());
+		assert!(database.rename("b.txt", "notes").unwrap());
+		assert!(database.get("b.txt").is_none());
+		assert_eq!(read(database.get("notes").unwrap()), b"b.txt");
+
+		// NOTE a removed file can be added again
+		database.push_entry(super::FileEntry::open("a.txt", path("a.txt"), FileAccess::Lazy).unwrap()).unwrap();
+		assert_eq!(database.iter().map(|entry| entry.name.as_ref()).collect::<Vec<&str>>(), ["notes", "c.txt", "a.txt"]);
+
+		assert!(database.remove("c.txt").is_some());
+		assert!(database.remove("notes").is_some());
+		assert_eq!(database.position("a.txt"), Some(0));
+		assert_eq!(read(database.get("a.txt").unwrap()), b"a.txt");
+		assert!(database.push_entry(super::FileEntry::open("b.txt", path("b.txt"), FileAccess::Lazy).unwrap()).is_ok());
+		assert_eq!(database.iter().map(|entry| entry.name.as_ref()).collect::<Vec<&str>>(), ["a.txt", "b.txt"]);
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn test_file_database_remove_missing_file() {
+		let directory = std::env::temp_dir().join(format!("localshare-missing-{}", std::process::id()));
+		std::fs::create_dir_all(directory.join("real")).unwrap();
+		std::os::unix::fs::symlink(directory.join("real"), directory.join("link")).unwrap();
+		let path = directory.join("link").join("x.txt");
+		let open = |name: &str, path: &std::path::Path| super::FileEntry::open(name, path, FileAccess::Lazy).unwrap();
+
+		let mut database = super::FileDatabase::with_access(FileAccess::Lazy);
+		for name in ["y", "z"] {
+			std::fs::write(directory.join("real").join(name), name).unwrap();
+			database.push_entry(open(name, &directory.join("real").join(name))).unwrap();
+		}
+		std::fs::write(&path, "x").unwrap();
+		database.push_entry(open("x", &path)).unwrap();
+
+		// NOTE the file is gone when it is removed, its path can not be
+		// resolved the way it was when it was added
+		std::fs::remove_file(&path).unwrap();
+		assert!(database.remove("x").is_some());
+		std::fs::write(&path, "x").unwrap();
+		database.push_entry(open("x", &path)).unwrap();
+
+		// NOTE the same once the removed slots were compacted
+		std::fs::remove_file(&path).unwrap();
+		assert!(database.remove("x").is_some());
+		assert!(database.remove("y").is_some());
+		assert_eq!(database.position("z"), Some(0));
+		std::fs::write(&path, "x").unwrap();
+		database.push_entry(open("x", &path)).unwrap();
+		assert!(database.push_entry(open("again", &directory.join("real").join("x.txt"))).is_err());
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn test_shared_directory_symlinks() {
+		let directory = std::env::temp_dir().join(format!("localshare-shared-{}", std::process::id()));
+		std::fs::create_dir_all(directory.join("secret")).unwrap();
+		std::fs::write(directory.join("notes.txt"), "notes").unwrap();
+		std::fs::write(directory.join("key.pem"), "key").unwrap();
+		std::fs::write(directory.join("secret").join("plans.txt"), "plans").unwrap();
+		std::os::unix::fs::symlink(directory.join("key.pem"), directory.join("key.txt")).unwrap();
+		std::os::unix::fs::symlink(directory.join("secret"), directory.join("public")).unwrap();
+		std::os::unix::fs::symlink(directory.join("notes.txt"), directory.join("link.txt")).unwrap();
+
+		let exclude = ["*.pem", "secret"].map(|pattern| crate::glob::GlobPattern::new(pattern).unwrap()).to_vec();
+		let shared = super::SharedDirectory::new(&directory.to_string_lossy(), vec![], exclude).unwrap();
+		assert!(shared.resolve("notes.txt").is_some());
+		assert!(shared.resolve("key.pem").is_none());
+		// NOTE a link to a shared file is followed, one to an excluded file or
+		// into an excluded directory is not
+		assert_eq!(shared.resolve("link.txt"), Some(std::fs::canonicalize(directory.join("notes.txt")).unwrap()));
+		assert!(shared.resolve("key.txt").is_none());
+		assert!(shared.resolve("public/plans.txt").is_none());
+		let mut names = shared.list("").unwrap().into_iter().map(|entry| entry.name).collect::<Vec<String>>();
+		names.sort();
+		assert_eq!(names, ["link.txt", "notes.txt"]);
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn test_static_file_overrides() {
+		let directory = std::env::temp_dir().join(format!("localshare-static-{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		std::fs::write(directory.join("index.html"), "<p>%title%</p>{% include \"head.html\" %}").unwrap();
+		std::fs::write(directory.join("icon.ico"), "icon").unwrap();
+		let builtin = |name: &str| super::BUILTIN_STATIC_FILES.iter().find(|(builtin, _content)| *builtin == name).unwrap().1;
+
+		let mut config = crate::config::Config::default();
+		assert_eq!(super::static_file(&config, "index.html").unwrap().as_ref(), builtin("index.html"));
+
+		config.static_dir = Some(directory.clone());
+		config.favicon_file = Some(directory.join("icon.ico"));
+		assert_eq!(super::static_file(&config, "index.html").unwrap().as_ref(), b"<p>%title%</p>{% include \"head.html\" %}");
+		assert_eq!(super::static_file(&config, "favicon.ico").unwrap().as_ref(), b"icon");
+		// NOTE a file the static directory does not have falls back to the built-in copy
+		assert_eq!(super::static_file(&config, "head.html").unwrap().as_ref(), builtin("head.html"));
+		assert!(super::static_file(&config, "missing.html").is_none());
+		assert!(super::static_file(&config, "../index.html").is_none());
+
+		// NOTE the overriding page includes the built-in partial
+		let mut page = Vec::<u8>::new();
+		super::parse_template(&config, "index.html").unwrap().render(&[("title", "hi".into())], &mut page).unwrap();
+		assert_eq!(String::from_utf8(page).unwrap(), "<p>hi</p>  <meta charset=\"UTF-8\">\n  <title>hi</title>\n");
+		assert!(super::parse_template(&config, "missing.html").is_err());
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn test_keep_offline() {
+		use crate::state::{FileState, State};
+
+		let file = |name: &str, path: &str| FileState { name: name.to_owned(), path: path.into() };
+		let offline = State {
+			files: vec![file("notes", "/mnt/usb/notes.txt"), file("taken", "/mnt/usb/old.txt")],
+			playlists: vec!["/mnt/usb/music".into()],
+			..State::default()
+		};
+		let mut state = State { files: vec![file("taken", "/home/new.txt")], ..State::default() };
+		super::keep_offline(&mut state, &offline);
+
+		// NOTE an entry that could not be loaded is saved again as it was,
+		// unless a loaded one took its name since
+		assert_eq!(state.files, [file("taken", "/home/new.txt"), file("notes", "/mnt/usb/notes.txt")]);
+		assert_eq!(state.playlists, [std::path::PathBuf::from("/mnt/usb/music")]);
+	}
+
+	#[test]
+	fn test_peer_health() {
+		let mut health = super::PeerHealth::default();
+		let now = std::time::Instant::now();
+		assert!(health.is_reachable() && health.is_due(now));
+
+		let backoffs = (0..8).map(|_| {
+			health.failed(now);
+			return health.next_check.unwrap() - now;
+		}).collect::<Vec<std::time::Duration>>();
+		assert_eq!(backoffs[..3], [super::PEER_CHECK_INTERVAL, super::PEER_CHECK_INTERVAL * 2, super::PEER_CHECK_INTERVAL * 4]);
+		assert_eq!(backoffs[7], super::PEER_CHECK_MAX_BACKOFF);
+		assert!(!health.is_reachable() && !health.is_due(now));
+
+		health.succeeded(std::time::Duration::from_millis(3), now);
+		assert!(health.is_reachable());
+		assert_eq!(health.failures, 0);
+		assert_eq!(health.next_check, Some(now + super::PEER_CHECK_INTERVAL));
+	}
+}
@@ -0,0 +1,73 @@
+
+use anyhow::Result;
+
+use crate::{globals::GLOBALS, http::client::ClientOptions};
+
+
+// NOTE peers are checked by this many threads at most, however many there are
+const CHECK_WORKERS: usize = 4;
+
+// NOTE shorter than the client's defaults, so that a dead peer is found out
+// (and the index page waits for it) no longer than this. a peer answering
+// /files with a redirect is not one worth following
+pub fn peer_request_options() -> ClientOptions {
+	return ClientOptions {
+		connect_timeout: std::time::Duration::from_secs(3),
+		read_timeout: std::time::Duration::from_secs(5),
+		max_redirects: 0,
+		..Default::default()
+	};
+}
+
+// asks addr for its file list, returning how long the answer took
+fn check_peer(addr: std::net::SocketAddr) -> Result<std::time::Duration> {
+	let start = std::time::Instant::now();
+	// NOTE the head is enough to know it answers, the body is not read
+	let response = crate::http::client::get(addr, "/files", &[], &peer_request_options())?;
+	if !response.is_success() {
+		bail!("{} answered /files with {} {}", addr, response.status_code, response.status_text);
+	}
+	return Ok(start.elapsed());
+}
+
+// records the outcome of a request to a peer, logging when it stops or
+// starts answering
+pub fn record_peer_request(addr: std::net::SocketAddr, outcome: Result<std::time::Duration, &anyhow::Error>) {
+	let previous_failures = match GLOBALS.record_peer_check(addr, outcome.ok()) {
+		Some(previous_failures) => previous_failures,
+		None => return,
+	};
+	match outcome {
+		Err(e) if previous_failures == 0 => println!("\rWARN: peer {} is unreachable -> {}", addr, e),
+		Ok(_) if previous_failures > 0 => println!("\rINFO: peer {} is reachable again", addr),
+		_ => {},
+	}
+}
+
+// hands the peers that are due to CHECK_WORKERS threads. each peer keeps its
+// own schedule, so one that does not answer only delays the peers queued
+// behind it while every worker is busy
+pub fn run_health_checks() -> Result<()> {
+	let (sender, receiver) = std::sync::mpsc::channel::<std::net::SocketAddr>();
+	let receiver = std::sync::Arc::new(std::sync::Mutex::new(receiver));
+	for _ in 0..CHECK_WORKERS {
+		let receiver = receiver.clone();
+		GLOBALS.push_thread(move || {
+			loop {
+				// NOTE the lock is only held while waiting, not while checking
+				let addr = match receiver.lock().expect("failed to lock the health check queue").recv() {
+					Ok(addr) => addr,
+					Err(_) => return Ok(()),
+				};
+				record_peer_request(addr, check_peer(addr).as_ref().copied());
+			}
+		});
+	}
+
+	loop {
+		for addr in GLOBALS.claim_due_peers(std::time::Instant::now()) {
+			sender.send(addr)?;
+		}
+		std::thread::sleep(std::time::Duration::from_secs(1));
+	}
+}
@@ -3,6 +3,8 @@ use std::{any::Any, io::{ Read, Write }};
 
 use anyhow::Result;
 
+pub mod client;
+
 
 #[derive(Clone, Copy, Debug)]
 pub enum HttpMethod {
@@ -27,18 +29,47 @@ impl HttpMethod {
 	}
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub enum HttpHeader<'a> {
 	ContentType(ContentType),
 	TransferEncoding(TransferEncoding),
 	ContentDisposition(ContentDisposition<'a>),
 	ContentLength(usize),
+	AcceptRanges,
+	ContentRange(ContentRange),
+	// NOTE raw value of the Range header, see ByteRange::parse_ranges
+	Range(&'a str),
+	// NOTE a multipart/form-data Content-Type, holding its boundary (owned
+	// when it was sent quoted)
+	MultipartFormData(std::borrow::Cow<'a, str>),
+	Location(&'a str),
+	Connection(ConnectionOption),
+	// NOTE raw value, e.g. `Bearer <token>`
+	Authorization(&'a str),
+	// NOTE raw value, see HttpRequest::prefers_json
+	Accept(&'a str),
+	// NOTE the methods a route answers to, sent with a 405
+	Allow(&'a str),
+	ContentSecurityPolicy(&'a str),
+	Host(&'a str),
+	// NOTE X-Content-Type-Options: nosniff, browsers keep to the sent type
+	NoSniff,
 }
 
 impl<'a> HttpHeader<'a> {
 	pub fn from_str_pair(key: &str, value: &'a str) -> Result<Option<Self>> {
 		match key {
 			"Content-Type" => {
+				let (mime, _params) = value.split_once(';').unwrap_or((value, ""));
+				if mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+					return match split_header_params(value).into_iter()
+						.find(|(key, _value)| key.eq_ignore_ascii_case("boundary"))
+					{
+						Some((_key, HeaderParam::Plain(boundary))) => Ok(Some(HttpHeader::MultipartFormData(boundary.into()))),
+						Some((_key, HeaderParam::Quoted(boundary))) => Ok(Some(HttpHeader::MultipartFormData(boundary.into()))),
+						None => bail!("multipart/form-data without a boundary"),
+					};
+				}
 				if let Some(ctype) = ContentType::from_str(value) {
 					return Ok(Some(HttpHeader::ContentType(ctype)));
 				}else { bail!("unrecognized content type"); }
@@ -58,11 +89,16 @@ impl<'a> HttpHeader<'a> {
 					return Ok(Some(HttpHeader::ContentDisposition(disp)));
 				}else { bail!("failed to parse Content-Disposition header"); }
 			},
+			"Range" => Ok(Some(HttpHeader::Range(value))),
+			"Connection" => Ok(Some(HttpHeader::Connection(ConnectionOption::from_str(value)))),
+			"Authorization" => Ok(Some(HttpHeader::Authorization(value))),
+			"Accept" => Ok(Some(HttpHeader::Accept(value))),
+			"Host" => Ok(Some(HttpHeader::Host(value))),
 			// NOTE these are a list of recognized but unhandled http header keys
-			"Accept-Language" | "Range" | "DNT" | "Sec-GPC"
-				| "Connection" | "Referer" | "Sec-Fetch-Dest"
+			"Accept-Language" | "DNT" | "Sec-GPC"
+				| "Referer" | "Sec-Fetch-Dest"
 				| "Sec-Fetch-Mode" | "Sec-Fetch-Site" | "Accept-Encoding"
-				| "Priority" | "Accept" | "Host" | "User-Agent"
+				| "Priority" | "User-Agent"
 				| "Upgrade-Insecure-Requests" => {
 					Ok(None)
 				}
@@ -87,7 +123,54 @@ impl ReadInto for HttpHeader<'_> {
 			HttpHeader::ContentLength(len) => {
 				write_size += destination.write(b"Content-Length: ")?;
 				write_size += len.read_into(destination)?;
-			}
+			},
+			HttpHeader::AcceptRanges => {
+				write_size += destination.write(b"Accept-Ranges: bytes")?;
+			},
+			HttpHeader::ContentRange(range) => write_size += range.read_into(destination)?,
+			HttpHeader::Range(spec) => {
+				write_size += destination.write(b"Range: ")?;
+				write_size += destination.write(spec.as_bytes())?;
+			},
+			HttpHeader::MultipartFormData(boundary) => {
+				write_size += destination.write(b"Content-Type: multipart/form-data; boundary=")?;
+				// NOTE boundaries may hold characters that are only allowed quoted
+				let quoted = !boundary.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"'+_-.".contains(&byte));
+				if quoted { write_size += destination.write(b"\"")?; }
+				write_size += destination.write(boundary.as_bytes())?;
+				if quoted { write_size += destination.write(b"\"")?; }
+			},
+			HttpHeader::Location(location) => {
+				write_size += destination.write(b"Location: ")?;
+				write_size += destination.write(location.as_bytes())?;
+			},
+			HttpHeader::Connection(option) => {
+				write_size += destination.write(b"Connection: ")?;
+				write_size += destination.write(option.as_str().as_bytes())?;
+			},
+			HttpHeader::Authorization(credentials) => {
+				write_size += destination.write(b"Authorization: ")?;
+				write_size += destination.write(credentials.as_bytes())?;
+			},
+			HttpHeader::Accept(media_ranges) => {
+				write_size += destination.write(b"Accept: ")?;
+				write_size += destination.write(media_ranges.as_bytes())?;
+			},
+			HttpHeader::Allow(methods) => {
+				write_size += destination.write(b"Allow: ")?;
+				write_size += destination.write(methods.as_bytes())?;
+			},
+			HttpHeader::ContentSecurityPolicy(policy) => {
+				write_size += destination.write(b"Content-Security-Policy: ")?;
+				write_size += destination.write(policy.as_bytes())?;
+			},
+			HttpHeader::Host(host) => {
+				write_size += destination.write(b"Host: ")?;
+				write_size += destination.write(host.as_bytes())?;
+			},
+			HttpHeader::NoSniff => {
+				write_size += destination.write(b"X-Content-Type-Options: nosniff")?;
+			},
 		};
 		// write_size += destination.write(b"\r\n")?;
 		return Ok(write_size);
@@ -101,7 +184,10 @@ pub enum ContentType {
 	text_plain,
 	image_x_icon,
 	audio_flac,
-	application_json
+	application_json,
+	multipart_byteranges,
+	// NOTE any other type, like the ones detected for served files
+	other(&'static str),
 }
 
 impl ContentType {
@@ -122,10 +208,130 @@ impl ContentType {
 			Self::image_x_icon => "image/x-icon",
 			Self::audio_flac => "audio/flac",
 			Self::application_json => "application/json",
+			Self::multipart_byteranges => MULTIPART_BYTERANGES,
+			Self::other(mime) => mime,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionOption {
+	KeepAlive,
+	Close,
+	// NOTE other connection options (e.g. Upgrade) are not acted on
+	Other,
+}
+
+impl ConnectionOption {
+	// NOTE close wins over keep-alive if a client sends both
+	pub fn from_str(source: &str) -> Self {
+		let mut option = Self::Other;
+		for token in source.split(',').map(|token| token.trim()) {
+			if token.eq_ignore_ascii_case("close") { return Self::Close; }
+			if token.eq_ignore_ascii_case("keep-alive") { option = Self::KeepAlive; }
+		}
+		return option;
+	}
+
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::KeepAlive => "keep-alive",
+			Self::Close => "close",
+			Self::Other => "",
+		}
+	}
+}
+
+pub const BYTERANGES_BOUNDARY: &str = "localshare-byteranges-7f3a9c2e51d8";
+const MULTIPART_BYTERANGES: &str = "multipart/byteranges; boundary=localshare-byteranges-7f3a9c2e51d8";
+
+// NOTE a single range from a `Range: bytes=...` request header, the
+// bounds are inclusive as they are on the wire
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteRange {
+	Bounded(usize, usize),
+	From(usize),
+	Suffix(usize),
+}
+
+impl ByteRange {
+	// NOTE requests with more ranges than this are served in full
+	pub const MAX_RANGES: usize = 32;
+
+	pub fn parse_ranges(source: &str) -> Result<Vec<ByteRange>> {
+		let spec = source.trim().strip_prefix("bytes=")
+			.ok_or(anyhow!("unsupported range unit"))?;
+
+		let mut ranges = Vec::<ByteRange>::new();
+		for range_str in spec.split(',').map(|range| range.trim()) {
+			if range_str == "" { continue; }
+			let (start, end) = range_str.split_once('-')
+				.ok_or(anyhow!("byte range missing '-' -> {}", range_str))?;
+			let range = match (start, end) {
+				("", "") => bail!("empty byte range"),
+				("", suffix) => ByteRange::Suffix(suffix.parse::<usize>()?),
+				(start, "") => ByteRange::From(start.parse::<usize>()?),
+				(start, end) => {
+					let (start, end) = (start.parse::<usize>()?, end.parse::<usize>()?);
+					if end < start { bail!("byte range ends before it starts -> {}", range_str); }
+					ByteRange::Bounded(start, end)
+				}
+			};
+			ranges.push(range);
+			if ranges.len() > Self::MAX_RANGES { bail!("too many byte ranges requested"); }
+		}
+
+		if ranges.len() == 0 { bail!("no byte ranges in range header"); }
+		return Ok(ranges);
+	}
+
+	// returns the inclusive (start, end) of this range inside content of
+	// the given length, or None if the range cannot be satisfied
+	pub fn resolve(self, length: usize) -> Option<(usize, usize)> {
+		if length == 0 { return None; }
+		match self {
+			ByteRange::Bounded(start, end) => {
+				if start >= length { return None; }
+				return Some((start, end.min(length - 1)));
+			},
+			ByteRange::From(start) => {
+				if start >= length { return None; }
+				return Some((start, length - 1));
+			},
+			ByteRange::Suffix(0) => return None,
+			ByteRange::Suffix(suffix) => {
+				return Some((length.saturating_sub(suffix), length - 1));
+			}
 		}
 	}
 }
 
+#[derive(Clone, Copy)]
+pub enum ContentRange {
+	Bytes{ start: usize, end: usize, total: usize },
+	Unsatisfied(usize),
+}
+
+impl ReadInto for ContentRange {
+	fn read_into(&mut self, destination: &mut dyn Write) -> Result<usize> {
+		let mut write_size = destination.write(b"Content-Range: bytes ")?;
+		match self {
+			ContentRange::Bytes{ start, end, total } => {
+				write_size += start.read_into(destination)?;
+				write_size += destination.write(b"-")?;
+				write_size += end.read_into(destination)?;
+				write_size += destination.write(b"/")?;
+				write_size += total.read_into(destination)?;
+			},
+			ContentRange::Unsatisfied(total) => {
+				write_size += destination.write(b"*/")?;
+				write_size += total.read_into(destination)?;
+			}
+		}
+		return Ok(write_size);
+	}
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Clone, Copy)]
 pub enum TransferEncoding {
@@ -133,7 +339,8 @@ pub enum TransferEncoding {
 	_8bit,
 	binary,
 	quoted_printable,
-	base64
+	base64,
+	chunked,
 }
 
 impl TransferEncoding {
@@ -144,6 +351,7 @@ impl TransferEncoding {
 			"binary" => Some(Self::binary),
 			"quoted_printable" => Some(Self::quoted_printable),
 			"base64" => Some(Self::base64),
+			"chunked" => Some(Self::chunked),
 			_ => None
 		}
 	}
@@ -155,32 +363,62 @@ impl TransferEncoding {
 			Self::binary => "binary",
 			Self::quoted_printable => "quoted_printable",
 			Self::base64 => "base64",
+			Self::chunked => "chunked",
 		}
 	}
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ContentDisposition<'a> {
 	Inline,
-	Attachment(Option<&'a str>)
+	Attachment(Option<std::borrow::Cow<'a, str>>)
 }
 
 impl<'a> ContentDisposition<'a> {
 	pub fn from_str(source: &'a str) -> Result<ContentDisposition<'a>> {
-		if source == "inline" { return Ok(ContentDisposition::Inline); }
-		else if source == "attachment" { return Ok(ContentDisposition::Attachment(None)); }
-		else if source.starts_with("attachment; ") {
-			let source = &source["attachment; ".len()..];
-			if let Some(seperator) = source.find("; ") {
-				return Ok(ContentDisposition::Attachment(Some(&source[..seperator])));
-			}else {
-				return Ok(ContentDisposition::Attachment(Some(&source[..])));
-			}
-		}
-		else { bail!("Failed to parse content disposition"); }
+		let (disposition, _params) = source.split_once(';').unwrap_or((source, ""));
+		let disposition = disposition.trim();
+		if disposition.eq_ignore_ascii_case("inline") { return Ok(ContentDisposition::Inline); }
+		if !disposition.eq_ignore_ascii_case("attachment") { bail!("Failed to parse content disposition"); }
+		return Ok(ContentDisposition::Attachment(disposition_filename(source).map(std::borrow::Cow::Owned)));
 	}
 }
 
+// the filename parameter of a Content-Disposition value, an RFC 5987
+// `filename*=UTF-8''...` is preferred over a plain or quoted `filename`
+pub fn disposition_filename(source: &str) -> Option<String> {
+	let params = split_header_params(source);
+	let extended = params.iter()
+		.find(|(key, _value)| key.eq_ignore_ascii_case("filename*"))
+		.and_then(|(_key, value)| decode_extended_value(value.as_str()));
+	return extended.or_else(|| params.iter()
+		.find(|(key, _value)| key.eq_ignore_ascii_case("filename"))
+		.map(|(_key, value)| value.as_str().to_owned()));
+}
+
+// decodes an RFC 5987 `charset'language'percent-encoded` value, only utf-8
+// and latin-1 are understood
+fn decode_extended_value(source: &str) -> Option<String> {
+	let mut parts = source.splitn(3, '\'');
+	let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+	let bytes = crate::url::percent_decode_bytes(encoded)?;
+	if charset.eq_ignore_ascii_case("utf-8") { return String::from_utf8(bytes).ok(); }
+	if charset.eq_ignore_ascii_case("iso-8859-1") { return Some(bytes.into_iter().map(char::from).collect()); }
+	return None;
+}
+
+// NOTE characters of a quoted filename that clients handle inconsistently
+// (and anything outside ascii) are replaced, the exact name goes in
+// filename* for the clients that understand it
+fn ascii_filename_fallback(name: &str) -> String {
+	return name.chars()
+		.map(|chr| match chr {
+			' '..='~' if !"\"\\%".contains(chr) => chr,
+			_ => '_',
+		})
+		.collect();
+}
+
 impl ReadInto for ContentDisposition<'_> {
 	fn read_into(&mut self, destination: &mut dyn Write) -> Result<usize> {
 		let mut write_size = destination.write(b"Content-Disposition: ")?;
@@ -188,10 +426,21 @@ impl ReadInto for ContentDisposition<'_> {
 			ContentDisposition::Inline => write_size += destination.write(b"inline")?,
 			ContentDisposition::Attachment(None) => write_size += destination.write(b"attachment")?,
 			ContentDisposition::Attachment(Some(fname)) => {
-				write_size += destination.write(b"attachment; filename=")?;
-				write_size += destination.write(fname.as_bytes())?;
-				write_size += destination.write(b"; filename*=")?;
-				write_size += destination.write(fname.as_bytes())?;
+				let fallback = ascii_filename_fallback(fname);
+				write_size += destination.write(b"attachment; filename=\"")?;
+				write_size += destination.write(fallback.as_bytes())?;
+				write_size += destination.write(b"\"")?;
+				if fallback != *fname {
+					// NOTE only attr-chars may appear unencoded in an RFC 5987 value
+					write_size += destination.write(b"; filename*=UTF-8''")?;
+					for byte in fname.bytes() {
+						if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+							write_size += destination.write(&[byte])?;
+						}else {
+							write_size += destination.write(format!("%{:02X}", byte).as_bytes())?;
+						}
+					}
+				}
 			}
 		}
 		return Ok(write_size);
@@ -202,12 +451,15 @@ impl ReadInto for ContentDisposition<'_> {
 pub struct StreamBuffer<'a> {
 	buffer: &'a mut [u8],
 	filled: usize,
+	// NOTE set once anything went out, after which the response can no
+	// longer be replaced by an error response
+	sent: bool,
 	pub stream: &'a mut dyn Write
 }
 
 impl<'a> StreamBuffer<'a> {
 	pub fn new(buffer: &'a mut [u8], stream: &'a mut dyn Write) -> Self {
-		return Self{ buffer, stream, filled: 0 };
+		return Self{ buffer, stream, filled: 0, sent: false };
 	}
 	// pub fn push_http_response_primary_header<
 	// 	A: AsRef<[u8]>, B: AsRef<[u8]>
@@ -256,6 +508,7 @@ impl<'a> StreamBuffer<'a> {
 	// }
 
 	pub fn clear(&mut self) { self.filled = 0; }
+	pub fn has_sent(&self) -> bool { return self.sent; }
 }
 
 impl <'a> Write for StreamBuffer<'a> {
@@ -269,6 +522,7 @@ impl <'a> Write for StreamBuffer<'a> {
 	}
 
 	fn flush(&mut self) -> std::io::Result<()> {
+		if self.filled > 0 { self.sent = true; }
 		self.stream.write_all(&self.buffer[0..self.filled])?;
 		self.stream.flush()?;
 		self.filled = 0;
@@ -276,6 +530,43 @@ impl <'a> Write for StreamBuffer<'a> {
 	}
 }
 
+// NOTE adds a header line to a response as it is written, right after the
+// status line, so routes do not need to know about the connection
+pub struct HeaderInjector<'a> {
+	inner: &'a mut dyn Write,
+	header: Option<&'static str>,
+}
+
+impl<'a> HeaderInjector<'a> {
+	pub fn new(inner: &'a mut dyn Write, header: Option<&'static str>) -> Self {
+		return Self{ inner, header };
+	}
+}
+
+impl Write for HeaderInjector<'_> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let header = match self.header {
+			Some(header) => header,
+			None => return self.inner.write(buf),
+		};
+		match buf.iter().position(|byte| *byte == b'\n') {
+			Some(index) => {
+				self.inner.write_all(&buf[..=index])?;
+				self.inner.write_all(header.as_bytes())?;
+				self.inner.write_all(b"\r\n")?;
+				self.inner.write_all(&buf[index+1..])?;
+				self.header = None;
+			},
+			None => self.inner.write_all(buf)?,
+		}
+		return Ok(buf.len());
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		return self.inner.flush();
+	}
+}
+
 pub trait ReadInto {
 	fn read_into(&mut self, destination: &mut dyn Write) -> Result<usize>;
 }
@@ -312,6 +603,7 @@ impl ReadInto for usize {
 		let mut buffer_index = 0;
 
 		let mut number = self.clone();
+		if number == 0 { return Ok(destination.write(b"0")?); }
 		while number > 0 {
 			let digit = number % 10;
 			digit_buffer[buffer_index] = digit as u8 + b'0';
@@ -338,66 +630,374 @@ impl ReadInto for ClosureReader<'_> {
 	}
 }
 
-pub struct BodyTemplate<'a> {
-	pub template: &'a [u8],
-	pub keys: &'a [&'a [u8]],
-	pub values: &'a mut [&'a mut dyn ReadInto],
+// how a template value is escaped, picked by the template with a mode after
+// the key, `%key%` is html text, `%key:attr%` a quoted attribute value,
+// `%key:js%` the inside of a javascript string, `%key:url%` a url component,
+// `%key:path%` a url path keeping its / separators and `%key:raw%` writes
+// the value untouched, for values that are markup
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Escape {
+	Html,
+	Attribute,
+	JsString,
+	UrlComponent,
+	UrlPath,
+	Raw,
 }
 
-impl ReadInto for BodyTemplate<'_> {
-	fn read_into(&mut self, destination: &mut dyn Write) -> Result<usize> {
-		assert!(self.keys.len() == self.values.len());
-		enum ParseState {
-			None,
-			Escaping,
-			Key(usize)
-		}
+impl Escape {
+	pub fn from_mode(mode: &[u8]) -> Option<Self> {
+		return match mode {
+			b"" | b"html" => Some(Self::Html),
+			b"attr" => Some(Self::Attribute),
+			b"js" => Some(Self::JsString),
+			b"url" => Some(Self::UrlComponent),
+			b"path" => Some(Self::UrlPath),
+			b"raw" => Some(Self::Raw),
+			_ => None,
+		};
+	}
 
+	// NOTE works byte by byte, every escaped character is ascii so a value
+	// split across writes in the middle of a utf-8 sequence is still escaped
+	// correctly
+	fn escaped_byte(self, byte: u8) -> Option<String> {
+		let escaped = match (self, byte) {
+			(Self::Raw, _) => return None,
+			(Self::Html | Self::Attribute, b'&') => "&amp;",
+			(Self::Html | Self::Attribute, b'<') => "&lt;",
+			(Self::Html | Self::Attribute, b'>') => "&gt;",
+			(Self::Html | Self::Attribute, b'"') => "&quot;",
+			(Self::Html | Self::Attribute, b'\'') => "&#39;",
+			(Self::Attribute, b'`' | b'\t' | b'\n' | b'\r') => return Some(format!("&#{};", byte)),
+			(Self::Html | Self::Attribute, _) => return None,
+			(Self::JsString, b'\\') => "\\\\",
+			(Self::JsString, b'"') => "\\\"",
+			(Self::JsString, b'\'') => "\\'",
+			(Self::JsString, b'`') => "\\`",
+			(Self::JsString, b'\n') => "\\n",
+			(Self::JsString, b'\r') => "\\r",
+			// NOTE < > and & are escaped too so a value can not close the
+			// script element or start an html comment inside it
+			(Self::JsString, b'<' | b'>' | b'&') => return Some(format!("\\u{:04X}", byte)),
+			(Self::JsString, byte) if byte < 0x20 || byte == 0x7f => return Some(format!("\\u{:04X}", byte)),
+			(Self::JsString, _) => return None,
+			(Self::UrlComponent | Self::UrlPath, byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => return None,
+			(Self::UrlPath, b'/') => return None,
+			(Self::UrlComponent | Self::UrlPath, byte) => return Some(format!("%{:02X}", byte)),
+		};
+		return Some(escaped.to_owned());
+	}
+
+	pub fn write(self, text: &[u8], destination: &mut dyn Write) -> std::io::Result<usize> {
 		let mut write_size = 0;
-		let mut template_index = 0;
-		let mut state = ParseState::None;
-		'write: while template_index < self.template.len() {
-			match state {
-				ParseState::None => {
-					if self.template[template_index] == b'%' {
-						state = ParseState::Escaping;
-						template_index += 1;
-						continue 'write;
-					}
-					write_size += destination.write(&[self.template[template_index]])?;
-					template_index += 1;
+		let mut unescaped_start = 0;
+		for (index, byte) in text.iter().enumerate() {
+			if let Some(escaped) = self.escaped_byte(*byte) {
+				destination.write_all(&text[unescaped_start..index])?;
+				destination.write_all(escaped.as_bytes())?;
+				write_size += index - unescaped_start + escaped.len();
+				unescaped_start = index + 1;
+			}
+		}
+		destination.write_all(&text[unescaped_start..])?;
+		return Ok(write_size + text.len() - unescaped_start);
+	}
+
+	pub fn apply(self, text: &str) -> String {
+		let mut escaped = Vec::<u8>::with_capacity(text.len());
+		self.write(text.as_bytes(), &mut escaped).unwrap();
+		// NOTE only ascii is ever inserted or removed
+		return String::from_utf8(escaped).unwrap();
+	}
+}
+
+// a value handed to a template, records are read with `%item.field%` inside
+// a `{% for item in list %}` block
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateValue {
+	Text(String),
+	Bool(bool),
+	List(Vec<TemplateValue>),
+	Record(Vec<(&'static str, TemplateValue)>),
+}
+
+impl TemplateValue {
+	fn is_truthy(&self) -> bool {
+		return match self {
+			Self::Text(text) => text != "",
+			Self::Bool(value) => *value,
+			Self::List(items) => items.len() > 0,
+			Self::Record(_) => true,
+		};
+	}
+
+	fn field(&self, name: &str) -> Option<&TemplateValue> {
+		return match self {
+			Self::Record(fields) => fields.iter().find(|(key, _value)| *key == name).map(|(_key, value)| value),
+			_ => None,
+		};
+	}
+}
+
+impl From<&str> for TemplateValue {
+	fn from(value: &str) -> Self { return Self::Text(value.to_owned()); }
+}
+
+impl From<String> for TemplateValue {
+	fn from(value: String) -> Self { return Self::Text(value); }
+}
+
+impl From<bool> for TemplateValue {
+	fn from(value: bool) -> Self { return Self::Bool(value); }
+}
+
+impl From<usize> for TemplateValue {
+	fn from(value: usize) -> Self { return Self::Text(value.to_string()); }
+}
+
+impl<T: Into<TemplateValue>> From<Vec<T>> for TemplateValue {
+	fn from(values: Vec<T>) -> Self { return Self::List(values.into_iter().map(Into::into).collect()); }
+}
+
+#[derive(Debug)]
+enum TemplateNode {
+	Text(Vec<u8>),
+	Value { path: Vec<String>, escape: Escape },
+	For { item: String, list: Vec<String>, body: Vec<TemplateNode> },
+	If { condition: Vec<String>, negate: bool, then: Vec<TemplateNode>, otherwise: Vec<TemplateNode> },
+}
+
+enum TemplateToken {
+	Text(Vec<u8>),
+	Value { path: Vec<String>, escape: Escape },
+	Tag(String),
+}
+
+// NOTE keeps a partial from including itself forever
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// a page template parsed once and rendered for every request
+//
+// `%key%` writes a value, escaped for html text unless a mode follows the key
+// (see Escape), `%%` is a literal %. a key can name a field of a record with
+// `%item.field%`. blocks are written as
+//   {% for item in list %} ... {% endfor %}, with `%loop.index%`,
+//     `loop.first` and `loop.last` available inside
+//   {% if key %} ... {% else %} ... {% endif %}, or `if not key`, where
+//     empty text, false and empty lists are false
+//   {% include "partial.html" %}, which is parsed in place
+#[derive(Debug)]
+pub struct Template {
+	nodes: Vec<TemplateNode>,
+}
+
+impl Template {
+	// include loads the source of a partial by name
+	pub fn parse(source: &[u8], include: &dyn Fn(&str) -> Result<Vec<u8>>) -> Result<Self> {
+		return Ok(Self { nodes: Self::parse_included(source, include, 0)? });
+	}
+
+	fn parse_included(source: &[u8], include: &dyn Fn(&str) -> Result<Vec<u8>>, depth: usize) -> Result<Vec<TemplateNode>> {
+		let mut tokens = Self::tokenize(source)?.into_iter();
+		let (nodes, closing) = Self::parse_nodes(&mut tokens, include, depth, &[])?;
+		if let Some(tag) = closing {
+			bail!("unexpected {{% {} %}}", tag);
+		}
+		return Ok(nodes);
+	}
+
+	fn tokenize(source: &[u8]) -> Result<Vec<TemplateToken>> {
+		let line_of = |index: usize| source[..index].iter().filter(|byte| **byte == b'\n').count() + 1;
+		let find = |pattern: &[u8], from: usize| source[from..].windows(pattern.len())
+			.position(|window| window == pattern)
+			.map(|position| position + from);
+
+		let mut tokens = Vec::<TemplateToken>::new();
+		let mut text = Vec::<u8>::new();
+		let mut index = 0;
+		while index < source.len() {
+			if source[index..].starts_with(b"{%") {
+				let end = find(b"%}", index + 2)
+					.ok_or(anyhow!("unclosed {{% on line {}", line_of(index)))?;
+				let tag = std::str::from_utf8(&source[index + 2..end])
+					.map_err(|_| anyhow!("tag on line {} is not valid utf-8", line_of(index)))?;
+				if text.len() > 0 { tokens.push(TemplateToken::Text(std::mem::take(&mut text))); }
+				tokens.push(TemplateToken::Tag(tag.trim().to_owned()));
+				index = end + 2;
+			}else if source[index] == b'%' {
+				if source.get(index + 1) == Some(&b'%') {
+					text.push(b'%');
+					index += 2;
+					continue;
+				}
+				let end = find(b"%", index + 1)
+					.ok_or(anyhow!("unclosed % on line {}, write a literal % as %%", line_of(index)))?;
+				let key = &source[index + 1..end];
+				let (key, mode) = match key.iter().position(|byte| *byte == b':') {
+					Some(colon) => (&key[..colon], &key[colon + 1..]),
+					None => (key, b"".as_slice()),
+				};
+				let escape = Escape::from_mode(mode)
+					.ok_or(anyhow!("unknown escape mode {} on line {}", String::from_utf8_lossy(mode), line_of(index)))?;
+				if key.len() == 0 || !key.iter().all(|byte| byte.is_ascii_alphanumeric() || b"_.".contains(byte)) {
+					bail!("invalid key {} on line {}, write a literal % as %%", String::from_utf8_lossy(key), line_of(index));
+				}
+				if text.len() > 0 { tokens.push(TemplateToken::Text(std::mem::take(&mut text))); }
+				tokens.push(TemplateToken::Value { path: Self::parse_path(std::str::from_utf8(key)?), escape });
+				index = end + 1;
+			}else {
+				text.push(source[index]);
+				index += 1;
+			}
+		}
+		if text.len() > 0 { tokens.push(TemplateToken::Text(text)); }
+		return Ok(tokens);
+	}
+
+	fn parse_path(key: &str) -> Vec<String> {
+		return key.split('.').map(str::to_owned).collect();
+	}
+
+	// parses nodes up to one of the closing tags, which is returned, or to
+	// the end of the template
+	fn parse_nodes(
+		tokens: &mut std::vec::IntoIter<TemplateToken>,
+		include: &dyn Fn(&str) -> Result<Vec<u8>>,
+		depth: usize,
+		closing: &[&str],
+	) -> Result<(Vec<TemplateNode>, Option<String>)> {
+		let mut nodes = Vec::<TemplateNode>::new();
+		while let Some(token) = tokens.next() {
+			let tag = match token {
+				TemplateToken::Text(text) => { nodes.push(TemplateNode::Text(text)); continue; },
+				TemplateToken::Value { path, escape } => { nodes.push(TemplateNode::Value { path, escape }); continue; },
+				TemplateToken::Tag(tag) => tag,
+			};
+			let words = tag.split_whitespace().collect::<Vec<&str>>();
+			match words.as_slice() {
+				["for", item, "in", list] => {
+					let (body, _end) = Self::parse_block(tokens, include, depth, &tag, &["endfor"])?;
+					nodes.push(TemplateNode::For { item: item.to_string(), list: Self::parse_path(list), body });
 				},
-				ParseState::Escaping => {
-					if self.template[template_index] == b'%' {
-						write_size += destination.write(b"%")?;
-						template_index += 1;
-						state = ParseState::None;
-						continue 'write;
-					}else {
-						state = ParseState::Key(template_index);
-						template_index += 1;
-						continue 'write;
-					}
+				["if", condition] | ["if", "not", condition] => {
+					let (then, end) = Self::parse_block(tokens, include, depth, &tag, &["else", "endif"])?;
+					let otherwise = match end.as_str() {
+						"else" => Self::parse_block(tokens, include, depth, &tag, &["endif"])?.0,
+						_ => Vec::new(),
+					};
+					nodes.push(TemplateNode::If {
+						condition: Self::parse_path(condition), negate: words[1] == "not", then, otherwise
+					});
 				},
-				ParseState::Key(key_start) => {
-					if self.template[template_index] == b'%' {
-						let template_key = &self.template[key_start..template_index];
-						match self.keys.iter().enumerate().find(|(_iter, key)| **key == template_key) {
-							Some((index, _key)) => { self.values[index].read_into(destination)?; }
-							None => {
-								println!("\rDBG: failed to find key |-{}-|", unsafe{ template_key.as_ascii_unchecked() }.as_str());
-								write_size += destination.write(b"UNMATCHED KEY: ")?;
-								write_size += destination.write(template_key)?;
-							}
-						};
-						state = ParseState::None;
+				["include", name] if name.len() > 2 && name.starts_with('"') && name.ends_with('"') => {
+					let name = &name[1..name.len() - 1];
+					if depth >= MAX_INCLUDE_DEPTH {
+						bail!("includes nested deeper than {} levels at {}", MAX_INCLUDE_DEPTH, name);
 					}
-					template_index += 1;
-				}
+					let source = include(name)?;
+					nodes.extend(Self::parse_included(&source, include, depth + 1)
+						.map_err(|e| anyhow!("in {} -> {}", name, e))?);
+				},
+				[word] if closing.contains(word) => return Ok((nodes, Some(word.to_string()))),
+				_ => bail!("unexpected {{% {} %}}", tag),
 			}
 		}
+		return Ok((nodes, None));
+	}
 
-		return Ok(write_size);
+	fn parse_block(
+		tokens: &mut std::vec::IntoIter<TemplateToken>,
+		include: &dyn Fn(&str) -> Result<Vec<u8>>,
+		depth: usize,
+		opening: &str,
+		closing: &[&str],
+	) -> Result<(Vec<TemplateNode>, String)> {
+		return match Self::parse_nodes(tokens, include, depth, closing)? {
+			(nodes, Some(end)) => Ok((nodes, end)),
+			(_nodes, None) => Err(anyhow!("{{% {} %}} is missing its {{% {} %}}", opening, closing.last().unwrap())),
+		};
+	}
+
+	pub fn render(&self, values: &[(&str, TemplateValue)], destination: &mut dyn Write) -> Result<usize> {
+		return render_nodes(&self.nodes, &TemplateScope { name: "", value: None, values, parent: None }, destination);
+	}
+}
+
+// the values a template is rendered with, loops add a scope holding the
+// current item on top of their parent's
+struct TemplateScope<'a> {
+	name: &'a str,
+	value: Option<&'a TemplateValue>,
+	values: &'a [(&'a str, TemplateValue)],
+	parent: Option<&'a TemplateScope<'a>>,
+}
+
+impl TemplateScope<'_> {
+	fn lookup(&self, path: &[String]) -> Option<&TemplateValue> {
+		let mut value = None;
+		let mut scope = Some(self);
+		while let Some(current) = scope {
+			match current.value {
+				Some(item) if current.name == path[0] => { value = Some(item); break; },
+				Some(_item) => {},
+				None => {
+					value = current.values.iter().find(|(key, _value)| *key == path[0]).map(|(_key, value)| value);
+					break;
+				},
+			}
+			scope = current.parent;
+		}
+		return path[1..].iter().try_fold(value?, |value, field| value.field(field));
+	}
+}
+
+fn render_nodes(nodes: &[TemplateNode], scope: &TemplateScope, destination: &mut dyn Write) -> Result<usize> {
+	let mut write_size = 0;
+	for node in nodes {
+		match node {
+			TemplateNode::Text(text) => write_size += destination.write(text)?,
+			TemplateNode::Value { path, escape } => match scope.lookup(path) {
+				Some(TemplateValue::Text(text)) => write_size += escape.write(text.as_bytes(), destination)?,
+				Some(TemplateValue::Bool(value)) => write_size += destination.write(value.to_string().as_bytes())?,
+				Some(_value) => bail!("template key {} is a list or a record", path.join(".")),
+				None => bail!("template key {} has no value", path.join(".")),
+			},
+			TemplateNode::For { item, list, body } => {
+				let items = match scope.lookup(list) {
+					Some(TemplateValue::List(items)) => items.as_slice(),
+					Some(_value) => bail!("template key {} is not a list", list.join(".")),
+					None => &[],
+				};
+				for (index, value) in items.iter().enumerate() {
+					let loop_record = TemplateValue::Record(vec![
+						("index", index.into()),
+						("first", (index == 0).into()),
+						("last", (index + 1 == items.len()).into()),
+					]);
+					let loop_scope = TemplateScope { name: "loop", value: Some(&loop_record), values: &[], parent: Some(scope) };
+					let item_scope = TemplateScope { name: item, value: Some(value), values: &[], parent: Some(&loop_scope) };
+					write_size += render_nodes(body, &item_scope, destination)?;
+				}
+			},
+			TemplateNode::If { condition, negate, then, otherwise } => {
+				let truthy = scope.lookup(condition).map(TemplateValue::is_truthy).unwrap_or(false);
+				write_size += render_nodes(if truthy != *negate { then } else { otherwise }, scope, destination)?;
+			},
+		}
+	}
+	return Ok(write_size);
+}
+
+// renders a template into a response body
+pub struct TemplateReader<'a> {
+	pub template: &'a Template,
+	pub values: &'a [(&'a str, TemplateValue)],
+}
+
+impl ReadInto for TemplateReader<'_> {
+	fn read_into(&mut self, destination: &mut dyn Write) -> Result<usize> {
+		return self.template.render(self.values, destination);
 	}
 }
 
@@ -413,43 +1013,51 @@ pub struct HttpRequest<'a> {
 }
 
 impl HttpRequest<'_> {
-	// NOTE expects source to be non-blocking
+	// NOTE expects source to be blocking, ideally with a read timeout set,
+	// a timed out read is reported as HttpReadError::TimedOut
 	pub fn read_blocking<'a>(
 		buffer: &'a mut Vec<u8>,
 		source: &mut dyn std::io::Read,
+		limits: RequestLimits,
 	) -> Result<HttpRequest<'a>> {
-		// TODO this may still be incorrect
-		let mut intermediate_buffer: [u8; 16384] = unsafe{ std::mem::zeroed() };
-		'preliminary_read: loop {
-			match source.read(&mut intermediate_buffer) {
-				Ok(count) => {
-					buffer.write_all(&intermediate_buffer[..count]).unwrap();
-					break 'preliminary_read;
-				},
-				Err(e) => { match e.kind() {
-					std::io::ErrorKind::WouldBlock => {
-						std::thread::sleep(std::time::Duration::from_millis(10));
-						continue 'preliminary_read;
-					},
-					_ => {
-						println!("\rWARN: failed to read from tcp socket -> {e}");
-						break 'preliminary_read;
-					}
-				}}
-			}
+		let head_length = read_request_head(buffer, source, limits)?;
+		let framing = BodyFraming::from_head(&buffer[..head_length])?;
+
+		let prefetched = buffer.split_off(head_length);
+		let mut body_reader = BodyReader::new(source, prefetched, framing);
+		body_reader.read_to_end_limited(buffer, limits.max_body_size)?;
+
+		let leftover = body_reader.into_leftover();
+		if leftover.len() > 0 {
+			println!("\rWARN: discarding {} bytes received after the request body", leftover.len());
 		}
 
-		if buffer.len() == 0 { bail!("0 bytes read from request source"); }
-		else if buffer.len() < 4 { bail!("read size of invalid size {}", buffer.len()); }
+		let buffer: &'a Vec<u8> = buffer;
+		return HttpRequest::parse(&buffer[..head_length - 4], &buffer[head_length..]);
+	}
 
-		let (head, body) = match crate::split_slice_uninclusive(buffer.as_slice(), b"\r\n\r\n") { 
-			Some(pair) => pair,
-			None => {
-				// println!("\rWARN: did not receive body separator from http request in time");
-				(&buffer.as_slice()[..buffer.len()-4], &b""[..])
-			}
-		};
-		let head = unsafe{ head.as_ascii_unchecked() }.as_str();
+	// reads only the request head, the body is left to the returned reader so
+	// that routes can stream it instead of holding it in memory
+	pub fn read_head_blocking<'a, 's>(
+		buffer: &'a mut Vec<u8>,
+		source: &'s mut dyn std::io::Read,
+		limits: RequestLimits,
+	) -> Result<(HttpRequest<'a>, BodyReader<'s>)> {
+		let head_length = read_request_head(buffer, source, limits)?;
+		let framing = BodyFraming::from_head(&buffer[..head_length])?;
+
+		let prefetched = buffer.split_off(head_length);
+		let buffer: &'a Vec<u8> = buffer;
+		let request = HttpRequest::parse(&buffer[..head_length - 4], b"")?;
+
+		return Ok((request, BodyReader::new(source, prefetched, framing)));
+	}
+
+	// parses the request line and headers from head (without the final blank
+	// line), the body is passed through untouched
+	pub fn parse<'a>(head: &'a [u8], body: &'a [u8]) -> Result<HttpRequest<'a>> {
+		let head = std::str::from_utf8(head)
+			.map_err(|_| HttpReadError::Malformed("request head is not valid utf-8"))?;
 
 		let mut headers = Vec::<crate::http::HttpHeader>::new();
 		let mut header_iter = head.split("\r\n");
@@ -478,17 +1086,17 @@ impl HttpRequest<'_> {
 
 		let mut primary_header_segments = primary_header.split(" ");
 		let method_str = primary_header_segments.next()
-			.ok_or(anyhow!("malformed http primary header - no method"))?;
+			.ok_or(HttpReadError::Malformed("malformed http primary header - no method"))?;
 		let uri_str = primary_header_segments.next()
-			.ok_or(anyhow!("malformed http primary header - no route"))?;
+			.ok_or(HttpReadError::Malformed("malformed http primary header - no route"))?;
 		let version_str = primary_header_segments.next()
-			.ok_or(anyhow!("malformed http primary header - no version"))?;
+			.ok_or(HttpReadError::Malformed("malformed http primary header - no version"))?;
 
 		let (route_str, query_param_str) = uri_str.split_once("?")
 			.unwrap_or((uri_str, ""));
 
 		let method = HttpMethod::from_str(method_str)
-			.ok_or(anyhow!("invalid http method"))?;
+			.ok_or(HttpReadError::UnsupportedMethod)?;
 	
 		return Ok(HttpRequest {
 			protocol_version: version_str,
@@ -500,6 +1108,75 @@ impl HttpRequest<'_> {
 		});
 	}
 
+	// HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only when
+	// the client asks for it
+	pub fn wants_keep_alive(&self) -> bool {
+		let option = self.headers.iter().find_map(|header| match header {
+			HttpHeader::Connection(option) => Some(*option),
+			_ => None
+		});
+		match (self.protocol_version, option) {
+			(_, Some(ConnectionOption::Close)) => false,
+			("HTTP/1.1", _) => true,
+			("HTTP/1.0", Some(ConnectionOption::KeepAlive)) => true,
+			_ => false,
+		}
+	}
+
+	// the token of an `Authorization: Bearer <token>` header
+	pub fn bearer_token(&self) -> Option<&str> {
+		return self.headers.iter().find_map(|header| match header {
+			HttpHeader::Authorization(credentials) => {
+				let (scheme, token) = credentials.trim().split_once(' ')?;
+				if scheme.eq_ignore_ascii_case("Bearer") { Some(token.trim()) } else { None }
+			},
+			_ => None
+		});
+	}
+
+	// whether the client would rather get json than html, going by the q
+	// values of its Accept header. html wins ties and a missing header
+	pub fn prefers_json(&self) -> bool {
+		let accept = match self.headers.iter().find_map(|header| match header {
+			HttpHeader::Accept(media_ranges) => Some(*media_ranges),
+			_ => None
+		}) {
+			Some(accept) => accept,
+			None => return false,
+		};
+
+		// NOTE the q value of the most specific range matching media_type
+		let quality = |media_type: &str| -> f32 {
+			let (main_type, _sub_type) = media_type.split_once('/').unwrap_or((media_type, ""));
+			let mut best: Option<(u8, f32)> = None;
+			for media_range in accept.split(',') {
+				let mut params = media_range.split(';');
+				let range = params.next().unwrap_or("").trim();
+				let specificity = match range.split_once('/') {
+					_ if range.eq_ignore_ascii_case(media_type) => 2,
+					Some((range_main, "*")) if range_main.eq_ignore_ascii_case(main_type) => 1,
+					Some(("*", "*")) => 0,
+					_ => continue,
+				};
+				let q = params.filter_map(|param| param.trim().strip_prefix("q="))
+					.find_map(|q| q.parse::<f32>().ok())
+					.unwrap_or(1.0);
+				if best.map(|(best_specificity, _q)| specificity > best_specificity).unwrap_or(true) {
+					best = Some((specificity, q));
+				}
+			}
+			return best.map(|(_specificity, q)| q).unwrap_or(0.0);
+		};
+		return quality("application/json") > quality("text/html");
+	}
+
+	pub fn range_header(&self) -> Option<&str> {
+		return self.headers.iter().find_map(|header| match header {
+			HttpHeader::Range(spec) => Some(*spec),
+			_ => None
+		});
+	}
+
 	fn write_headers_to(&self, sink: &mut dyn Write) -> Result<()> {
 		sink.write(self.method.as_str().as_bytes())?;
 		sink.write(b" ")?;
@@ -513,7 +1190,7 @@ impl HttpRequest<'_> {
 		sink.write(b"\r\n")?;
 		
 		for header in self.headers.iter() {
-			let mut header = *header;
+			let mut header = header.clone();
 			header.read_into(sink)?;
 			sink.write(b"\r\n")?;
 		}
@@ -540,103 +1217,519 @@ impl HttpRequest<'_> {
 	}
 }
 
-pub struct HttpResponse<'a> {
-	pub protocol_version: &'a str,
-	pub status_code: usize,
-	pub status_text: &'a str,
-	pub headers: Vec<HttpHeader<'a>>,
-	pub body: &'a [u8]
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLimits {
+	pub max_header_size: usize,
+	pub max_body_size: usize,
+	// NOTE uploads are streamed to disk so they get their own, larger limit
+	pub max_upload_size: usize,
 }
 
-impl HttpResponse<'_> {
-	pub fn read_blocking<'a>(
-		buffer: &'a mut Vec<u8>,
-		source: &mut dyn std::io::Read,
-	) -> Result<HttpResponse<'a>> {
-		// TODO this may still be incorrect
-		let mut intermediate_buffer: [u8; 16384] = unsafe{ std::mem::zeroed() };
-		'preliminary_read: loop {
-			match source.read(&mut intermediate_buffer) {
-				Ok(count) => {
-					buffer.write_all(&intermediate_buffer[..count]).unwrap();
-					break 'preliminary_read;
-				},
-				Err(e) => { match e.kind() {
-					std::io::ErrorKind::WouldBlock => {
-						std::thread::sleep(std::time::Duration::from_millis(10));
-						println!("\rDBG: encountered nonblocking stream in response reader");
-						continue 'preliminary_read;
+impl Default for RequestLimits {
+	fn default() -> Self {
+		return Self {
+			max_header_size: 16 * 1024,
+			max_body_size: 16 * 1024 * 1024,
+			max_upload_size: 16 * 1024 * 1024 * 1024,
+		};
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionLimits {
+	// how long a kept alive connection may wait for its next request
+	pub idle_timeout: std::time::Duration,
+	// how long a client may take to send a request once it has started
+	pub request_timeout: std::time::Duration,
+	pub max_requests: usize,
+}
+
+impl Default for ConnectionLimits {
+	fn default() -> Self {
+		return Self {
+			idle_timeout: std::time::Duration::from_secs(5),
+			request_timeout: std::time::Duration::from_secs(30),
+			max_requests: 100,
+		};
+	}
+}
+
+// NOTE errors which have a matching http status so that the client can be
+// told why its request was rejected
+#[derive(Debug)]
+pub enum HttpReadError {
+	ConnectionClosed,
+	TimedOut,
+	HeaderTooLarge,
+	BodyTooLarge,
+	Malformed(&'static str),
+	UnsupportedMethod,
+	Io(std::io::Error),
+}
+
+impl HttpReadError {
+	pub fn from_io(error: std::io::Error) -> Self {
+		match error.kind() {
+			std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Self::TimedOut,
+			std::io::ErrorKind::UnexpectedEof => Self::ConnectionClosed,
+			std::io::ErrorKind::InvalidData => Self::Malformed("invalid message body framing"),
+			_ => Self::Io(error),
+		}
+	}
+
+	// the response status to send for this error, if the client can still
+	// be answered at all
+	pub fn status(&self) -> Option<(usize, &'static str)> {
+		match self {
+			Self::ConnectionClosed | Self::Io(_) => None,
+			Self::TimedOut => Some((408, "Request Timeout")),
+			Self::HeaderTooLarge => Some((431, "Request Header Fields Too Large")),
+			Self::BodyTooLarge => Some((413, "Content Too Large")),
+			Self::Malformed(_) => Some((400, "Bad Request")),
+			Self::UnsupportedMethod => Some((501, "Not Implemented")),
+		}
+	}
+}
+
+impl std::fmt::Display for HttpReadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::ConnectionClosed => write!(f, "connection closed before the request was complete"),
+			Self::TimedOut => write!(f, "timed out waiting for the request"),
+			Self::HeaderTooLarge => write!(f, "request head exceeds the header size limit"),
+			Self::BodyTooLarge => write!(f, "request body exceeds the body size limit"),
+			Self::Malformed(reason) => write!(f, "malformed request -> {}", reason),
+			Self::UnsupportedMethod => write!(f, "unsupported request method"),
+			Self::Io(e) => write!(f, "failed to read request -> {}", e),
+		}
+	}
+}
+
+impl std::error::Error for HttpReadError {}
+
+// reads from source into buffer until the blank line ending the request head
+// has arrived, returning the length of the head including that blank line.
+// buffer may already hold bytes and may hold body bytes past the head on return
+pub fn read_request_head(
+	buffer: &mut Vec<u8>,
+	source: &mut dyn Read,
+	limits: RequestLimits,
+) -> std::result::Result<usize, HttpReadError> {
+	let mut intermediate_buffer: [u8; 16384] = unsafe{ std::mem::zeroed() };
+	let mut search_start = 0;
+	loop {
+		if let Some(index) = buffer[search_start..].windows(4).position(|window| window == b"\r\n\r\n") {
+			let head_length = search_start + index + 4;
+			if head_length > limits.max_header_size { return Err(HttpReadError::HeaderTooLarge); }
+			return Ok(head_length);
+		}
+		if buffer.len() > limits.max_header_size { return Err(HttpReadError::HeaderTooLarge); }
+		search_start = buffer.len().saturating_sub(3);
+
+		let count = match source.read(&mut intermediate_buffer) {
+			Ok(count) => count,
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(HttpReadError::from_io(e)),
+		};
+		if count == 0 { return Err(HttpReadError::ConnectionClosed); }
+		buffer.extend_from_slice(&intermediate_buffer[..count]);
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyFraming {
+	Empty,
+	Length(usize),
+	Chunked,
+	// NOTE only responses, a body without a length that ends when the server
+	// closes the connection
+	UntilClose,
+}
+
+impl BodyFraming {
+	// NOTE only looks at the framing headers, so it is case insensitive and
+	// independent of HttpHeader::from_str_pair
+	pub fn from_head(head: &[u8]) -> std::result::Result<Self, HttpReadError> {
+		let head = std::str::from_utf8(head)
+			.map_err(|_| HttpReadError::Malformed("request head is not valid utf-8"))?;
+
+		let mut content_length: Option<usize> = None;
+		let mut chunked = false;
+		for line in head.split("\r\n").skip(1) {
+			let (key, value) = match line.split_once(':') {
+				Some((key, value)) => (key.trim(), value.trim()),
+				None => continue,
+			};
+			if key.eq_ignore_ascii_case("Content-Length") {
+				let length = value.parse::<usize>()
+					.map_err(|_| HttpReadError::Malformed("invalid content length"))?;
+				if content_length.is_some_and(|previous| previous != length) {
+					return Err(HttpReadError::Malformed("conflicting content lengths"));
+				}
+				content_length = Some(length);
+			}else if key.eq_ignore_ascii_case("Transfer-Encoding") {
+				match value.rsplit(',').next().map(|coding| coding.trim()) {
+					Some(coding) if coding.eq_ignore_ascii_case("chunked") => chunked = true,
+					_ => return Err(HttpReadError::Malformed("unsupported transfer coding")),
+				}
+			}
+		}
+
+		// NOTE chunked takes precedence over a content length (RFC 9112 6.3)
+		if chunked { return Ok(Self::Chunked); }
+		return Ok(match content_length {
+			Some(0) | None => Self::Empty,
+			Some(length) => Self::Length(length),
+		});
+	}
+
+	// the framing of a response body, which is read until the connection
+	// closes when the head gives no length (RFC 9112 6.3)
+	pub fn from_response_head(head: &[u8], status_code: usize) -> std::result::Result<Self, HttpReadError> {
+		if status_code < 200 || status_code == 204 || status_code == 304 { return Ok(Self::Empty); }
+		let framing = Self::from_head(head)?;
+		let has_length = head.split(|byte| *byte == b'\n').skip(1)
+			.any(|line| line.split(|byte| *byte == b':').next()
+				.is_some_and(|key| key.trim_ascii().eq_ignore_ascii_case(b"Content-Length")));
+		if framing == Self::Empty && !has_length { return Ok(Self::UntilClose); }
+		return Ok(framing);
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ChunkState {
+	Size,
+	Data,
+	DataEnd,
+	Done,
+}
+
+// NOTE a reader over exactly one message body, reading bytes that were
+// already pulled off the socket (prefetched) before reading from source
+pub struct BodyReader<'s> {
+	source: &'s mut dyn Read,
+	pending: Vec<u8>,
+	pending_start: usize,
+	framing: BodyFraming,
+	remaining: usize,
+	chunk_state: ChunkState,
+}
+
+impl<'s> BodyReader<'s> {
+	const MAX_CHUNK_LINE: usize = 4096;
+
+	pub fn new(source: &'s mut dyn Read, prefetched: Vec<u8>, framing: BodyFraming) -> Self {
+		let remaining = match framing {
+			BodyFraming::Length(length) => length,
+			_ => 0
+		};
+		return Self {
+			source, pending: prefetched, pending_start: 0,
+			framing, remaining, chunk_state: ChunkState::Size
+		};
+	}
+
+	pub fn framing(&self) -> BodyFraming { return self.framing; }
+
+	// reads the rest of the body into destination, failing without reading
+	// further once more than limit bytes have arrived
+	pub fn read_to_end_limited(
+		&mut self, destination: &mut Vec<u8>, limit: usize
+	) -> std::result::Result<usize, HttpReadError> {
+		if let BodyFraming::Length(length) = self.framing {
+			if length > limit { return Err(HttpReadError::BodyTooLarge); }
+		}
+		let size = self.take(limit as u64 + 1)
+			.read_to_end(destination)
+			.map_err(HttpReadError::from_io)?;
+		if size > limit { return Err(HttpReadError::BodyTooLarge); }
+		return Ok(size);
+	}
+
+	pub fn is_finished(&self) -> bool {
+		match self.framing {
+			BodyFraming::Empty => true,
+			BodyFraming::Length(_) => self.remaining == 0,
+			BodyFraming::Chunked => self.chunk_state == ChunkState::Done,
+			BodyFraming::UntilClose => false,
+		}
+	}
+
+	// bytes read from the source that come after this body, e.g. the start
+	// of a pipelined request
+	pub fn into_leftover(mut self) -> Vec<u8> {
+		return self.pending.split_off(self.pending_start);
+	}
+
+	fn read_raw(&mut self, destination: &mut [u8]) -> std::io::Result<usize> {
+		if self.pending_start < self.pending.len() {
+			let count = destination.len().min(self.pending.len() - self.pending_start);
+			destination[..count].copy_from_slice(&self.pending[self.pending_start..self.pending_start+count]);
+			self.pending_start += count;
+			return Ok(count);
+		}
+		let count = self.source.read(destination)?;
+		if count == 0 && destination.len() > 0 {
+			return Err(std::io::ErrorKind::UnexpectedEof.into());
+		}
+		return Ok(count);
+	}
+
+	fn read_line(&mut self) -> std::io::Result<String> {
+		loop {
+			if let Some(index) = self.pending[self.pending_start..].windows(2).position(|window| window == b"\r\n") {
+				let line = &self.pending[self.pending_start..self.pending_start+index];
+				let line = String::from_utf8(line.to_vec())
+					.map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+				self.pending_start += index + 2;
+				return Ok(line);
+			}
+			if self.pending.len() - self.pending_start > Self::MAX_CHUNK_LINE {
+				return Err(std::io::ErrorKind::InvalidData.into());
+			}
+
+			self.pending.drain(..self.pending_start);
+			self.pending_start = 0;
+			let mut intermediate_buffer = [0u8; 1024];
+			let count = self.source.read(&mut intermediate_buffer)?;
+			if count == 0 { return Err(std::io::ErrorKind::UnexpectedEof.into()); }
+			self.pending.extend_from_slice(&intermediate_buffer[..count]);
+		}
+	}
+}
+
+impl Read for BodyReader<'_> {
+	fn read(&mut self, destination: &mut [u8]) -> std::io::Result<usize> {
+		if destination.len() == 0 { return Ok(0); }
+		match self.framing {
+			BodyFraming::Empty => return Ok(0),
+			BodyFraming::UntilClose => {
+				if self.pending_start < self.pending.len() { return self.read_raw(destination); }
+				return self.source.read(destination);
+			},
+			BodyFraming::Length(_) => {
+				if self.remaining == 0 { return Ok(0); }
+				let limit = destination.len().min(self.remaining);
+				let count = self.read_raw(&mut destination[..limit])?;
+				self.remaining -= count;
+				return Ok(count);
+			},
+			BodyFraming::Chunked => loop {
+				match self.chunk_state {
+					ChunkState::Done => return Ok(0),
+					ChunkState::Size => {
+						let line = self.read_line()?;
+						let size_str = line.split(';').next().unwrap_or("").trim();
+						let size = usize::from_str_radix(size_str, 16)
+							.map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+						if size == 0 {
+							// NOTE trailer fields are read and dropped
+							while self.read_line()? != "" {}
+							self.chunk_state = ChunkState::Done;
+						}else {
+							self.remaining = size;
+							self.chunk_state = ChunkState::Data;
+						}
+					},
+					ChunkState::Data => {
+						let limit = destination.len().min(self.remaining);
+						let count = self.read_raw(&mut destination[..limit])?;
+						self.remaining -= count;
+						if self.remaining == 0 { self.chunk_state = ChunkState::DataEnd; }
+						return Ok(count);
 					},
-					_ => {
-						println!("\rWARN: failed to read from tcp socket -> {e}");
-						break 'preliminary_read;
+					ChunkState::DataEnd => {
+						if self.read_line()? != "" {
+							return Err(std::io::ErrorKind::InvalidData.into());
+						}
+						self.chunk_state = ChunkState::Size;
 					}
-				}}
+				}
 			}
 		}
+	}
+}
+
+pub enum HeaderParam<'a> {
+	Plain(&'a str),
+	Quoted(String),
+}
 
-		println!("\rDBG: no body -> buffer len = {}", buffer.len());
-		println!("\rDBG: body string -> {}", unsafe{ buffer.as_slice().as_ascii_unchecked() }.as_str() );
-		if buffer.len() == 0 { bail!("Failed to read any bytes from source, even in blocking mode"); }
+impl HeaderParam<'_> {
+	pub fn as_str(&self) -> &str {
+		match self {
+			HeaderParam::Plain(value) => value,
+			HeaderParam::Quoted(value) => value.as_str(),
+		}
+	}
+}
 
-		let (head, body) = match crate::split_slice_uninclusive(buffer.as_slice(), b"\r\n\r\n") { 
+// splits the `; key=value` parameters following a header value, quoted
+// values have their quotes and backslash escapes removed
+pub fn split_header_params(source: &str) -> Vec<(&str, HeaderParam<'_>)> {
+	let mut params = Vec::new();
+	let mut rest = match source.split_once(';') {
+		Some((_value, rest)) => rest,
+		None => return params,
+	};
+
+	loop {
+		rest = rest.trim_start_matches([' ', '\t', ';']);
+		let (key, after_key) = match rest.split_once('=') {
 			Some(pair) => pair,
-			None => {
-				// println!("\rWARN: did not receive body separator from http request in time");
-				(&buffer.as_slice()[..buffer.len()-4], &b""[..])
+			None => return params,
+		};
+		let key = key.trim();
+
+		if after_key.starts_with('"') {
+			let mut value = String::new();
+			let mut escaped = false;
+			let mut end = after_key.len();
+			for (index, chr) in after_key.char_indices().skip(1) {
+				if escaped { value.push(chr); escaped = false; }
+				else if chr == '\\' { escaped = true; }
+				else if chr == '"' { end = index + 1; break; }
+				else { value.push(chr); }
 			}
+			params.push((key, HeaderParam::Quoted(value)));
+			rest = &after_key[end..];
+		}else {
+			let (value, after_value) = after_key.split_once(';').unwrap_or((after_key, ""));
+			params.push((key, HeaderParam::Plain(value.trim())));
+			rest = after_value;
+		}
+	}
+}
+
+pub struct MultipartPart {
+	pub name: Option<String>,
+	pub filename: Option<String>,
+	pub content_type: Option<String>,
+}
+
+// NOTE a streaming multipart/form-data parser, Read yields the data of the
+// current part and next_part skips whatever is left of it
+pub struct MultipartReader<'r> {
+	source: &'r mut dyn Read,
+	delimiter: Vec<u8>,
+	buffer: Vec<u8>,
+	in_part: bool,
+	finished: bool,
+}
+
+impl<'r> MultipartReader<'r> {
+	const MAX_PART_HEAD: usize = 16 * 1024;
+
+	pub fn new(source: &'r mut dyn Read, boundary: &str) -> Self {
+		let mut delimiter = b"\r\n--".to_vec();
+		delimiter.extend_from_slice(boundary.as_bytes());
+		return Self {
+			source,
+			delimiter,
+			// NOTE the first delimiter has no leading CRLF, pretending there was
+			// one lets every delimiter be matched the same way
+			buffer: b"\r\n".to_vec(),
+			// NOTE starting inside a part makes next_part skip the preamble
+			in_part: true,
+			finished: false,
 		};
-		let head = unsafe{ head.as_ascii_unchecked() }.as_str();
+	}
 
-		let mut headers = Vec::<crate::http::HttpHeader>::new();
-		let mut header_iter = head.split("\r\n");
-		let primary_header = header_iter.next()
-			.ok_or(anyhow!("Http request without primary header"))?;
-		for line in header_iter {
-			let (key, value) = match line.split_once(": ") {
-				Some(pair) => pair,
-				None => {
-					println!("\rWARN: malformed http header (missing value) -> {}", line);
-					continue;
-				}
+	fn fill(&mut self) -> Result<()> {
+		let mut intermediate_buffer = [0u8; 16384];
+		let count = self.source.read(&mut intermediate_buffer)?;
+		if count == 0 { bail!("multipart body ended before its closing delimiter"); }
+		self.buffer.extend_from_slice(&intermediate_buffer[..count]);
+		return Ok(());
+	}
+
+	fn find_delimiter(&self) -> Option<usize> {
+		return self.buffer.windows(self.delimiter.len())
+			.position(|window| window == self.delimiter.as_slice());
+	}
+
+	pub fn next_part(&mut self) -> Result<Option<MultipartPart>> {
+		let mut scratch = [0u8; 16384];
+		while self.in_part {
+			if self.read(&mut scratch)? == 0 { break; }
+		}
+		if self.finished { return Ok(None); }
+
+		while self.buffer.len() < self.delimiter.len() + 2 { self.fill()?; }
+		self.buffer.drain(..self.delimiter.len());
+		if self.buffer.starts_with(b"--") {
+			self.finished = true;
+			return Ok(None);
+		}
+
+		let head_end = loop {
+			if let Some(index) = self.buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+				break index;
+			}
+			if self.buffer.len() > Self::MAX_PART_HEAD { bail!("multipart part head too large"); }
+			self.fill()?;
+		};
+
+		let head = String::from_utf8(self.buffer[..head_end].to_vec())?;
+		self.buffer.drain(..head_end + 4);
+
+		let mut part = MultipartPart{ name: None, filename: None, content_type: None };
+		// NOTE the first line is the transport padding after the delimiter
+		for line in head.split("\r\n").skip(1) {
+			let (key, value) = match line.split_once(':') {
+				Some((key, value)) => (key.trim(), value.trim()),
+				None => continue,
 			};
-			match crate::http::HttpHeader::from_str_pair(key, value) {
-				Ok(Some(header)) => {
-					headers.push(header);
-				},
-				Ok(None) => {
-					// println!("\rWARN: failed to parse http header | key: {} - value: {} |", key, value);
-				},
-				Err(e) => {
-					println!("\rWARN: failed to parse http header -> {e} - {}", line);
+			if key.eq_ignore_ascii_case("Content-Disposition") {
+				for (param, param_value) in split_header_params(value) {
+					if param.eq_ignore_ascii_case("name") {
+						part.name = Some(param_value.as_str().to_owned());
+					}
 				}
+				part.filename = disposition_filename(value);
+			}else if key.eq_ignore_ascii_case("Content-Type") {
+				part.content_type = Some(value.to_owned());
 			}
 		}
 
-		let mut primary_header_segments = primary_header.split(" ");
-		let version_str = primary_header_segments.next()
-			.ok_or(anyhow!("malformed http primary header - no method"))?;
-		let status_code = primary_header_segments.next()
-			.ok_or(anyhow!("malformed http primary header - no route"))?
-			.parse::<usize>()?;
-		let status_text = primary_header_segments.next()
-			.ok_or(anyhow!("malformed http primary header - no version"))?;
-
-		// let (route_str, query_param_str) = uri_str.split_once("?")
-		// 	.unwrap_or((uri_str, ""));
-
-		// let method = HttpMethod::from_str(method_str)
-		// 	.ok_or(anyhow!("invalid http method"))?;
-	
-		return Ok(HttpResponse{
-			protocol_version: version_str,
-			headers,
-			body,
-			status_code,
-			status_text,
-		});
+		self.in_part = true;
+		return Ok(Some(part));
 	}
+}
+
+impl Read for MultipartReader<'_> {
+	fn read(&mut self, destination: &mut [u8]) -> std::io::Result<usize> {
+		if !self.in_part || destination.len() == 0 { return Ok(0); }
+		loop {
+			// NOTE bytes that could be the start of a delimiter are held back
+			let available = match self.find_delimiter() {
+				Some(0) => {
+					self.in_part = false;
+					return Ok(0);
+				},
+				Some(index) => index,
+				None => self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+			};
+			if available > 0 {
+				let count = available.min(destination.len());
+				destination[..count].copy_from_slice(&self.buffer[..count]);
+				self.buffer.drain(..count);
+				return Ok(count);
+			}
+			self.fill().map_err(|e| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e.to_string()))?;
+		}
+	}
+}
 
+pub struct HttpResponse<'a> {
+	pub protocol_version: &'a str,
+	pub status_code: usize,
+	pub status_text: &'a str,
+	pub headers: Vec<HttpHeader<'a>>,
+	pub body: &'a [u8]
+}
+
+impl HttpResponse<'_> {
 	fn write_headers_to(&self, sink: &mut dyn Write) -> Result<()> {
 		sink.write(self.protocol_version.as_bytes())?;
 		sink.write(b" ")?;
@@ -646,7 +1739,7 @@ impl HttpResponse<'_> {
 		sink.write(b"\r\n")?;
 
 		for header in self.headers.iter() {
-			let mut header = *header;
+			let mut header = header.clone();
 			header.read_into(sink)?;
 			sink.write(b"\r\n")?;
 		}
@@ -654,8 +1747,18 @@ impl HttpResponse<'_> {
 		return Ok(());
 	}
 
+	fn has_content_length(&self) -> bool {
+		return self.headers.iter().any(|header| matches!(header, HttpHeader::ContentLength(_)));
+	}
+
+	// NOTE a Content-Length is always sent so that the connection can be
+	// reused for another request afterwards
 	pub fn write_to_sink(&self, sink: &mut dyn Write) -> Result<()> {
 		self.write_headers_to(sink)?;
+		if !self.has_content_length() {
+			HttpHeader::ContentLength(self.body.len()).read_into(sink)?;
+			sink.write(b"\r\n")?;
+		}
 		sink.write(b"\r\n")?;
 		sink.flush()?;
 
@@ -664,11 +1767,23 @@ impl HttpResponse<'_> {
 		return Ok(());
 	}
 
+	// NOTE without a Content-Length header the body is rendered in memory
+	// first to measure it
 	pub fn write_from_readinto(&self, source: &mut dyn ReadInto, sink: &mut dyn Write) -> Result<()> {
-		self.write_headers_to(sink)?;
-		sink.write(b"\r\n")?;
+		if self.has_content_length() {
+			self.write_headers_to(sink)?;
+			sink.write(b"\r\n")?;
+			source.read_into(sink)?;
+			return Ok(());
+		}
 
-		source.read_into(sink)?;
+		let mut body = Vec::<u8>::new();
+		source.read_into(&mut body)?;
+
+		self.write_headers_to(sink)?;
+		HttpHeader::ContentLength(body.len()).read_into(sink)?;
+		sink.write(b"\r\n\r\n")?;
+		sink.write_all(body.as_slice())?;
 
 		return Ok(());
 	}
@@ -697,20 +1812,6 @@ macro_rules! iterator_reader {
 	};
 }
 
-pub fn make_http_request(
-	buffer: &mut StreamBuffer,
-	method: HttpMethod,
-	route: &str,
-	headers: &[HttpHeader],
-) -> Result<()> {
-	write!(buffer, "{} {} HTTP/1.1", method.as_str(), route)?;
-	for header in headers { header.clone().read_into(buffer)?; }
-	buffer.write(b"\r\n\r\n")?;
-	
-	return Ok(());
-}
-
-
 #[cfg(test)]
 mod http_tools_test {
 	#[test]
@@ -740,8 +1841,8 @@ mod http_tools_test {
 			output.clear();
 			response_structs[index].write_to_sink(&mut output).unwrap();
 			assert_eq!(
-				unsafe{ output.as_slice().as_ascii_unchecked() }.as_str(),
-				unsafe{ response_strings[index].as_ascii_unchecked() }.as_str()
+				std::str::from_utf8(output.as_slice()).unwrap(),
+				std::str::from_utf8(response_strings[index]).unwrap()
 			);
 		}
 	}
@@ -762,5 +1863,351 @@ mod http_tools_test {
 		req.write_to_sink(&mut buffer).unwrap();
 		assert_eq!(buffer.as_slice(), req_str);
 	}
-}
 
+	#[test]
+	fn test_byte_range_parsing() {
+		use super::ByteRange;
+
+		assert_eq!(ByteRange::parse_ranges("bytes=0-99").unwrap(), vec![ByteRange::Bounded(0, 99)]);
+		assert_eq!(
+			ByteRange::parse_ranges("bytes=500-, -200,10-20").unwrap(),
+			vec![ByteRange::From(500), ByteRange::Suffix(200), ByteRange::Bounded(10, 20)]
+		);
+		assert!(ByteRange::parse_ranges("items=0-1").is_err());
+		assert!(ByteRange::parse_ranges("bytes=20-10").is_err());
+		assert!(ByteRange::parse_ranges("bytes=-").is_err());
+		assert!(ByteRange::parse_ranges("bytes=").is_err());
+	}
+
+	#[test]
+	fn test_byte_range_resolution() {
+		use super::ByteRange;
+
+		assert_eq!(ByteRange::Bounded(0, 99).resolve(1000), Some((0, 99)));
+		assert_eq!(ByteRange::Bounded(900, 2000).resolve(1000), Some((900, 999)));
+		assert_eq!(ByteRange::Bounded(1000, 2000).resolve(1000), None);
+		assert_eq!(ByteRange::From(10).resolve(1000), Some((10, 999)));
+		assert_eq!(ByteRange::Suffix(200).resolve(1000), Some((800, 999)));
+		assert_eq!(ByteRange::Suffix(2000).resolve(1000), Some((0, 999)));
+		assert_eq!(ByteRange::Suffix(0).resolve(1000), None);
+		assert_eq!(ByteRange::From(0).resolve(0), None);
+	}
+
+	#[test]
+	fn test_content_range_header() {
+		use super::ReadInto;
+
+		let mut output = Vec::<u8>::new();
+		super::HttpHeader::ContentRange(super::ContentRange::Bytes{ start: 0, end: 99, total: 1000 })
+			.read_into(&mut output).unwrap();
+		assert_eq!(output.as_slice(), b"Content-Range: bytes 0-99/1000");
+
+		output.clear();
+		super::HttpHeader::ContentRange(super::ContentRange::Unsatisfied(1000))
+			.read_into(&mut output).unwrap();
+		assert_eq!(output.as_slice(), b"Content-Range: bytes */1000");
+	}
+
+	#[test]
+	fn test_read_request_with_content_length() {
+		let raw = b"POST /peers HTTP/1.1\r\nContent-Length: 11\r\n\r\n10.0.0.2\nabGET / HTTP/1.1\r\n\r\n";
+		let mut source = std::io::Cursor::new(&raw[..]);
+		let mut buffer = Vec::<u8>::new();
+		let request = super::HttpRequest::read_blocking(
+			&mut buffer, &mut source, super::RequestLimits::default()
+		).unwrap();
+
+		assert_eq!(request.route, "/peers");
+		assert_eq!(request.body, b"10.0.0.2\nab");
+	}
+
+	#[test]
+	fn test_read_request_across_reads() {
+		// NOTE yields the request a few bytes at a time like a slow socket would
+		struct Trickle<'a>(&'a [u8]);
+		impl std::io::Read for Trickle<'_> {
+			fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+				let count = buf.len().min(self.0.len()).min(3);
+				buf[..count].copy_from_slice(&self.0[..count]);
+				self.0 = &self.0[count..];
+				return Ok(count);
+			}
+		}
+
+		let mut source = Trickle(b"POST /peers HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nTrailer: x\r\n\r\n");
+		let mut buffer = Vec::<u8>::new();
+		let request = super::HttpRequest::read_blocking(
+			&mut buffer, &mut source, super::RequestLimits::default()
+		).unwrap();
+
+		assert_eq!(request.body, b"hello, world");
+	}
+
+	#[test]
+	fn test_body_reader_leftover() {
+		use std::io::Read;
+
+		let mut source = std::io::Cursor::new(&b"lo\r\n0\r\n\r\nnext"[..]);
+		let mut reader = super::BodyReader::new(
+			&mut source, b"2\r\nhe\r\n3\r\nl".to_vec(), super::BodyFraming::Chunked
+		);
+		let mut body = Vec::<u8>::new();
+		reader.read_to_end(&mut body).unwrap();
+
+		assert_eq!(body.as_slice(), b"hello");
+		assert!(reader.is_finished());
+		assert_eq!(reader.into_leftover().as_slice(), b"next");
+	}
+
+	#[test]
+	fn test_request_limits() {
+		let limits = super::RequestLimits{ max_header_size: 64, max_body_size: 8, max_upload_size: 8 };
+
+		let mut buffer = Vec::<u8>::new();
+		let mut source = std::io::Cursor::new(&b"GET / HTTP/1.1\r\nUser-Agent: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n"[..]);
+		let error = super::HttpRequest::read_blocking(&mut buffer, &mut source, limits).err().unwrap();
+		assert!(matches!(error.downcast_ref::<super::HttpReadError>(), Some(super::HttpReadError::HeaderTooLarge)));
+
+		let mut buffer = Vec::<u8>::new();
+		let mut source = std::io::Cursor::new(&b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789"[..]);
+		let error = super::HttpRequest::read_blocking(&mut buffer, &mut source, limits).err().unwrap();
+		assert!(matches!(error.downcast_ref::<super::HttpReadError>(), Some(super::HttpReadError::BodyTooLarge)));
+
+		let mut buffer = Vec::<u8>::new();
+		let mut source = std::io::Cursor::new(&b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n123456789\r\n0\r\n\r\n"[..]);
+		let error = super::HttpRequest::read_blocking(&mut buffer, &mut source, limits).err().unwrap();
+		assert!(matches!(error.downcast_ref::<super::HttpReadError>(), Some(super::HttpReadError::BodyTooLarge)));
+
+		let mut buffer = Vec::<u8>::new();
+		let mut source = std::io::Cursor::new(&b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n1234"[..]);
+		let error = super::HttpRequest::read_blocking(&mut buffer, &mut source, super::RequestLimits::default()).err().unwrap();
+		assert!(matches!(error.downcast_ref::<super::HttpReadError>(), Some(super::HttpReadError::ConnectionClosed)));
+	}
+
+	#[test]
+	fn test_multipart_reader() {
+		use std::io::Read;
+
+		let body = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nhi\r\n--XyZ\r\nContent-Disposition: form-data; name=\"files\"; filename=\"a \\\"b\\\";c.txt\"\r\nContent-Type: text/plain\r\n\r\nline one\r\n--Xy not a delimiter\r\n--XyZ--\r\n";
+		let mut source = &body[..];
+		let mut multipart = super::MultipartReader::new(&mut source, "XyZ");
+
+		let part = multipart.next_part().unwrap().unwrap();
+		assert_eq!(part.name.as_deref(), Some("note"));
+		assert_eq!(part.filename, None);
+
+		// NOTE the rest of the note part is skipped without reading it
+		let part = multipart.next_part().unwrap().unwrap();
+		assert_eq!(part.name.as_deref(), Some("files"));
+		assert_eq!(part.filename.as_deref(), Some("a \"b\";c.txt"));
+		assert_eq!(part.content_type.as_deref(), Some("text/plain"));
+
+		let mut data = Vec::<u8>::new();
+		multipart.read_to_end(&mut data).unwrap();
+		assert_eq!(data.as_slice(), b"line one\r\n--Xy not a delimiter");
+
+		assert!(multipart.next_part().unwrap().is_none());
+	}
+
+	#[test]
+	fn test_multipart_boundary() {
+		use super::{HttpHeader, ReadInto};
+
+		let boundary = |value: &str| match HttpHeader::from_str_pair("Content-Type", value) {
+			Ok(Some(HttpHeader::MultipartFormData(boundary))) => Some(boundary.into_owned()),
+			_ => None,
+		};
+		assert_eq!(boundary("multipart/form-data; boundary=XyZ").as_deref(), Some("XyZ"));
+		assert_eq!(boundary("multipart/form-data; boundary=\"Xy:Z 1\"; charset=utf-8").as_deref(), Some("Xy:Z 1"));
+		assert_eq!(boundary("multipart/form-data"), None);
+
+		let mut written = Vec::<u8>::new();
+		HttpHeader::MultipartFormData("Xy:Z 1".into()).read_into(&mut written).unwrap();
+		assert_eq!(written, b"Content-Type: multipart/form-data; boundary=\"Xy:Z 1\"");
+	}
+
+	#[test]
+	fn test_multipart_reader_truncated() {
+		let mut source = &b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nunterminated"[..];
+		let mut multipart = super::MultipartReader::new(&mut source, "XyZ");
+
+		assert!(multipart.next_part().unwrap().is_some());
+		assert!(multipart.next_part().is_err());
+	}
+
+	#[test]
+	fn test_content_disposition() {
+		use super::{ContentDisposition, ReadInto};
+
+		let header = |name: &str| {
+			let mut output = Vec::<u8>::new();
+			ContentDisposition::Attachment(Some(name.into())).read_into(&mut output).unwrap();
+			String::from_utf8(output).unwrap()
+		};
+		assert_eq!(header("notes.txt"), "Content-Disposition: attachment; filename=\"notes.txt\"");
+		assert_eq!(
+			header("my sóng+1 \"%\".txt"),
+			"Content-Disposition: attachment; filename=\"my s_ng+1 ___.txt\"; filename*=UTF-8''my%20s%C3%B3ng+1%20%22%25%22.txt"
+		);
+
+		// NOTE every name written reads back the same
+		for name in ["notes.txt", "a;b=c.txt", "my sóng+1 & co.txt", "日本語 \"quoted\\\".txt"] {
+			let value = header(name);
+			let value = value.strip_prefix("Content-Disposition: ").unwrap();
+			assert_eq!(ContentDisposition::from_str(value).unwrap(), ContentDisposition::Attachment(Some(name.into())));
+		}
+
+		assert_eq!(super::disposition_filename("attachment; filename=\"a; b.txt\"").as_deref(), Some("a; b.txt"));
+		assert_eq!(super::disposition_filename("attachment; FILENAME=plain.txt").as_deref(), Some("plain.txt"));
+		assert_eq!(super::disposition_filename("attachment; filename*=iso-8859-1'en'%E9t%E9.txt; filename=ete.txt").as_deref(), Some("été.txt"));
+		// NOTE an undecodable filename* falls back to filename
+		assert_eq!(super::disposition_filename("attachment; filename*=UTF-8''%FF; filename=ok.txt").as_deref(), Some("ok.txt"));
+		assert_eq!(super::disposition_filename("attachment"), None);
+		assert_eq!(ContentDisposition::from_str("Inline").unwrap(), ContentDisposition::Inline);
+		assert!(ContentDisposition::from_str("form-data; name=\"a\"").is_err());
+
+		let body = "--XyZ\r\nContent-Disposition: form-data; name=\"files\"; filename=\"_.txt\"; filename*=UTF-8''%E6%97%A5.txt\r\n\r\ndata\r\n--XyZ--\r\n";
+		let mut source = body.as_bytes();
+		let mut multipart = super::MultipartReader::new(&mut source, "XyZ");
+		assert_eq!(multipart.next_part().unwrap().unwrap().filename.as_deref(), Some("日.txt"));
+	}
+
+	#[test]
+	fn test_keep_alive_negotiation() {
+		let cases: &[(&[u8], bool)] = &[
+			(b"GET / HTTP/1.1", true),
+			(b"GET / HTTP/1.1\r\nConnection: close", false),
+			(b"GET / HTTP/1.1\r\nConnection: keep-alive, close", false),
+			(b"GET / HTTP/1.0", false),
+			(b"GET / HTTP/1.0\r\nConnection: Keep-Alive", true),
+		];
+		for (head, keep_alive) in cases {
+			let request = super::HttpRequest::parse(head, b"").unwrap();
+			assert_eq!(request.wants_keep_alive(), *keep_alive);
+		}
+	}
+
+	#[test]
+	fn test_bearer_token() {
+		let cases: &[(&[u8], Option<&str>)] = &[
+			(b"POST /admin/remove HTTP/1.1\r\nAuthorization: Bearer s3cret", Some("s3cret")),
+			(b"POST /admin/remove HTTP/1.1\r\nAuthorization: bearer  s3cret ", Some("s3cret")),
+			(b"POST /admin/remove HTTP/1.1\r\nAuthorization: Basic dXNlcjpwYXNz", None),
+			(b"POST /admin/remove HTTP/1.1", None),
+		];
+		for (head, token) in cases {
+			let request = super::HttpRequest::parse(head, b"").unwrap();
+			assert_eq!(request.bearer_token(), *token);
+		}
+	}
+
+	#[test]
+	fn test_prefers_json() {
+		let cases: &[(&[u8], bool)] = &[
+			(b"GET /files HTTP/1.1\r\nAccept: application/json", true),
+			(b"GET /files HTTP/1.1\r\nAccept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8", false),
+			(b"GET /files HTTP/1.1\r\nAccept: text/html;q=0.5, application/*", true),
+			(b"GET /files HTTP/1.1\r\nAccept: application/json;q=0.2, */*", false),
+			(b"GET /files HTTP/1.1\r\nAccept: */*", false),
+			(b"GET /files HTTP/1.1", false),
+		];
+		for (head, expected) in cases {
+			let request = super::HttpRequest::parse(head, b"").unwrap();
+			assert_eq!(request.prefers_json(), *expected, "{}", std::str::from_utf8(head).unwrap());
+		}
+	}
+
+	#[test]
+	fn test_header_injector() {
+		use std::io::Write;
+
+		let mut output = Vec::<u8>::new();
+		let mut injector = super::HeaderInjector::new(&mut output, Some("Connection: close"));
+		injector.write_all(b"HTTP/1.1 200").unwrap();
+		injector.write_all(b" OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+		assert_eq!(output.as_slice(), b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+	}
+
+	#[test]
+	fn test_template_escaping() {
+		use super::{Template, TemplateValue};
+
+		let render = |template: &[u8], values: &[(&str, TemplateValue)]| {
+			let mut output = Vec::<u8>::new();
+			Template::parse(template, &|name| Err(anyhow!("no partial {}", name))).unwrap()
+				.render(values, &mut output).unwrap();
+			return String::from_utf8(output).unwrap();
+		};
+
+		let name = "a \"b\" & 'c' </script><!--\n";
+		assert_eq!(
+			render(
+				b"<h1>%name%</h1><a title=\"%name:attr%\" href=\"?n=%name:url%\">%markup:raw%</a>\
+				<script>let s = \"%name:js%\";</script> 100%%",
+				&[("name", name.into()), ("markup", "<b>bold</b>".into())]
+			),
+			"<h1>a &quot;b&quot; &amp; &#39;c&#39; &lt;/script&gt;&lt;!--\n</h1>\
+			<a title=\"a &quot;b&quot; &amp; &#39;c&#39; &lt;/script&gt;&lt;!--&#10;\" \
+			href=\"?n=a%20%22b%22%20%26%20%27c%27%20%3C%2Fscript%3E%3C%21--%0A\"><b>bold</b></a>\
+			<script>let s = \"a \\\"b\\\" \\u0026 \\'c\\' \\u003C/script\\u003E\\u003C!--\\n\";</script> \
+			100%"
+		);
+		// NOTE a key that is missing or not text is an error, never written into the page
+		let template = Template::parse(b"%name% %record.missing%", &|_name| Ok(Vec::new())).unwrap();
+		assert!(template.render(&[("name", "a".into())], &mut Vec::<u8>::new()).is_err());
+		assert!(template.render(&[("name", vec!["a"].into()), ("record", TemplateValue::Record(vec![]))], &mut Vec::<u8>::new()).is_err());
+		assert!(template.render(&[("name", "a".into()), ("record", TemplateValue::Record(vec![("missing", "b".into())]))], &mut Vec::<u8>::new()).is_ok());
+		assert!(Template::parse(b"%name:bogus%", &|_name| Ok(Vec::new())).is_err());
+		assert!(Template::parse(b"width: 50%;", &|_name| Ok(Vec::new())).is_err());
+
+		assert_eq!(super::Escape::Html.apply("日本 <x>"), "日本 &lt;x&gt;");
+		assert_eq!(super::Escape::UrlComponent.apply("日"), "%E6%97%A5");
+		assert_eq!(super::Escape::UrlPath.apply("/a b/日"), "/a%20b/%E6%97%A5");
+		assert_eq!(super::Escape::Raw.apply("<x>"), "<x>");
+	}
+
+	#[test]
+	fn test_template_blocks() {
+		use super::{Template, TemplateValue};
+
+		let partials = |name: &str| match name {
+			"song.html" => Ok(b"<li>%loop.index%: %song.name%{% if song.new %} (new){% endif %}</li>".to_vec()),
+			"loop.html" => Ok(b"{% include \"loop.html\" %}".to_vec()),
+			_ => Err(anyhow!("no partial {}", name)),
+		};
+		let template = Template::parse(
+			b"<h1>%title%</h1>{% if songs %}<ol>{% for song in songs %}{% include \"song.html\" %}{% endfor %}</ol>\
+			{% else %}no songs{% endif %}{% if not songs %}!{% endif %}\
+			[{% for tag in tags %}\"%tag:js%\"{% if not loop.last %},{% endif %}{% endfor %}]",
+			&partials
+		).unwrap();
+
+		let song = |name: &str, new: bool| TemplateValue::Record(vec![("name", name.into()), ("new", new.into())]);
+		let mut output = Vec::<u8>::new();
+		template.render(&[
+			("title", "<mix>".into()),
+			("songs", TemplateValue::List(vec![song("a & b", false), song("c", true)])),
+			("tags", vec!["x", "y\""].into()),
+		], &mut output).unwrap();
+		assert_eq!(
+			String::from_utf8(output).unwrap(),
+			"<h1>&lt;mix&gt;</h1><ol><li>0: a &amp; b</li><li>1: c (new)</li></ol>[\"x\",\"y\\\"\"]"
+		);
+
+		let mut output = Vec::<u8>::new();
+		template.render(&[("title", "".into()), ("songs", TemplateValue::List(Vec::new()))], &mut output).unwrap();
+		assert_eq!(String::from_utf8(output).unwrap(), "<h1></h1>no songs![]");
+
+		for broken in [
+			b"{% for song in songs %}".as_slice(),
+			b"{% if songs %}{% endfor %}",
+			b"{% endif %}",
+			b"{% include \"missing.html\" %}",
+			b"{% include \"loop.html\" %}",
+			b"{% while songs %}",
+			b"{% if songs ",
+		] {
+			assert!(Template::parse(broken, &partials).is_err(), "{}", String::from_utf8_lossy(broken));
+		}
+	}
+}
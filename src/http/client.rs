@@ -0,0 +1,342 @@
+
+use std::{
+	io::Write,
+	net::{SocketAddr, TcpStream},
+	time::Duration,
+};
+
+use super::{BodyFraming, BodyReader, ConnectionOption, HttpHeader, HttpMethod, HttpReadError, HttpRequest, RequestLimits};
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClientOptions {
+	pub connect_timeout: Duration,
+	// how long the server may go quiet, while the request is sent or the
+	// response is read
+	pub read_timeout: Duration,
+	pub max_header_size: usize,
+	// NOTE redirects are only followed to the address the request was sent
+	// to, so a peer can not point the client at another host
+	pub max_redirects: usize,
+}
+
+impl Default for ClientOptions {
+	fn default() -> Self {
+		return Self {
+			connect_timeout: Duration::from_secs(10),
+			read_timeout: Duration::from_secs(30),
+			max_header_size: 16 * 1024,
+			max_redirects: 4,
+		};
+	}
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+	Connect(SocketAddr, std::io::Error),
+	TimedOut(SocketAddr),
+	Io(SocketAddr, std::io::Error),
+	// the server answered with something other than a valid http response
+	InvalidResponse(SocketAddr, String),
+	TooLarge(SocketAddr),
+	// a redirect that was not followed, holding its location
+	Redirect(SocketAddr, String),
+}
+
+impl ClientError {
+	// NOTE for errors reading a response body, which come out of a Read
+	pub fn from_io(addr: SocketAddr, error: std::io::Error) -> Self {
+		match error.kind() {
+			std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Self::TimedOut(addr),
+			std::io::ErrorKind::UnexpectedEof => Self::InvalidResponse(addr, "the connection closed before the body was complete".to_owned()),
+			std::io::ErrorKind::InvalidData => Self::InvalidResponse(addr, "invalid message body framing".to_owned()),
+			_ => Self::Io(addr, error),
+		}
+	}
+
+	fn from_read_error(addr: SocketAddr, error: HttpReadError) -> Self {
+		match error {
+			HttpReadError::TimedOut => Self::TimedOut(addr),
+			HttpReadError::HeaderTooLarge | HttpReadError::BodyTooLarge => Self::TooLarge(addr),
+			HttpReadError::ConnectionClosed => Self::InvalidResponse(addr, "the connection closed before the response was complete".to_owned()),
+			HttpReadError::Io(e) => Self::from_io(addr, e),
+			e => Self::InvalidResponse(addr, e.to_string()),
+		}
+	}
+
+	pub fn is_timeout(&self) -> bool {
+		return matches!(self, Self::TimedOut(_));
+	}
+}
+
+impl std::fmt::Display for ClientError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Connect(addr, e) => write!(f, "unable to connect to {} -> {}", addr, e),
+			Self::TimedOut(addr) => write!(f, "{} did not answer in time", addr),
+			Self::Io(addr, e) => write!(f, "connection to {} failed -> {}", addr, e),
+			Self::InvalidResponse(addr, reason) => write!(f, "{} sent an invalid response -> {}", addr, reason),
+			Self::TooLarge(addr) => write!(f, "the response of {} is too large", addr),
+			Self::Redirect(addr, location) => write!(f, "{} redirected to {}, which is not followed", addr, location),
+		}
+	}
+}
+
+impl std::error::Error for ClientError {}
+
+// a response whose head has been read, the body is left on the connection
+// until it is asked for
+pub struct Response {
+	pub status_code: usize,
+	pub status_text: String,
+	pub headers: Vec<(String, String)>,
+	addr: SocketAddr,
+	stream: TcpStream,
+	prefetched: Vec<u8>,
+	framing: BodyFraming,
+}
+
+impl Response {
+	// the first value of the header key, matched case insensitively
+	pub fn header(&self, key: &str) -> Option<&str> {
+		return self.headers.iter()
+			.find(|(header_key, _value)| header_key.eq_ignore_ascii_case(key))
+			.map(|(_key, value)| value.as_str());
+	}
+
+	pub fn framing(&self) -> BodyFraming { return self.framing; }
+
+	pub fn is_success(&self) -> bool { return (200..300).contains(&self.status_code); }
+
+	// NOTE the body can be read once, read errors can be turned into a
+	// ClientError with ClientError::from_io
+	pub fn body(&mut self) -> BodyReader<'_> {
+		return BodyReader::new(&mut self.stream, std::mem::take(&mut self.prefetched), self.framing);
+	}
+
+	// reads the whole body, failing once it is larger than limit
+	pub fn read_body(mut self, limit: usize) -> Result<Vec<u8>, ClientError> {
+		let addr = self.addr;
+		let mut body = Vec::<u8>::new();
+		self.body().read_to_end_limited(&mut body, limit)
+			.map_err(|e| ClientError::from_read_error(addr, e))?;
+		return Ok(body);
+	}
+}
+
+// sends a request without a body, see send
+pub fn get(addr: SocketAddr, target: &str, headers: &[HttpHeader], options: &ClientOptions) -> Result<Response, ClientError> {
+	return send(addr, HttpMethod::GET, target, headers, b"", options);
+}
+
+// sends one request over a new connection and reads the response head,
+// following redirects that stay on the same address. target is the path
+// with its query
+pub fn send(
+	addr: SocketAddr,
+	method: HttpMethod,
+	target: &str,
+	headers: &[HttpHeader],
+	body: &[u8],
+	options: &ClientOptions,
+) -> Result<Response, ClientError> {
+	let mut method = method;
+	let mut target = target.to_owned();
+	let mut body = body;
+	let mut redirects = 0;
+	loop {
+		let response = send_once(addr, method, &target, headers, body, options)?;
+		if !matches!(response.status_code, 301 | 302 | 303 | 307 | 308) { return Ok(response); }
+
+		let location = match response.header("Location") {
+			Some(location) => location.to_owned(),
+			None => return Ok(response),
+		};
+		target = match redirect_target(addr, &location) {
+			Some(target) if redirects < options.max_redirects => target,
+			_ => return Err(ClientError::Redirect(addr, location)),
+		};
+		redirects += 1;
+		// NOTE a 303 (and a 301 or 302, as browsers do) turns into a GET
+		if response.status_code != 307 && response.status_code != 308 {
+			method = HttpMethod::GET;
+			body = b"";
+		}
+	}
+}
+
+// the target of a redirect to location, when it stays on addr. locations
+// are either a path or an http url naming the same address
+fn redirect_target(addr: SocketAddr, location: &str) -> Option<String> {
+	if location.starts_with('/') && !location.starts_with("//") { return Some(location.to_owned()); }
+
+	let rest = location.strip_prefix("http://")?;
+	let (authority, path) = match rest.find(['/', '?']) {
+		Some(index) => (&rest[..index], &rest[index..]),
+		None => (rest, "/"),
+	};
+	let authority_addr = authority.parse::<SocketAddr>().ok()
+		.or_else(|| authority.trim_start_matches('[').trim_end_matches(']').parse::<std::net::IpAddr>().ok()
+			.map(|ip| SocketAddr::new(ip, 80)))?;
+	if authority_addr != addr { return None; }
+	return Some(match path.starts_with('?') {
+		true => format!("/{}", path),
+		false => path.to_owned(),
+	});
+}
+
+fn send_once(
+	addr: SocketAddr,
+	method: HttpMethod,
+	target: &str,
+	headers: &[HttpHeader],
+	body: &[u8],
+	options: &ClientOptions,
+) -> Result<Response, ClientError> {
+	let mut stream = match TcpStream::connect_timeout(&addr, options.connect_timeout) {
+		Ok(stream) => stream,
+		Err(e) if e.kind() == std::io::ErrorKind::TimedOut => return Err(ClientError::TimedOut(addr)),
+		Err(e) => return Err(ClientError::Connect(addr, e)),
+	};
+	let io_error = |e: std::io::Error| ClientError::from_io(addr, e);
+	stream.set_read_timeout(Some(options.read_timeout)).map_err(io_error)?;
+	stream.set_write_timeout(Some(options.read_timeout)).map_err(io_error)?;
+
+	// NOTE every request gets its own connection, which is closed after it
+	let host = addr.to_string();
+	let (route, query_params) = target.split_once('?').unwrap_or((target, ""));
+	let mut request = HttpRequest {
+		protocol_version: "HTTP/1.1",
+		method,
+		route,
+		query_params,
+		headers: vec![HttpHeader::Host(&host), HttpHeader::Connection(ConnectionOption::Close)],
+		body,
+	};
+	request.headers.extend(headers.iter().cloned());
+	if body.len() > 0 || matches!(method, HttpMethod::POST) {
+		request.headers.push(HttpHeader::ContentLength(body.len()));
+	}
+	let mut message = Vec::<u8>::new();
+	request.write_to_sink(&mut message)
+		.map_err(|e| ClientError::Io(addr, std::io::Error::other(e.to_string())))?;
+	stream.write_all(&message).map_err(io_error)?;
+	stream.flush().map_err(io_error)?;
+
+	let limits = RequestLimits { max_header_size: options.max_header_size, ..RequestLimits::default() };
+	let mut buffer = Vec::<u8>::new();
+	let head_length = super::read_request_head(&mut buffer, &mut stream, limits)
+		.map_err(|e| ClientError::from_read_error(addr, e))?;
+	let prefetched = buffer.split_off(head_length);
+
+	let (status_code, status_text, headers) = parse_response_head(&buffer[..head_length - 4])
+		.map_err(|reason| ClientError::InvalidResponse(addr, reason.to_owned()))?;
+	let framing = BodyFraming::from_response_head(&buffer[..head_length], status_code)
+		.map_err(|e| ClientError::from_read_error(addr, e))?;
+
+	return Ok(Response { status_code, status_text, headers, addr, stream, prefetched, framing });
+}
+
+// the status and headers of a response head, without its final blank line
+fn parse_response_head(head: &[u8]) -> Result<(usize, String, Vec<(String, String)>), &'static str> {
+	let head = std::str::from_utf8(head).map_err(|_| "the response head is not valid utf-8")?;
+	let mut lines = head.split("\r\n");
+
+	let mut status_line = lines.next().unwrap_or("").splitn(3, ' ');
+	let (status_code, status_text) = match (status_line.next(), status_line.next(), status_line.next()) {
+		(Some(version), Some(code), text) if version.starts_with("HTTP/1.") => (
+			code.parse::<usize>().map_err(|_| "malformed status line")?,
+			text.unwrap_or("").to_owned(),
+		),
+		_ => return Err("malformed status line"),
+	};
+
+	let mut headers = Vec::<(String, String)>::new();
+	for line in lines {
+		let (key, value) = line.split_once(':').ok_or("malformed header line")?;
+		headers.push((key.trim().to_owned(), value.trim().to_owned()));
+	}
+	return Ok((status_code, status_text, headers));
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use std::io::{Read, Write};
+
+	// serves each of responses to one connection, in order, returning the
+	// requests that were received
+	pub(crate) fn serve(responses: Vec<&'static [u8]>) -> (std::net::SocketAddr, std::thread::JoinHandle<Vec<String>>) {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let handle = std::thread::spawn(move || {
+			let mut requests = Vec::new();
+			for response in responses {
+				let (mut stream, _addr) = listener.accept().unwrap();
+				let mut buffer = Vec::new();
+				super::super::read_request_head(&mut buffer, &mut stream, Default::default()).unwrap();
+				requests.push(String::from_utf8(buffer).unwrap());
+				stream.write_all(response).unwrap();
+			}
+			return requests;
+		});
+		return (addr, handle);
+	}
+
+	#[test]
+	fn test_response_framing() {
+		let (addr, handle) = serve(vec![
+			b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello and more",
+			b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n",
+			b"HTTP/1.0 200 OK\r\nX-Note: until close\r\n\r\nhello",
+			b"HTTP/1.1 204 No Content\r\n\r\n",
+		]);
+		let options = super::ClientOptions::default();
+
+		for expected in ["hello", "hello", "hello", ""] {
+			let response = super::get(addr, "/files", &[], &options).unwrap();
+			assert!(response.is_success());
+			assert_eq!(response.read_body(1024).unwrap(), expected.as_bytes());
+		}
+
+		let requests = handle.join().unwrap();
+		assert!(requests[0].starts_with(&format!("GET /files HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", addr)));
+	}
+
+	#[test]
+	fn test_redirects_and_errors() {
+		let (addr, handle) = serve(vec![
+			b"HTTP/1.1 302 Found\r\nLocation: /moved?a=1\r\nContent-Length: 0\r\n\r\n",
+			b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+			b"HTTP/1.1 301 Moved Permanently\r\nLocation: http://10.1.2.3:8000/elsewhere\r\n\r\n",
+			b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nshort",
+			b"not http at all\r\n\r\n",
+		]);
+		let options = super::ClientOptions::default();
+
+		let mut response = super::get(addr, "/old", &[], &options).unwrap();
+		let mut body = String::new();
+		response.body().read_to_string(&mut body).unwrap();
+		assert_eq!(body, "ok");
+
+		let error = super::get(addr, "/", &[], &options).err().unwrap();
+		assert!(matches!(error, super::ClientError::Redirect(_, ref location) if location == "http://10.1.2.3:8000/elsewhere"));
+
+		let error = super::get(addr, "/", &[], &options).unwrap().read_body(1024).err().unwrap();
+		assert!(matches!(error, super::ClientError::InvalidResponse(..)));
+
+		let error = super::get(addr, "/", &[], &options).err().unwrap();
+		assert!(matches!(error, super::ClientError::InvalidResponse(..)));
+
+		let requests = handle.join().unwrap();
+		assert!(requests[1].starts_with("GET /moved?a=1 HTTP/1.1\r\n"));
+
+		// NOTE a server that accepts but never answers
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let options = super::ClientOptions { read_timeout: std::time::Duration::from_millis(100), ..options };
+		let error = super::get(listener.local_addr().unwrap(), "/", &[], &options).err().unwrap();
+		assert!(error.is_timeout());
+
+		drop(listener);
+	}
+}
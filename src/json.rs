@@ -0,0 +1,298 @@
+
+use anyhow::Result;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue {
+	Null,
+	Bool(bool),
+	Number(f64),
+	String(String),
+	Array(Vec<JsonValue>),
+	// NOTE keeps the key order of the source, objects here are small
+	Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+	pub fn parse(source: &str) -> Result<Self> {
+		let mut parser = Parser { source: source.as_bytes(), index: 0 };
+		let value = parser.parse_value(0)?;
+		parser.skip_whitespace();
+		if parser.index != parser.source.len() { bail!("trailing characters at byte {}", parser.index); }
+		return Ok(value);
+	}
+
+	pub fn get(&self, key: &str) -> Option<&JsonValue> {
+		match self {
+			JsonValue::Object(members) => members.iter()
+				.find(|(member_key, _value)| member_key == key)
+				.map(|(_key, value)| value),
+			_ => None,
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match self { JsonValue::String(string) => Some(string), _ => None }
+	}
+
+	pub fn as_array(&self) -> Option<&[JsonValue]> {
+		match self { JsonValue::Array(items) => Some(items), _ => None }
+	}
+
+	pub fn as_u64(&self) -> Option<u64> {
+		match self {
+			JsonValue::Number(number) if *number >= 0.0 && number.fract() == 0.0 => Some(*number as u64),
+			_ => None,
+		}
+	}
+
+	// serializes compactly, or indented by tabs when pretty
+	pub fn to_json_string(&self, pretty: bool) -> String {
+		let mut output = String::new();
+		self.write_to(&mut output, if pretty { Some(0) } else { None });
+		return output;
+	}
+
+	fn write_to(&self, output: &mut String, indent: Option<usize>) {
+		let newline = |output: &mut String, depth: usize| {
+			if indent.is_some() {
+				output.push('\n');
+				for _ in 0..depth { output.push('\t'); }
+			}
+		};
+		let depth = indent.unwrap_or(0);
+		let inner = indent.map(|depth| depth + 1);
+
+		match self {
+			JsonValue::Null => output.push_str("null"),
+			JsonValue::Bool(value) => output.push_str(if *value { "true" } else { "false" }),
+			JsonValue::Number(number) => {
+				if number.is_finite() { output.push_str(&number.to_string()); }
+				else { output.push_str("null"); }
+			},
+			JsonValue::String(string) => write_json_string(output, string),
+			JsonValue::Array(items) => {
+				output.push('[');
+				for (index, item) in items.iter().enumerate() {
+					if index > 0 { output.push(','); }
+					newline(output, depth + 1);
+					item.write_to(output, inner);
+				}
+				if !items.is_empty() { newline(output, depth); }
+				output.push(']');
+			},
+			JsonValue::Object(members) => {
+				output.push('{');
+				for (index, (key, value)) in members.iter().enumerate() {
+					if index > 0 { output.push(','); }
+					newline(output, depth + 1);
+					write_json_string(output, key);
+					output.push_str(if indent.is_some() { ": " } else { ":" });
+					value.write_to(output, inner);
+				}
+				if !members.is_empty() { newline(output, depth); }
+				output.push('}');
+			},
+		}
+	}
+}
+
+impl From<&str> for JsonValue {
+	fn from(value: &str) -> Self { return JsonValue::String(value.to_owned()); }
+}
+
+impl From<String> for JsonValue {
+	fn from(value: String) -> Self { return JsonValue::String(value); }
+}
+
+impl From<u64> for JsonValue {
+	fn from(value: u64) -> Self { return JsonValue::Number(value as f64); }
+}
+
+impl From<bool> for JsonValue {
+	fn from(value: bool) -> Self { return JsonValue::Bool(value); }
+}
+
+// appends string as a quoted json string
+pub fn write_json_string(output: &mut String, string: &str) {
+	output.push('"');
+	for chr in string.chars() {
+		match chr {
+			'"' => output.push_str("\\\""),
+			'\\' => output.push_str("\\\\"),
+			'\n' => output.push_str("\\n"),
+			'\r' => output.push_str("\\r"),
+			'\t' => output.push_str("\\t"),
+			// NOTE also escapes < so the output can sit inside a <script>
+			'<' => output.push_str("\\u003c"),
+			chr if (chr as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", chr as u32)),
+			chr => output.push(chr),
+		}
+	}
+	output.push('"');
+}
+
+// NOTE deeper documents are refused rather than risking the stack
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+	source: &'a [u8],
+	index: usize,
+}
+
+impl Parser<'_> {
+	fn skip_whitespace(&mut self) {
+		while self.index < self.source.len() && b" \t\r\n".contains(&self.source[self.index]) {
+			self.index += 1;
+		}
+	}
+
+	fn expect(&mut self, literal: &str) -> Result<()> {
+		if !self.source[self.index..].starts_with(literal.as_bytes()) {
+			bail!("expected {} at byte {}", literal, self.index);
+		}
+		self.index += literal.len();
+		return Ok(());
+	}
+
+	fn parse_value(&mut self, depth: usize) -> Result<JsonValue> {
+		if depth > MAX_DEPTH { bail!("json nested too deeply"); }
+		self.skip_whitespace();
+		match self.source.get(self.index) {
+			None => bail!("unexpected end of json"),
+			Some(b'n') => { self.expect("null")?; Ok(JsonValue::Null) },
+			Some(b't') => { self.expect("true")?; Ok(JsonValue::Bool(true)) },
+			Some(b'f') => { self.expect("false")?; Ok(JsonValue::Bool(false)) },
+			Some(b'"') => Ok(JsonValue::String(self.parse_string()?)),
+			Some(b'[') => {
+				self.index += 1;
+				let mut items = Vec::new();
+				self.skip_whitespace();
+				if self.source.get(self.index) == Some(&b']') {
+					self.index += 1;
+					return Ok(JsonValue::Array(items));
+				}
+				loop {
+					items.push(self.parse_value(depth + 1)?);
+					self.skip_whitespace();
+					match self.source.get(self.index) {
+						Some(b',') => self.index += 1,
+						Some(b']') => { self.index += 1; break; },
+						_ => bail!("expected , or ] at byte {}", self.index),
+					}
+				}
+				Ok(JsonValue::Array(items))
+			},
+			Some(b'{') => {
+				self.index += 1;
+				let mut members = Vec::new();
+				self.skip_whitespace();
+				if self.source.get(self.index) == Some(&b'}') {
+					self.index += 1;
+					return Ok(JsonValue::Object(members));
+				}
+				loop {
+					self.skip_whitespace();
+					if self.source.get(self.index) != Some(&b'"') { bail!("expected a key at byte {}", self.index); }
+					let key = self.parse_string()?;
+					self.skip_whitespace();
+					self.expect(":")?;
+					members.push((key, self.parse_value(depth + 1)?));
+					self.skip_whitespace();
+					match self.source.get(self.index) {
+						Some(b',') => self.index += 1,
+						Some(b'}') => { self.index += 1; break; },
+						_ => bail!("expected , or }} at byte {}", self.index),
+					}
+				}
+				Ok(JsonValue::Object(members))
+			},
+			Some(b'-' | b'0'..=b'9') => {
+				let start = self.index;
+				while self.index < self.source.len() && b"+-.eE0123456789".contains(&self.source[self.index]) {
+					self.index += 1;
+				}
+				let number = std::str::from_utf8(&self.source[start..self.index])?;
+				Ok(JsonValue::Number(number.parse::<f64>()
+					.map_err(|_| anyhow!("invalid number {} at byte {}", number, start))?))
+			},
+			Some(_) => bail!("unexpected character at byte {}", self.index),
+		}
+	}
+
+	fn parse_hex4(&mut self) -> Result<u32> {
+		let digits = self.source.get(self.index..self.index + 4)
+			.ok_or(anyhow!("truncated \\u escape"))?;
+		self.index += 4;
+		return Ok(u32::from_str_radix(std::str::from_utf8(digits)?, 16)?);
+	}
+
+	fn parse_string(&mut self) -> Result<String> {
+		self.index += 1;
+		let mut string = Vec::<u8>::new();
+		loop {
+			let byte = *self.source.get(self.index).ok_or(anyhow!("unterminated string"))?;
+			self.index += 1;
+			match byte {
+				b'"' => break,
+				b'\\' => {
+					let escape = *self.source.get(self.index).ok_or(anyhow!("unterminated string"))?;
+					self.index += 1;
+					let chr = match escape {
+						b'"' => '"', b'\\' => '\\', b'/' => '/',
+						b'b' => '\u{8}', b'f' => '\u{c}', b'n' => '\n', b'r' => '\r', b't' => '\t',
+						b'u' => {
+							let mut code = self.parse_hex4()?;
+							// NOTE characters outside the BMP come as a surrogate pair
+							if (0xd800..0xdc00).contains(&code) {
+								self.expect("\\u")?;
+								let low = self.parse_hex4()?;
+								if !(0xdc00..0xe000).contains(&low) { bail!("invalid surrogate pair"); }
+								code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+							}
+							char::from_u32(code).ok_or(anyhow!("invalid \\u escape"))?
+						},
+						_ => bail!("invalid escape at byte {}", self.index),
+					};
+					let mut encoded = [0u8; 4];
+					string.extend_from_slice(chr.encode_utf8(&mut encoded).as_bytes());
+				},
+				byte if byte < 0x20 => bail!("control character in string at byte {}", self.index),
+				byte => string.push(byte),
+			}
+		}
+		return Ok(String::from_utf8(string)?);
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::JsonValue;
+
+	#[test]
+	fn test_json_round_trip() {
+		let source = r#" { "version": 1, "names": ["a \"b\"", "caf\u00e9", "\ud83c\udfb5"], "empty": {}, "ok": true, "none": null, "size": -2.5e3 } "#;
+		let value = JsonValue::parse(source).unwrap();
+
+		assert_eq!(value.get("version").and_then(JsonValue::as_u64), Some(1));
+		let names = value.get("names").and_then(JsonValue::as_array).unwrap();
+		assert_eq!(names[0].as_str(), Some("a \"b\""));
+		assert_eq!(names[1].as_str(), Some("café"));
+		assert_eq!(names[2].as_str(), Some("🎵"));
+		assert_eq!(value.get("size"), Some(&JsonValue::Number(-2500.0)));
+
+		for pretty in [false, true] {
+			assert_eq!(JsonValue::parse(&value.to_json_string(pretty)).unwrap(), value);
+		}
+		assert_eq!(
+			JsonValue::Array(vec!["</script>".into(), 3u64.into()]).to_json_string(false),
+			"[\"\\u003c/script>\",3]"
+		);
+
+		for invalid in ["", "[1,]", "{\"a\" 1}", "\"open", "[1] 2", "tru", "\"\\ud800\""] {
+			assert!(JsonValue::parse(invalid).is_err(), "{}", invalid);
+		}
+		assert!(JsonValue::parse(&"[".repeat(100)).is_err());
+	}
+}
@@ -1,10 +1,6 @@
-#![feature(ascii_char)]
 #![feature(generic_arg_infer)]
-#![feature(addr_parse_ascii)]
 
-// TODO remove file serving duplcates in Globals::add_file
-
-use std::{io::Write, str::FromStr, sync::Arc};
+use std::io::Write;
 
 
 #[macro_use]
@@ -18,11 +14,25 @@ extern crate crossterm;
 mod http;
 mod routes;
 mod globals;
+mod config;
+mod commands;
+mod glob;
+mod json;
+mod state;
+mod content;
+mod url;
+mod mime;
+mod watcher;
+mod control;
+mod discovery;
+mod mdns;
+mod health;
 
 
 use globals::GLOBALS;
 use anyhow::Result;
 use routes::handle_client;
+use commands::CommandOutcome;
 
 
 // NOTE this could be done faster maybe with some SIMD or
@@ -61,8 +71,25 @@ impl<'a> CommandTokenIter<'a> {
 	}
 }
 
+// NOTE inside quotes \" and \\ stand for " and \, any other backslash is kept
+fn unescape_token(token: &str) -> std::borrow::Cow<'_, str> {
+	if !token.contains('\\') { return std::borrow::Cow::Borrowed(token); }
+	let mut unescaped = String::with_capacity(token.len());
+	let mut chars = token.chars().peekable();
+	while let Some(chr) = chars.next() {
+		match (chr, chars.peek()) {
+			('\\', Some(&next)) if next == '"' || next == '\\' => {
+				unescaped.push(next);
+				chars.next();
+			},
+			_ => unescaped.push(chr),
+		}
+	}
+	return std::borrow::Cow::Owned(unescaped);
+}
+
 impl<'a> Iterator for CommandTokenIter<'a> {
-	type Item = &'a str;
+	type Item = std::borrow::Cow<'a, str>;
 
 	fn next(&mut self) -> Option<Self::Item> {
 		if self.index >= self.source.len() { return None; }
@@ -78,17 +105,21 @@ impl<'a> Iterator for CommandTokenIter<'a> {
 			if self.source.as_bytes()[token_end] == b' ' && !inside_quotes {
 				let token_start = self.index;
 				self.index = token_end;
-				return Some(&self.source[token_start..self.index]);
+				return Some(std::borrow::Cow::Borrowed(&self.source[token_start..self.index]));
+			}else if inside_quotes && self.source.as_bytes()[token_end] == b'\\' {
+				// NOTE skips the escaped character, unescape_token resolves it
+				token_end += 2;
+				continue;
 			}else if self.source.as_bytes()[token_end] == b'\"' {
 				if inside_quotes {
 					let token_start = self.index + 1;
 					self.index = token_end + 1;
-					return Some(&self.source[token_start..token_end]);
+					return Some(unescape_token(&self.source[token_start..token_end]));
 				}
 				else if self.index != token_end {
 					let token_start = self.index;
 					self.index = token_end;
-					return Some(&self.source[token_start..(token_end-1)]);
+					return Some(std::borrow::Cow::Borrowed(&self.source[token_start..(token_end-1)]));
 				}
 				else {
 					inside_quotes = true;
@@ -98,8 +129,9 @@ impl<'a> Iterator for CommandTokenIter<'a> {
 		}
 		let mut token_start = self.index;
 		if self.source.as_bytes()[token_start] == b'\"' { token_start += 1; };
-		self.index = token_end;
-		return Some(&self.source[token_start..]);
+		self.index = self.source.len();
+		if inside_quotes { return Some(unescape_token(&self.source[token_start..])); }
+		return Some(std::borrow::Cow::Borrowed(&self.source[token_start..]));
 	}
 }
 
@@ -148,8 +180,34 @@ impl<T> Iterator for ThreadPool<T> {
 }
 
 
+fn say_goodbye() {
+	if !GLOBALS.config.mdns { return; }
+	if let Err(e) = mdns::say_goodbye() {
+		println!("\rWARN: failed to say goodbye over mDNS -> {}", e);
+	}
+}
+
 fn main() {
 
+	match config::parse_args(std::env::args().skip(1)) {
+		Ok(config::Invocation::Serve(config)) => config::set_startup_config(config),
+		Ok(config::Invocation::Control(config, command)) => {
+			if let Err(e) = control::send_command(&config.control_socket_path(), &command) {
+				eprintln!("Error: {}", e);
+				std::process::exit(1);
+			}
+			return;
+		},
+		Ok(config::Invocation::Help(usage)) => {
+			println!("{}", usage);
+			return;
+		},
+		Err(e) => {
+			eprintln!("Error: {}", e);
+			std::process::exit(2);
+		}
+	};
+
 	let _thread_cleaner_join_handle = std::thread::spawn(|| {
 		loop {
 			GLOBALS.thread_pool.lock().expect("Failed to lock global thread pool").clean_threads();
@@ -157,9 +215,14 @@ fn main() {
 		}
 	});
 
-	let listener = std::net::TcpListener::bind(
-		std::net::SocketAddrV4::new(std::net::Ipv4Addr::UNSPECIFIED, 8000)
-	).expect("Failed to bind tcp listener to unspecified address at port 8000");
+	let bind_address = std::net::SocketAddr::new(GLOBALS.config.bind_address, GLOBALS.config.port);
+	let listener = match std::net::TcpListener::bind(bind_address) {
+		Ok(listener) => listener,
+		Err(e) => {
+			eprintln!("Error: failed to bind tcp listener to {} -> {}", bind_address, e);
+			std::process::exit(1);
+		}
+	};
 
 	println!("\rINFO: serving at local addr: {:?}", listener.local_addr());
 
@@ -183,6 +246,41 @@ fn main() {
 		}
 	});
 
+	if !GLOBALS.config.rescan_interval.is_zero() {
+		let interval = GLOBALS.config.rescan_interval;
+		GLOBALS.push_thread(move || watcher::watch_directories(interval));
+	}
+
+	if GLOBALS.config.discovery {
+		let (group, interval) = (GLOBALS.config.discovery_group, GLOBALS.config.discovery_interval);
+		GLOBALS.push_thread(move || {
+			// NOTE peers can still be added by hand without it
+			if let Err(e) = discovery::run_discovery(group, interval) {
+				println!("\rWARN: peer discovery stopped -> {}", e);
+			}
+			return Ok(());
+		});
+	}
+
+	if GLOBALS.config.mdns {
+		GLOBALS.push_thread(move || {
+			if let Err(e) = mdns::run_mdns() {
+				println!("\rWARN: mDNS stopped -> {}", e);
+			}
+			return Ok(());
+		});
+	}
+
+	GLOBALS.push_thread(health::run_health_checks);
+
+	if GLOBALS.config.daemon {
+		if let Err(e) = control::serve_control_socket(&GLOBALS.config.control_socket_path()) {
+			println!("Error: control socket failed -> {}", e);
+		}
+		GLOBALS.save_state();
+		say_goodbye();
+		return;
+	}
 
 	crossterm::terminal::enable_raw_mode().expect("Failed to enable raw mode");
 
@@ -232,247 +330,22 @@ fn main() {
 		
 		}
 		stdout.write(b"\n").expect("Failed to write to stdout");
-		let line_str = &buffer.as_str()[0..];
-
-		let mut token_iterator = CommandTokenIter::new(line_str).into_iter();
-
-
-		match token_iterator.next() {
-			Some("quit") => {
-				if let Some(_) = token_iterator.next() {
-					println!("\rError: quit does not take any arguments");
-				}else {
-					break;
-				}
-			},
-			Some("clear") => {
-				if let Some(_) = token_iterator.next() {
-					println!("\rError: clear does not take any arguments");
-				}else {
-					queue!(stdout,
-						crossterm::terminal::Clear(crossterm::terminal::ClearType::All),
-						crossterm::cursor::MoveTo(0, 0)
-					).expect("Failed to queue to stdout");
-				}
-			},
-			Some("help") => {
-				if let Some(_) = token_iterator.next() {
-					println!("\rWARN: help does not currently process arguments");
-				}
-				println!("\
-	\rLocalShare - sharing files locally
-	\r----------------------------------
-	\r
-	\rquit / Ctrl-C               - quit the program / clear line then quit program
-	\rCtrl-W                      - clear line
-	\rshow                        - show the currently hosted files and playlists
-	\radd <file_path>             - add a file to the host list
-	\radd_playlist <playlist_dir> - add playlist_dir to playlists
-	\rdownload_playlist <name> <playlist url> [audio format]
-	\r                            - download a playlist with default audio format being flac\
-				");
-			},
-			Some("show") => {
-				match token_iterator.next() {
-					Some("files") => {
-						if let Some(_) = token_iterator.next() { println!("\rToo many args to \"show\""); }
-						else {
-							for filename in GLOBALS.read_file_entries().filenames.iter() {
-								println!("\r-> file {}", filename);
-							}
-						}
-					},
-					Some("playlists") => {
-						if let Some(_) = token_iterator.next() { println!("\rToo many args to \"show\""); }
-						else {
-							for playlist in GLOBALS.read_playlists().iter() {
-								println!("\r-> playlist {}", playlist.name);
-							}
-						}
-					},
-					Some(arg) => {
-						println!("\rError: unrecognized argument to \"show\": {}", arg);
-					},
-					None => {
-						for filename in GLOBALS.read_file_entries().filenames.iter() {
-							println!("\r-> file     {}", filename);
-						}
-						for playlist in GLOBALS.read_playlists().iter() {
-							println!("\r-> playlist {}", playlist.name);
-						}
-						for peer in GLOBALS.read_peers().iter() {
-							println!("\r-> peer {:?}", peer);
-						}
-					}
-				}
-			},
-			Some("add_playlist") => {
-				match token_iterator.next() {
-					Some(playlist_dir) => {
-						if let Some(_) = token_iterator.next() {
-							println!("\rError: too many argument to add_playlist");
-							continue;
-						}
-						if std::path::Path::is_dir(playlist_dir.as_ref()) {
-							println!("\rINFO: adding playlist");
-							GLOBALS.push_playlist_directory(playlist_dir).expect("Failed to push playlist to globals");
-						}
-						else if std::path::Path::exists(playlist_dir.as_ref()) {
-							println!("\rError: playlist {} is not a directory", playlist_dir);
-						} else {
-							println!("\rError: directory {} does not exist", playlist_dir);
-						}
-					},
-					None => {
-						println!("\rError: add_playlist requires an argument (playlist directory)");
-					}
-				}
-			},
-			Some("add") => {
-				for filename in token_iterator {
-					if std::path::Path::is_file(filename.as_ref()) {
-						println!("\rINFO: adding file {} to database", filename);
-						if let Err(e) = GLOBALS.push_file_entry( filename, filename ) {
-							println!("\rError: failed to map file {} | {}", filename, e);
-						}
-					}else if std::path::Path::is_dir(filename.as_ref()) {
-						println!("\rINFO: adding directories is not yet supported");
-					}
-					else {
-						println!("\rError: unable to locate file {}", filename);
-					}
-				}
-			},
-			Some("add_peer") => {
-				let peer_addr = match token_iterator.next() {
-					Some(addr_string) => {
-						if let Some(_) = token_iterator.next() {
-							println!("\rError: too many arguments to add_peer");
-							continue;
-						}
-						match std::net::IpAddr::from_str(addr_string) {
-							Ok(addr) => addr,
-							Err(e) => {
-								println!("\rError: failed to parse address of peer -> {}", e);
-								continue;
-							}
-						}
-					},
-					None => {
-						println!("\rError: add_peer expect an address");
-						continue;
-					}
-				};
-				GLOBALS.push_peer(peer_addr);
-			}
-			Some("download_playlist") => {
-				let playlist_name = match token_iterator.next() {
-					Some(name) => name,
-					None => {
-						println!("\rError: \"download_playlist\" requires a name argument and then a url argument");
-						continue;
-					}
-				};
-				let playlist_url = match token_iterator.next() {
-					Some(token) => token,
-					None => {
-						println!("\rError: \"download_playlist\" requires a name argument and then a url argument");
-						continue;
-					}
-				};
-				let audio_format = token_iterator.next().unwrap_or("flac");
-
-				let mut playlist_dirname = match std::env::current_dir() {
-					Ok(dir) => dir,
-					Err(e) => {
-						println!("\rError: unable to get current operating directory from environment -> {}", e);
-						continue;
-					}
-				};
-				playlist_dirname.push("playlists");
-				match std::fs::exists(&playlist_dirname) {
-					Ok(true) => {},
-					_ => {
-						if let Err(e) = std::fs::DirBuilder::new().create(&playlist_dirname) {
-							println!("\rError: failed to create playlists directory -> {}", e);
-							continue;
-						}
-					}
-				};
-				playlist_dirname.push(playlist_name);
-
-				// if let Ok(true) = std::fs::exists(playlist_dirname) {}
-				match std::fs::exists(&playlist_dirname) {
-					Ok(true) => {},
-					_ => {
-						if let Err(e) = std::fs::DirBuilder::new().create(&playlist_dirname) {
-							println!("\rError: failed to create directory (non-recursive) from playlist name -> {}", e);
-							continue;
-						}
-					}
-				};
-
-				let mut command = std::process::Command::new("yt-dlp");
-				command.arg(playlist_url)
-					.arg("-x")
-					.arg("--audio-format")
-					.arg(audio_format)
-					.arg("--audio-quality")
-					.arg("0"); // in ffmpeg 0 -> highest quality, 10 -> lowest
-
-				command.current_dir(&playlist_dirname);
-				// command.stdout(std::process::Stdio::inherit());
-				// command.stderr(std::process::Stdio::inherit())
-
-				let mut subproc = match command.spawn() {
-					Ok(child) => child,
-					Err(e) => {
-						println!("\rError: failed to spawn yt-dlp downloader backend -> {}", e);
-						continue;
-					}
-				};
 
-				crossterm::terminal::disable_raw_mode().unwrap();
-
-				let exit_status = match subproc.wait() {
-					Ok(status) => status,
-					Err(e) => {
-						println!("\rError: failed to wait for command -> {}", e);
-						continue;
-					}
-				};
-
-				crossterm::terminal::enable_raw_mode().unwrap();
-
-				if exit_status.success() {
-					println!("\ryt-dlp exited successfully with code 0");
-				}else {
-					println!("\rError: yt-dlp failed -> {}", exit_status);
-				}
-
-				;
-				if let Err(e) = GLOBALS.push_playlist_directory(
-					playlist_dirname.as_os_str().to_str().expect("failed to convert OsStr to str")
-				) {
-					println!("Error: failed to add newly downloaded playlist to playlist database -> {}", e);
-				}
+		match commands::run_command(buffer.as_str(), &mut stdout, true) {
+			Ok(CommandOutcome::Done) => {},
+			Ok(CommandOutcome::ClearScreen) => {
+				queue!(stdout,
+					crossterm::terminal::Clear(crossterm::terminal::ClearType::All),
+					crossterm::cursor::MoveTo(0, 0)
+				).expect("Failed to queue to stdout");
 			},
-			Some(_) => { println!("\rError: unrecognized command"); },
-			None => continue 'user_mainloop,
+			Ok(CommandOutcome::Quit) => break 'user_mainloop,
+			Err(e) => println!("\rError: failed to run command -> {}", e),
 		}
 	}
 
-	let mut entry_file = std::fs::File::create("entries.txt").expect("Failed to open entries file for saving");
-	for entry in GLOBALS.get_file_entry_names() {
-		entry_file.write(entry.as_bytes()).expect("failed to write to file");
-		entry_file.write(b"\n").expect("failed to write to file");
-	}
-
-	let mut playlist_file = std::fs::File::create("playlists.txt").expect("Failed to create/open playlists file");
-	for playlist_dir in GLOBALS.read_playlists().iter()
-		.map(|playlist| playlist.directory.clone()) {
-		write!(playlist_file, "{}\n", playlist_dir).expect("Failed to write to playlist file");
-	}
+	GLOBALS.save_state();
+	say_goodbye();
 
 	crossterm::terminal::disable_raw_mode().expect("Failed to exit raw mode");
 
@@ -489,6 +362,7 @@ mod tests {
 			"remove -r \"these things",
 			"quit   this",
 			"exit all    ",
+			"add \"say \\\"hi\\\".txt\" \"C:\\dir\\\\\"",
 		];
 		let command_token_strings: [&[&str]; _] = [
 			&["add", "this"],
@@ -496,6 +370,7 @@ mod tests {
 			&["remove", "-r", "these things"],
 			&["quit", "this"],
 			&["exit", "all"],
+			&["add", "say \"hi\".txt", "C:\\dir\\"],
 		];
 
 		for (command_iter, string) in command_strings.into_iter().enumerate() {
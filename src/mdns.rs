@@ -0,0 +1,750 @@
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+use anyhow::Result;
+
+use crate::globals::GLOBALS;
+
+
+pub const MDNS_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+// NOTE the largest message RFC 6762 allows, jumbo frames included
+const MAX_MESSAGE_SIZE: usize = 9000;
+// NOTE a compressed name pointing back into itself would loop forever
+const MAX_NAME_POINTERS: usize = 16;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// NOTE the top bit of the class is the cache-flush bit in records and the
+// unicast-response (QU) bit in questions
+const CLASS_FLAG: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+// NOTE the ttls RFC 6762 recommends for records naming a host and for the rest
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+// NOTE RFC 6762 caps the ttl of answers to legacy (non-mDNS) resolvers
+const LEGACY_TTL: u32 = 10;
+// NOTE a peer is kept no longer than this without being heard from again,
+// whatever ttl its records claim
+const MAX_PEER_TTL: u32 = HOST_TTL;
+const BROWSE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+const LOCALSHARE_SERVICE: &str = "_localshare._tcp.local";
+const HTTP_SERVICE: &str = "_http._tcp.local";
+const SERVICES_META_QUERY: &str = "_services._dns-sd._udp.local";
+
+// a domain name as its labels, which compare ignoring ascii case like dns does
+#[derive(Clone, Debug)]
+pub struct Name {
+	labels: Vec<String>,
+}
+
+impl Name {
+	// NOTE only for names without dots in their labels, see with_label
+	pub fn parse(dotted: &str) -> Self {
+		return Self { labels: dotted.split('.').filter(|label| *label != "").map(str::to_owned).collect() };
+	}
+
+	// label prepended to this name, cut to the 63 bytes a label can hold
+	pub fn with_label(&self, label: &str) -> Self {
+		let mut labels = vec![truncate(label, 63).to_owned()];
+		labels.extend(self.labels.iter().cloned());
+		return Self { labels };
+	}
+
+	pub fn first_label(&self) -> Option<&str> {
+		return self.labels.first().map(String::as_str);
+	}
+}
+
+impl PartialEq for Name {
+	fn eq(&self, other: &Self) -> bool {
+		return self.labels.len() == other.labels.len() && self.labels.iter().zip(&other.labels)
+			.all(|(label, other_label)| label.eq_ignore_ascii_case(other_label));
+	}
+}
+
+impl std::fmt::Display for Name {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return write!(f, "{}", self.labels.join("."));
+	}
+}
+
+fn truncate(source: &str, max_size: usize) -> &str {
+	let mut end = source.len().min(max_size);
+	while !source.is_char_boundary(end) { end -= 1; }
+	return &source[..end];
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Question {
+	pub name: Name,
+	pub kind: u16,
+	// NOTE the asker would like the answer sent to it directly
+	pub unicast: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordData {
+	A(Ipv4Addr),
+	Ptr(Name),
+	Txt(Vec<String>),
+	Srv { priority: u16, weight: u16, port: u16, target: Name },
+	Other(u16, Vec<u8>),
+}
+
+impl RecordData {
+	pub fn kind(&self) -> u16 {
+		return match self {
+			Self::A(_) => TYPE_A,
+			Self::Ptr(_) => TYPE_PTR,
+			Self::Txt(_) => TYPE_TXT,
+			Self::Srv { .. } => TYPE_SRV,
+			Self::Other(kind, _) => *kind,
+		};
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+	pub name: Name,
+	pub ttl: u32,
+	// NOTE replaces rather than adds to what caches hold for name
+	pub cache_flush: bool,
+	pub data: RecordData,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Message {
+	pub id: u16,
+	pub is_response: bool,
+	pub questions: Vec<Question>,
+	pub answers: Vec<Record>,
+	// NOTE authority records are parsed into here as well, they are of no
+	// use to a responder that does not probe
+	pub additional: Vec<Record>,
+}
+
+struct Reader<'a> {
+	message: &'a [u8],
+	offset: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn bytes(&mut self, size: usize) -> Result<&'a [u8]> {
+		let bytes = self.message.get(self.offset..self.offset + size)
+			.ok_or(anyhow!("message truncated at byte {}", self.offset))?;
+		self.offset += size;
+		return Ok(bytes);
+	}
+
+	fn u8(&mut self) -> Result<u8> { return Ok(self.bytes(1)?[0]); }
+	fn u16(&mut self) -> Result<u16> { return Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?)); }
+	fn u32(&mut self) -> Result<u32> { return Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?)); }
+
+	fn name(&mut self) -> Result<Name> {
+		let mut labels = Vec::new();
+		// NOTE after the first pointer the name continues elsewhere, but the
+		// message continues right after that pointer
+		let mut resume_at = None;
+		let mut pointers = 0;
+		loop {
+			let length = self.u8()?;
+			match length & 0xc0 {
+				0x00 if length == 0 => break,
+				0x00 => labels.push(String::from_utf8_lossy(self.bytes(length as usize)?).into_owned()),
+				0xc0 => {
+					let target = ((length as usize & 0x3f) << 8) | self.u8()? as usize;
+					pointers += 1;
+					if pointers > MAX_NAME_POINTERS { bail!("name with too many compression pointers"); }
+					resume_at.get_or_insert(self.offset);
+					self.offset = target;
+				},
+				_ => bail!("unsupported label type {:#x}", length & 0xc0),
+			}
+		}
+		if let Some(offset) = resume_at { self.offset = offset; }
+		return Ok(Name { labels });
+	}
+
+	fn question(&mut self) -> Result<Question> {
+		let name = self.name()?;
+		let kind = self.u16()?;
+		let class = self.u16()?;
+		return Ok(Question { name, kind, unicast: class & CLASS_FLAG != 0 });
+	}
+
+	fn record(&mut self) -> Result<Record> {
+		let name = self.name()?;
+		let kind = self.u16()?;
+		let class = self.u16()?;
+		let ttl = self.u32()?;
+		let size = self.u16()? as usize;
+		let end = self.offset + size;
+		if end > self.message.len() { bail!("record data of {} truncated", name); }
+
+		let data = match kind {
+			TYPE_A => RecordData::A(Ipv4Addr::from(<[u8; 4]>::try_from(self.bytes(4)?)?)),
+			TYPE_PTR => RecordData::Ptr(self.name()?),
+			TYPE_TXT => {
+				let mut strings = Vec::new();
+				while self.offset < end {
+					let length = self.u8()? as usize;
+					if length > 0 { strings.push(String::from_utf8_lossy(self.bytes(length)?).into_owned()); }
+				}
+				RecordData::Txt(strings)
+			},
+			TYPE_SRV => RecordData::Srv { priority: self.u16()?, weight: self.u16()?, port: self.u16()?, target: self.name()? },
+			_ => RecordData::Other(kind, self.bytes(size)?.to_vec()),
+		};
+		if self.offset != end { bail!("record data of {} does not match its length", name); }
+		return Ok(Record { name, ttl, cache_flush: class & CLASS_FLAG != 0, data });
+	}
+}
+
+// NOTE names are written uncompressed, the messages sent are small anyway
+fn write_name(output: &mut Vec<u8>, name: &Name) {
+	for label in &name.labels {
+		let label = truncate(label, 63);
+		output.push(label.len() as u8);
+		output.extend_from_slice(label.as_bytes());
+	}
+	output.push(0);
+}
+
+fn write_record(output: &mut Vec<u8>, record: &Record) {
+	write_name(output, &record.name);
+	output.extend_from_slice(&record.data.kind().to_be_bytes());
+	let class = if record.cache_flush { CLASS_IN | CLASS_FLAG } else { CLASS_IN };
+	output.extend_from_slice(&class.to_be_bytes());
+	output.extend_from_slice(&record.ttl.to_be_bytes());
+
+	let mut data = Vec::new();
+	match &record.data {
+		RecordData::A(address) => data.extend_from_slice(&address.octets()),
+		RecordData::Ptr(name) => write_name(&mut data, name),
+		RecordData::Txt(strings) => {
+			for string in strings {
+				let string = truncate(string, 255);
+				data.push(string.len() as u8);
+				data.extend_from_slice(string.as_bytes());
+			}
+			// NOTE a txt record holds at least one (empty) string
+			if strings.is_empty() { data.push(0); }
+		},
+		RecordData::Srv { priority, weight, port, target } => {
+			for value in [priority, weight, port] { data.extend_from_slice(&value.to_be_bytes()); }
+			write_name(&mut data, target);
+		},
+		RecordData::Other(_kind, bytes) => data.extend_from_slice(bytes),
+	}
+	output.extend_from_slice(&(data.len() as u16).to_be_bytes());
+	output.extend_from_slice(&data);
+}
+
+impl Message {
+	pub fn parse(datagram: &[u8]) -> Result<Self> {
+		let mut reader = Reader { message: datagram, offset: 0 };
+		let id = reader.u16()?;
+		let flags = reader.u16()?;
+		let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+		let mut message = Self { id, is_response: flags & FLAG_RESPONSE != 0, ..Default::default() };
+		for _ in 0..counts[0] { message.questions.push(reader.question()?); }
+		for _ in 0..counts[1] { message.answers.push(reader.record()?); }
+		for _ in 0..counts[2] + counts[3] { message.additional.push(reader.record()?); }
+		return Ok(message);
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut output = Vec::with_capacity(512);
+		output.extend_from_slice(&self.id.to_be_bytes());
+		let flags = if self.is_response { FLAG_RESPONSE | FLAG_AUTHORITATIVE } else { 0 };
+		output.extend_from_slice(&flags.to_be_bytes());
+		for count in [self.questions.len(), self.answers.len(), 0, self.additional.len()] {
+			output.extend_from_slice(&(count as u16).to_be_bytes());
+		}
+
+		for question in &self.questions {
+			write_name(&mut output, &question.name);
+			output.extend_from_slice(&question.kind.to_be_bytes());
+			let class = if question.unicast { CLASS_IN | CLASS_FLAG } else { CLASS_IN };
+			output.extend_from_slice(&class.to_be_bytes());
+		}
+		for record in self.answers.iter().chain(&self.additional) { write_record(&mut output, record); }
+		return output;
+	}
+
+	fn records(&self) -> impl Iterator<Item = &Record> {
+		return self.answers.iter().chain(&self.additional);
+	}
+}
+
+// this instance as a DNS-SD service, under both service types
+#[derive(Clone, Debug)]
+pub struct Service {
+	// NOTE `<name> (<port>)`, the port keeps instances on one host apart
+	pub instance: String,
+	// NOTE reachable as <host>.local
+	pub host: String,
+	pub address: Ipv4Addr,
+	pub port: u16,
+	pub txt: Vec<String>,
+}
+
+impl Service {
+	pub fn new(name: &str, host_name: &str, address: Ipv4Addr, port: u16) -> Self {
+		// NOTE only the first label of a fully qualified host name, and only
+		// the characters a host name may hold
+		let host = host_name.split('.').next().unwrap_or_default().chars()
+			.map(|chr| if chr.is_ascii_alphanumeric() || chr == '-' { chr } else { '-' })
+			.collect::<String>();
+		return Self {
+			instance: format!("{} ({})", name, port),
+			host: if host == "" { "localshare".to_owned() } else { host },
+			address, port,
+			txt: vec!["txtvers=1".to_owned(), "path=/".to_owned(), format!("name={}", name)],
+		};
+	}
+
+	pub fn host_name(&self) -> Name {
+		return Name::parse("local").with_label(&self.host);
+	}
+
+	pub fn instance_name(&self, service_type: &str) -> Name {
+		return Name::parse(service_type).with_label(&self.instance);
+	}
+
+	fn host_record(&self) -> Record {
+		return Record { name: self.host_name(), ttl: HOST_TTL, cache_flush: true, data: RecordData::A(self.address) };
+	}
+
+	fn instance_records(&self, service_type: &str) -> [Record; 2] {
+		let name = self.instance_name(service_type);
+		return [
+			Record {
+				name: name.clone(), ttl: HOST_TTL, cache_flush: true,
+				data: RecordData::Srv { priority: 0, weight: 0, port: self.port, target: self.host_name() },
+			},
+			Record { name, ttl: SERVICE_TTL, cache_flush: true, data: RecordData::Txt(self.txt.clone()) },
+		];
+	}
+
+	fn pointer_record(&self, service_type: &str) -> Record {
+		return Record {
+			name: Name::parse(service_type), ttl: SERVICE_TTL, cache_flush: false,
+			data: RecordData::Ptr(self.instance_name(service_type)),
+		};
+	}
+
+	// the records answering question and the additional records that save
+	// the asker the follow up questions, both empty when it is not about this
+	pub fn answer(&self, question: &Question) -> (Vec<Record>, Vec<Record>) {
+		let (mut answers, mut additional) = (Vec::new(), Vec::new());
+		let wants = |kind: u16| question.kind == kind || question.kind == TYPE_ANY;
+
+		if question.name == Name::parse(SERVICES_META_QUERY) && wants(TYPE_PTR) {
+			for service_type in [LOCALSHARE_SERVICE, HTTP_SERVICE] {
+				answers.push(Record {
+					name: question.name.clone(), ttl: SERVICE_TTL, cache_flush: false,
+					data: RecordData::Ptr(Name::parse(service_type)),
+				});
+			}
+		}
+		for service_type in [LOCALSHARE_SERVICE, HTTP_SERVICE] {
+			if question.name == Name::parse(service_type) && wants(TYPE_PTR) {
+				answers.push(self.pointer_record(service_type));
+				additional.extend(self.instance_records(service_type));
+				additional.push(self.host_record());
+			}
+			if question.name == self.instance_name(service_type) {
+				for record in self.instance_records(service_type) {
+					if wants(record.data.kind()) { answers.push(record); }
+				}
+				if wants(TYPE_SRV) { additional.push(self.host_record()); }
+			}
+		}
+		if question.name == self.host_name() && wants(TYPE_A) {
+			answers.push(self.host_record());
+		}
+		return (answers, additional);
+	}
+
+	// every record of this service, ttl 0 ones telling the others to forget
+	// it when goodbye
+	pub fn announcement(&self, goodbye: bool) -> Message {
+		let mut answers = Vec::new();
+		for service_type in [LOCALSHARE_SERVICE, HTTP_SERVICE] {
+			answers.push(self.pointer_record(service_type));
+			answers.extend(self.instance_records(service_type));
+		}
+		answers.push(self.host_record());
+		if goodbye { answers.iter_mut().for_each(|record| record.ttl = 0); }
+		return Message { id: 0, is_response: true, answers, ..Default::default() };
+	}
+}
+
+// the address the others should connect to, the one a datagram to the group
+// would leave from unless the server is bound to a single one
+fn local_address(bind_address: IpAddr) -> Ipv4Addr {
+	if let IpAddr::V4(address) = bind_address {
+		if !address.is_unspecified() { return address; }
+	}
+	// NOTE connecting a udp socket sends nothing, it only picks the interface
+	let connected = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+		.and_then(|socket| { socket.connect(MDNS_GROUP)?; return socket.local_addr(); });
+	return match connected {
+		Ok(SocketAddr::V4(addr)) if !addr.ip().is_unspecified() => *addr.ip(),
+		_ => Ipv4Addr::LOCALHOST,
+	};
+}
+
+// another localshare instance found by browsing, a ttl of 0 is its goodbye
+#[derive(Clone, Debug, PartialEq)]
+pub struct Found {
+	pub addr: SocketAddr,
+	pub name: String,
+	pub ttl: u32,
+}
+
+// a socket joined to the mDNS group, answering questions about this service
+// and browsing for the other instances
+pub struct Mdns {
+	socket: UdpSocket,
+	group: SocketAddrV4,
+	service: Service,
+}
+
+impl Mdns {
+	pub fn new(group: SocketAddrV4, service: Service) -> Result<Self> {
+		// NOTE shared with other responders on this machine (avahi, other instances)
+		let socket = crate::discovery::bind_shared(group.port())
+			.map_err(|e| anyhow!("unable to bind the mDNS port {} -> {}", group.port(), e))?;
+		socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)
+			.map_err(|e| anyhow!("unable to join the mDNS group {} -> {}", group.ip(), e))?;
+		socket.set_multicast_loop_v4(true)?;
+		socket.set_multicast_ttl_v4(255)?;
+		return Ok(Self { socket, group, service });
+	}
+
+	pub fn announce(&self, goodbye: bool) -> Result<()> {
+		self.socket.send_to(&self.service.announcement(goodbye).to_bytes(), self.group)?;
+		return Ok(());
+	}
+
+	pub fn browse(&self) -> Result<()> {
+		let question = Question { name: Name::parse(LOCALSHARE_SERVICE), kind: TYPE_PTR, unicast: false };
+		let query = Message { questions: vec![question], ..Default::default() };
+		self.socket.send_to(&query.to_bytes(), self.group)?;
+		return Ok(());
+	}
+
+	// waits up to timeout for a message, answering it when it is a question
+	// about this service, returning the instances it is an answer about
+	pub fn receive(&self, timeout: std::time::Duration) -> Result<Vec<Found>> {
+		self.socket.set_read_timeout(Some(timeout.max(std::time::Duration::from_millis(1))))?;
+		let mut datagram = [0u8; MAX_MESSAGE_SIZE];
+		let (size, source) = match self.socket.recv_from(&mut datagram) {
+			Ok(pair) => pair,
+			Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => return Ok(vec![]),
+			Err(e) => return Err(e.into()),
+		};
+
+		// NOTE other responders send all sorts, a message this one does not
+		// understand is none of its business
+		let message = match Message::parse(&datagram[..size]) {
+			Ok(message) => message,
+			Err(_) => return Ok(vec![]),
+		};
+		if !message.is_response {
+			// NOTE one answer that could not be sent is no reason to stop answering
+			if let Err(e) = self.respond(&message, source) {
+				println!("\rWARN: failed to answer an mDNS query from {} -> {}", source, e);
+			}
+			return Ok(vec![]);
+		}
+		// NOTE RFC 6762 section 6, responses must come from the mDNS port
+		if source.port() != self.group.port() { return Ok(vec![]); }
+		return Ok(found_instances(&message, source, &self.service.instance_name(LOCALSHARE_SERVICE)));
+	}
+
+	fn respond(&self, query: &Message, source: SocketAddr) -> Result<()> {
+		let (mut answers, mut additional) = (Vec::<Record>::new(), Vec::<Record>::new());
+		let mut unicast = false;
+		for question in &query.questions {
+			let (question_answers, question_additional) = self.service.answer(question);
+			if !question_answers.is_empty() { unicast |= question.unicast; }
+			for record in question_answers {
+				if !answers.contains(&record) { answers.push(record); }
+			}
+			for record in question_additional {
+				if !additional.contains(&record) { additional.push(record); }
+			}
+		}
+		if answers.is_empty() { return Ok(()); }
+		additional.retain(|record| !answers.contains(record));
+
+		// NOTE a query from another port is a plain dns resolver, which wants
+		// its id and questions back and must not cache for long
+		if source.port() != self.group.port() {
+			for record in answers.iter_mut().chain(additional.iter_mut()) {
+				record.ttl = record.ttl.min(LEGACY_TTL);
+				record.cache_flush = false;
+			}
+			let response = Message { id: query.id, is_response: true, questions: query.questions.clone(), answers, additional };
+			self.socket.send_to(&response.to_bytes(), source)?;
+			return Ok(());
+		}
+
+		let response = Message { id: 0, is_response: true, questions: vec![], answers, additional };
+		let destination = if unicast { source } else { SocketAddr::V4(self.group) };
+		self.socket.send_to(&response.to_bytes(), destination)?;
+		return Ok(());
+	}
+}
+
+// the other localshare instances message answers about. NOTE anyone on the
+// network can send a response, so only instances at the address the message
+// came from are taken, never a loopback or multicast one
+fn found_instances(message: &Message, source: SocketAddr, own_instance: &Name) -> Vec<Found> {
+	let source_ip = source.ip().to_canonical();
+	let usable_source = match source_ip {
+		IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast()),
+		IpAddr::V6(_) => false,
+	};
+	if !usable_source { return vec![]; }
+
+	let mut found = Vec::new();
+	for record in message.records() {
+		let RecordData::Ptr(instance) = &record.data else { continue; };
+		if record.name != Name::parse(LOCALSHARE_SERVICE) || instance == own_instance { continue; }
+
+		// NOTE responders send the srv, txt and a records along with the
+		// pointer, an instance without them is left for its next answer
+		let Some((port, target, srv_ttl)) = message.records().find_map(|record| match &record.data {
+			RecordData::Srv { port, target, .. } if record.name == *instance => Some((*port, target, record.ttl)),
+			_ => None,
+		}) else { continue; };
+		let claimed_address = message.records().find_map(|record| match record.data {
+			RecordData::A(address) if record.name == *target => Some(IpAddr::V4(address)),
+			_ => None,
+		});
+		if claimed_address.is_some_and(|address| address != source_ip) { continue; }
+		let name = message.records().find_map(|record| match &record.data {
+			RecordData::Txt(strings) if record.name == *instance => strings.iter()
+				.find_map(|string| string.strip_prefix("name=").map(str::to_owned)),
+			_ => None,
+		}).or(instance.first_label().map(str::to_owned)).unwrap_or_default();
+
+		let ttl = record.ttl.min(srv_ttl).min(MAX_PEER_TTL);
+		found.push(Found { addr: SocketAddr::new(source_ip, port), name, ttl });
+	}
+	return found;
+}
+
+fn this_service() -> Service {
+	let address = local_address(GLOBALS.config.bind_address);
+	return Service::new(&GLOBALS.config.instance_name(), &crate::config::host_name(), address, GLOBALS.config.port);
+}
+
+// tells the others to forget this instance, rather than keep it until its
+// records run out
+pub fn say_goodbye() -> Result<()> {
+	let socket = crate::discovery::bind_shared(MDNS_GROUP.port())?;
+	socket.set_multicast_ttl_v4(255)?;
+	socket.send_to(&this_service().announcement(true).to_bytes(), MDNS_GROUP)?;
+	return Ok(());
+}
+
+// advertises this instance over mDNS and keeps the instances it browses in
+// GLOBALS, for as long as their records live
+pub fn run_mdns() -> Result<()> {
+	let service = this_service();
+	let mdns = Mdns::new(MDNS_GROUP, service.clone())?;
+	println!("\rINFO: advertising {} as http://{}.local:{}/ over mDNS", service.instance, service.host, service.port);
+
+	// NOTE announced and browsed twice a second apart at startup, as RFC 6762
+	// section 8.3 asks, then browsed every BROWSE_INTERVAL while the records
+	// the others answer with outlive that
+	let mut startup_rounds = 2u32;
+	let mut next_browse = std::time::Instant::now();
+	loop {
+		let now = std::time::Instant::now();
+		if now >= next_browse {
+			if startup_rounds > 0 {
+				if let Err(e) = mdns.announce(false) { println!("\rWARN: failed to announce this instance over mDNS -> {}", e); }
+			}
+			if let Err(e) = mdns.browse() { println!("\rWARN: failed to browse for peers over mDNS -> {}", e); }
+			next_browse = now + if startup_rounds > 0 { std::time::Duration::from_secs(1) } else { BROWSE_INTERVAL };
+			startup_rounds = startup_rounds.saturating_sub(1);
+		}
+		for peer in GLOBALS.expire_peers(now) {
+			println!("\rINFO: peer {} ({}) went quiet", peer.addr, peer.name.unwrap_or_default());
+		}
+
+		let timeout = next_browse.saturating_duration_since(now).min(std::time::Duration::from_secs(1));
+		for found in mdns.receive(timeout)? {
+			// NOTE a goodbye only forgets peers that were discovered
+			if found.ttl == 0 {
+				if let Some(peer) = GLOBALS.forget_discovered_peer(found.addr) {
+					println!("\rINFO: peer {} ({}) said goodbye", peer.addr, peer.name.unwrap_or_default());
+				}
+				continue;
+			}
+			if GLOBALS.saw_peer(found.addr, &found.name, std::time::Duration::from_secs(found.ttl as u64)) {
+				println!("\rINFO: found peer {} at {} over mDNS", found.name, found.addr);
+			}
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use super::{Message, Mdns, Name, Question, Record, RecordData, Service};
+
+	#[test]
+	fn test_message() {
+		let service = Service::new("den pc", "den-pc.example.org", Ipv4Addr::new(192, 0, 2, 7), 8000);
+		assert_eq!(service.instance, "den pc (8000)");
+		assert_eq!(service.host_name().to_string(), "den-pc.local");
+
+		let mut message = service.announcement(false);
+		message.questions.push(Question { name: Name::parse("_http._tcp.local"), kind: super::TYPE_PTR, unicast: true });
+		message.additional.push(Record {
+			name: Name::parse("other.local"), ttl: 5, cache_flush: false, data: RecordData::Other(28, vec![0; 16]),
+		});
+		message.additional.push(Record {
+			name: Name::parse("empty.local"), ttl: 5, cache_flush: false, data: RecordData::Txt(vec![]),
+		});
+		assert_eq!(Message::parse(&message.to_bytes()).unwrap(), message);
+
+		// NOTE a response to `A? host.local` with the answer name compressed
+		// into a pointer to the question name at byte 12
+		let datagram = b"\x12\x34\x84\x00\x00\x01\x00\x01\x00\x00\x00\x00\
+			\x04HOST\x05local\x00\x00\x01\x00\x01\
+			\xc0\x0c\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\x00\x02\x09";
+		let message = Message::parse(datagram).unwrap();
+		assert_eq!(message.id, 0x1234);
+		assert!(message.is_response);
+		assert_eq!(message.answers, [Record {
+			name: Name::parse("host.local"), ttl: 120, cache_flush: true, data: RecordData::A(Ipv4Addr::new(192, 0, 2, 9)),
+		}]);
+
+		// NOTE a pointer to itself, and a message cut short
+		assert!(Message::parse(b"\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\xc0\x0c\x00\x01\x00\x01").is_err());
+		assert!(Message::parse(&datagram[..datagram.len() - 2]).is_err());
+	}
+
+	#[test]
+	fn test_service_answers() {
+		let service = Service::new("den", "den", Ipv4Addr::new(192, 0, 2, 7), 8000);
+		let question = |name: &str, kind: u16| Question { name: Name::parse(name), kind, unicast: false };
+
+		let (answers, additional) = service.answer(&question("_HTTP._tcp.local", super::TYPE_PTR));
+		assert_eq!(answers, [service.pointer_record(super::HTTP_SERVICE)]);
+		assert_eq!(additional.len(), 3);
+
+		let (answers, _additional) = service.answer(&question("_services._dns-sd._udp.local", super::TYPE_PTR));
+		assert_eq!(answers.len(), 2);
+
+		let (answers, additional) = service.answer(&Question {
+			name: service.instance_name(super::LOCALSHARE_SERVICE), kind: super::TYPE_ANY, unicast: false,
+		});
+		assert_eq!(answers.len(), 2);
+		assert_eq!(additional, [service.host_record()]);
+
+		assert_eq!(service.answer(&question("den.local", super::TYPE_A)).0, [service.host_record()]);
+		assert!(service.answer(&question("den.local", super::TYPE_TXT)).0.is_empty());
+		assert!(service.answer(&question("_ipp._tcp.local", super::TYPE_PTR)).0.is_empty());
+	}
+
+	#[test]
+	fn test_found_instances() {
+		let service = Service::new("den", "den", Ipv4Addr::new(192, 0, 2, 7), 8000);
+		let own = Service::new("me", "me", Ipv4Addr::new(192, 0, 2, 1), 8000).instance_name(super::LOCALSHARE_SERVICE);
+		let source = std::net::SocketAddr::from((Ipv4Addr::new(192, 0, 2, 7), 5353));
+		let found = |message: &Message, source| super::found_instances(message, source, &own);
+
+		let mut message = service.announcement(false);
+		assert_eq!(found(&message, source), [super::Found { addr: "192.0.2.7:8000".parse().unwrap(), name: "den".to_owned(), ttl: super::HOST_TTL }]);
+
+		// NOTE an a record naming another host than the sender is not believed
+		assert!(found(&message, std::net::SocketAddr::from((Ipv4Addr::new(192, 0, 2, 8), 5353))).is_empty());
+		assert!(found(&message, std::net::SocketAddr::from((Ipv4Addr::LOCALHOST, 5353))).is_empty());
+
+		// NOTE without an a record the sender is used, and ttls are capped
+		message.answers.retain(|record| record.data.kind() != super::TYPE_A);
+		message.answers.iter_mut().for_each(|record| record.ttl = u32::MAX);
+		let instances = found(&message, std::net::SocketAddr::from((Ipv4Addr::new(192, 0, 2, 9), 5353)));
+		assert_eq!(instances[0].addr, "192.0.2.9:8000".parse().unwrap());
+		assert_eq!(instances[0].ttl, super::MAX_PEER_TTL);
+
+		assert!(super::found_instances(&service.announcement(false), source, &service.instance_name(super::LOCALSHARE_SERVICE)).is_empty());
+	}
+
+	// NOTE two responders on one machine browsing each other, and a plain dns
+	// resolver asking one of them
+	#[test]
+	fn test_mdns_loopback() {
+		let port = 20000 + (std::process::id() % 20000) as u16;
+		let group = std::net::SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), port);
+		// NOTE the address answers come from, which the a records must match
+		let address = super::local_address(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+		let first = Mdns::new(group, Service::new("first", "first-host", address, 8001)).unwrap();
+		let second = Mdns::new(group, Service::new("second", "second-host", address, 8002)).unwrap();
+
+		let timeout = std::time::Duration::from_millis(200);
+		let find = |mdns: &Mdns, port: u16| {
+			let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+			while std::time::Instant::now() < deadline {
+				if let Some(found) = mdns.receive(timeout).unwrap().into_iter().find(|found| found.addr.port() == port) {
+					return Some(found);
+				}
+			}
+			return None;
+		};
+
+		// NOTE first answers the query it receives, second hears the answer
+		second.browse().unwrap();
+		assert!(first.receive(timeout).unwrap().is_empty());
+		let found = find(&second, 8001).expect("second did not find first");
+		assert_eq!(found.addr, std::net::SocketAddr::new(address.into(), 8001));
+		assert_eq!(found.name, "first");
+		assert_eq!(found.ttl, super::HOST_TTL);
+
+		first.announce(true).unwrap();
+		assert_eq!(find(&second, 8001).expect("second did not hear the goodbye").ttl, 0);
+
+		let resolver = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+		resolver.set_nonblocking(true).unwrap();
+		let query = Message {
+			id: 77, questions: vec![Question { name: Name::parse("second-host.local"), kind: super::TYPE_A, unicast: false }],
+			..Default::default()
+		};
+		resolver.send_to(&query.to_bytes(), group).unwrap();
+		let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+		while std::time::Instant::now() < deadline {
+			second.receive(timeout).unwrap();
+			let mut datagram = [0u8; 512];
+			if let Ok(size) = resolver.recv(&mut datagram) {
+				let response = Message::parse(&datagram[..size]).unwrap();
+				assert_eq!(response.id, 77);
+				assert_eq!(response.questions, query.questions);
+				assert_eq!(response.answers, [Record {
+					name: Name::parse("second-host.local"), ttl: super::LEGACY_TTL, cache_flush: false,
+					data: RecordData::A(address),
+				}]);
+				return;
+			}
+		}
+		panic!("the resolver got no answer");
+	}
+}
@@ -0,0 +1,196 @@
+
+use crate::http::ContentType;
+
+
+// NOTE how much of a file is read to sniff its type, enough to reach the
+// tar header at 257
+pub const SNIFF_LENGTH: usize = 512;
+
+const EXTENSIONS: &[(&str, &str)] = &[
+	// audio
+	("flac", "audio/flac"), ("mp3", "audio/mpeg"), ("ogg", "audio/ogg"), ("oga", "audio/ogg"),
+	("opus", "audio/ogg"), ("wav", "audio/wav"), ("m4a", "audio/mp4"), ("aac", "audio/aac"),
+	("weba", "audio/webm"), ("mid", "audio/midi"), ("midi", "audio/midi"), ("aif", "audio/aiff"),
+	("aiff", "audio/aiff"), ("wma", "audio/x-ms-wma"),
+	// video
+	("mp4", "video/mp4"), ("m4v", "video/mp4"), ("webm", "video/webm"), ("mkv", "video/x-matroska"),
+	("mov", "video/quicktime"), ("avi", "video/x-msvideo"), ("ogv", "video/ogg"), ("mpg", "video/mpeg"),
+	("mpeg", "video/mpeg"), ("3gp", "video/3gpp"), ("wmv", "video/x-ms-wmv"),
+	// images
+	("png", "image/png"), ("jpg", "image/jpeg"), ("jpeg", "image/jpeg"), ("gif", "image/gif"),
+	("webp", "image/webp"), ("avif", "image/avif"), ("heic", "image/heic"), ("svg", "image/svg+xml"),
+	("ico", "image/x-icon"), ("bmp", "image/bmp"), ("tif", "image/tiff"), ("tiff", "image/tiff"),
+	// documents
+	("pdf", "application/pdf"), ("epub", "application/epub+zip"), ("rtf", "application/rtf"),
+	("doc", "application/msword"), ("xls", "application/vnd.ms-excel"), ("ppt", "application/vnd.ms-powerpoint"),
+	("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+	("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+	("pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+	("odt", "application/vnd.oasis.opendocument.text"), ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+	("odp", "application/vnd.oasis.opendocument.presentation"),
+	("txt", "text/plain"), ("log", "text/plain"), ("csv", "text/csv"), ("tsv", "text/tab-separated-values"),
+	("md", "text/markdown"), ("html", "text/html"), ("htm", "text/html"), ("css", "text/css"),
+	("xml", "application/xml"), ("json", "application/json"),
+	// archives
+	("zip", "application/zip"), ("gz", "application/gzip"), ("tgz", "application/gzip"),
+	("tar", "application/x-tar"), ("bz2", "application/x-bzip2"), ("xz", "application/x-xz"),
+	("zst", "application/zstd"), ("7z", "application/x-7z-compressed"), ("rar", "application/vnd.rar"),
+	("iso", "application/x-iso9660-image"), ("deb", "application/vnd.debian.binary-package"),
+	("jar", "application/java-archive"), ("apk", "application/vnd.android.package-archive"),
+	// source code and config, served as text so they open in a browser
+	// NOTE .ts is left to sniffing, it is either typescript or mpeg-ts video
+	("rs", "text/x-rust"), ("c", "text/x-c"), ("h", "text/x-c"), ("cpp", "text/x-c++"), ("cc", "text/x-c++"),
+	("hpp", "text/x-c++"), ("py", "text/x-python"), ("js", "text/javascript"), ("mjs", "text/javascript"),
+	("go", "text/x-go"), ("java", "text/x-java"), ("kt", "text/x-kotlin"),
+	("rb", "text/x-ruby"), ("lua", "text/x-lua"), ("sh", "text/x-shellscript"), ("zig", "text/x-zig"),
+	("toml", "application/toml"), ("yaml", "application/yaml"), ("yml", "application/yaml"),
+	("ini", "text/plain"), ("conf", "text/plain"), ("diff", "text/x-diff"), ("patch", "text/x-diff"),
+	// fonts and others
+	("woff", "font/woff"), ("woff2", "font/woff2"), ("ttf", "font/ttf"), ("otf", "font/otf"),
+	("wasm", "application/wasm"),
+];
+
+// the type named by a file's extension, matched case insensitively
+pub fn from_extension(name: &str) -> Option<&'static str> {
+	let (_stem, extension) = name.rsplit_once('.')?;
+	if extension.contains('/') { return None; }
+	return EXTENSIONS.iter()
+		.find(|(known, _mime)| known.eq_ignore_ascii_case(extension))
+		.map(|(_extension, mime)| *mime);
+}
+
+// the type announced by the first bytes of a file
+pub fn from_magic(head: &[u8]) -> Option<&'static str> {
+	let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+	let contains = |magic: &[u8]| head.windows(magic.len()).any(|window| window == magic);
+
+	let mime = match head {
+		_ if at(0, b"fLaC") => "audio/flac",
+		_ if at(0, b"ID3") => "audio/mpeg",
+		// NOTE an mpeg audio frame sync, aac in adts shares the first 12 bits
+		[0xff, second, ..] if second & 0xf6 == 0xf0 => "audio/aac",
+		[0xff, second, ..] if second & 0xe0 == 0xe0 && second & 0x06 != 0 => "audio/mpeg",
+		_ if at(0, b"OggS") && contains(b"\x80theora") => "video/ogg",
+		_ if at(0, b"OggS") => "audio/ogg",
+		_ if at(0, b"RIFF") && at(8, b"WAVE") => "audio/wav",
+		_ if at(0, b"RIFF") && at(8, b"AVI ") => "video/x-msvideo",
+		_ if at(0, b"RIFF") && at(8, b"WEBP") => "image/webp",
+		_ if at(0, b"FORM") && at(8, b"AIFF") => "audio/aiff",
+		_ if at(4, b"ftypM4A ") => "audio/mp4",
+		_ if at(4, b"ftypavif") => "image/avif",
+		_ if at(4, b"ftypheic") => "image/heic",
+		_ if at(4, b"ftypqt  ") => "video/quicktime",
+		_ if at(4, b"ftyp") => "video/mp4",
+		_ if at(0, b"\x1a\x45\xdf\xa3") && contains(b"webm") => "video/webm",
+		_ if at(0, b"\x1a\x45\xdf\xa3") => "video/x-matroska",
+		// NOTE mpeg-ts packets are 188 bytes, each starting with 0x47
+		_ if at(0, b"\x47") && at(188, b"\x47") => "video/mp2t",
+		_ if at(0, b"\x89PNG\r\n\x1a\n") => "image/png",
+		_ if at(0, b"\xff\xd8\xff") => "image/jpeg",
+		_ if at(0, b"GIF87a") || at(0, b"GIF89a") => "image/gif",
+		_ if at(0, b"II*\0") || at(0, b"MM\0*") => "image/tiff",
+		_ if at(0, b"\0\0\x01\0") => "image/x-icon",
+		_ if at(0, b"%PDF-") => "application/pdf",
+		_ if at(0, b"PK\x03\x04") && at(30, b"mimetypeapplication/epub+zip") => "application/epub+zip",
+		_ if at(0, b"PK\x03\x04") => "application/zip",
+		_ if at(0, b"\x1f\x8b") => "application/gzip",
+		_ if at(0, b"BZh") => "application/x-bzip2",
+		_ if at(0, b"\xfd7zXZ\0") => "application/x-xz",
+		_ if at(0, b"\x28\xb5\x2f\xfd") => "application/zstd",
+		_ if at(0, b"7z\xbc\xaf\x27\x1c") => "application/x-7z-compressed",
+		_ if at(0, b"Rar!\x1a\x07") => "application/vnd.rar",
+		_ if at(257, b"ustar") => "application/x-tar",
+		_ if at(0, b"\0asm") => "application/wasm",
+		_ if at(0, b"wOFF") => "font/woff",
+		_ if at(0, b"wOF2") => "font/woff2",
+		_ => return None,
+	};
+	return Some(mime);
+}
+
+// NOTE html is never sniffed, a page hosted under an unknown extension is
+// shown as text instead of running in this server's origin
+fn is_text(head: &[u8]) -> bool {
+	let text = match std::str::from_utf8(head) {
+		Ok(text) => text,
+		// NOTE the head may end in the middle of a character
+		Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap(),
+		Err(_) => return false,
+	};
+	return !text.chars().any(|chr| chr.is_control() && !"\t\n\r\x0c\x1b".contains(chr));
+}
+
+// whether a browser showing this type as a page would run scripts in it
+pub fn runs_script(mime: &str) -> bool {
+	return matches!(mime, "text/html" | "image/svg+xml" | "application/xml" | "application/xhtml+xml" | "text/xml");
+}
+
+// whether this type is shown as lines of text in a preview
+pub fn is_text_type(mime: &str) -> bool {
+	return mime.starts_with("text/")
+		|| matches!(mime, "application/json" | "application/xml" | "application/toml" | "application/yaml");
+}
+
+// the type of a file named name starting with head, by its extension, its
+// magic bytes or whether it reads as text, in that order
+pub fn detect(name: &str, head: &[u8]) -> ContentType {
+	if let Some(mime) = from_extension(name).or_else(|| from_magic(head)) {
+		return ContentType::other(mime);
+	}
+	if is_text(head) { return ContentType::text_plain; }
+	return ContentType::other("application/octet-stream");
+}
+
+// the type of the file a response is reading, falling back to the extension
+// when its start can not be read
+pub fn detect_content(reader: &crate::content::ContentReader) -> ContentType {
+	let name = reader.path().to_string_lossy();
+	let head = match reader.read_head(SNIFF_LENGTH) {
+		Ok(head) => head,
+		Err(e) => {
+			println!("\rWARN: unable to sniff the type of {} -> {}", name, e);
+			Vec::new()
+		}
+	};
+	return detect(&name, &head);
+}
+
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn test_detect() {
+		let detect = |name: &str, head: &[u8]| super::detect(name, head).as_str();
+
+		assert_eq!(detect("/music/Song.MP3", b""), "audio/mpeg");
+		assert_eq!(detect("track.opus", b"OggS"), "audio/ogg");
+		assert_eq!(detect("clip.mkv", b""), "video/x-matroska");
+		assert_eq!(detect("main.rs", b"fn main() {}"), "text/x-rust");
+		assert_eq!(detect("report.pdf", b""), "application/pdf");
+		// NOTE the extension wins over the content
+		assert_eq!(detect("notes.txt", b"%PDF-1.7"), "text/plain");
+
+		assert_eq!(detect("song", b"fLaC\0\0\0\x22"), "audio/flac");
+		assert_eq!(detect("song", b"\xff\xfb\x90\x64"), "audio/mpeg");
+		assert_eq!(detect("song", b"\xff\xf1\x50\x80"), "audio/aac");
+		assert_eq!(detect("movie", b"\0\0\0\x20ftypisom"), "video/mp4");
+		assert_eq!(detect("voice", b"\0\0\0\x20ftypM4A "), "audio/mp4");
+		assert_eq!(detect("picture", b"\x89PNG\r\n\x1a\n\0\0"), "image/png");
+		assert_eq!(detect("sound", b"RIFF\x24\0\0\0WAVEfmt "), "audio/wav");
+		assert_eq!(detect("archive", b"PK\x03\x04\x14\0"), "application/zip");
+		let mut tar = vec![0u8; 512];
+		tar[257..262].copy_from_slice(b"ustar");
+		assert_eq!(detect("backup", &tar), "application/x-tar");
+
+		assert_eq!(detect("README", b"plain text\n"), "text/plain");
+		assert_eq!(detect("page", b"<!DOCTYPE html><html>"), "text/plain");
+		assert_eq!(detect("cut", "日本".as_bytes().split_last().unwrap().1), "text/plain");
+		assert_eq!(detect("app.ts", b"let x: number = 1;"), "text/plain");
+		let mut stream = vec![0u8; 376];
+		stream[0] = 0x47;
+		stream[188] = 0x47;
+		assert_eq!(detect("video.ts", &stream), "video/mp2t");
+		assert_eq!(detect("blob", b"\0\x01\x02\x03"), "application/octet-stream");
+		assert_eq!(detect("dir.d/file", b"\0"), "application/octet-stream");
+	}
+}
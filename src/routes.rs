@@ -1,5 +1,5 @@
 
-use std::{io::{Read, Write}, str::FromStr};
+use std::io::{Read, Write};
 
 use anyhow::Result;
 
@@ -8,34 +8,108 @@ use crate::{
 	http::{
 		ClosureReader,
 		HttpHeader,
+		Escape,
+		TemplateReader,
+		TemplateValue,
 		ReadInto,
 		ContentType,
-		TransferEncoding,
 		ContentDisposition,
+		ContentRange,
+		ByteRange,
 	}
 };
 
 
+// NOTE headers of a peer response that are not passed on, the client
+// connection is framed and kept alive independently of the peer connection
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+	"Connection", "Keep-Alive", "Proxy-Connection", "Transfer-Encoding", "TE", "Trailer", "Upgrade", "Content-Length"
+];
+
+// NOTE failures a route answers with a status of their own, anything else
+// a route returns becomes a 500
+#[derive(Debug)]
+pub enum RouteError {
+	BadRequest(String),
+	Unauthorized(String),
+	Forbidden(String),
+	NotFound(String),
+	// NOTE holds the value of the Allow header
+	MethodNotAllowed(&'static str),
+	Conflict(String),
+	TooLarge(String),
+	Internal,
+	BadGateway(String),
+	GatewayTimeout(String),
+}
 
-fn return_routing_error(sink: &mut dyn Write, error_string: &str) {
-	let response = crate::http::HttpResponse{
-		protocol_version: "HTTP/1.1",
-		status_code: 200,
-		status_text: "OK",
-		headers: vec!(),
-		body: b""
+impl RouteError {
+	pub fn status(&self) -> (usize, &'static str) {
+		match self {
+			Self::BadRequest(_) => (400, "Bad Request"),
+			Self::Unauthorized(_) => (401, "Unauthorized"),
+			Self::Forbidden(_) => (403, "Forbidden"),
+			Self::NotFound(_) => (404, "Not Found"),
+			Self::MethodNotAllowed(_) => (405, "Method Not Allowed"),
+			Self::Conflict(_) => (409, "Conflict"),
+			Self::TooLarge(_) => (413, "Content Too Large"),
+			Self::Internal => (500, "Internal Server Error"),
+			Self::BadGateway(_) => (502, "Bad Gateway"),
+			Self::GatewayTimeout(_) => (504, "Gateway Timeout"),
+		}
+	}
+}
+
+impl std::fmt::Display for RouteError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::BadRequest(message) | Self::Unauthorized(message) | Self::Forbidden(message)
+				| Self::NotFound(message) | Self::Conflict(message) | Self::TooLarge(message)
+				| Self::BadGateway(message) | Self::GatewayTimeout(message) => write!(f, "{}", message),
+			Self::MethodNotAllowed(allowed) => write!(f, "this route only answers to {}", allowed),
+			// NOTE the cause is logged, not shown to the client
+			Self::Internal => write!(f, "the server failed to answer this request"),
+		}
+	}
+}
+
+impl std::error::Error for RouteError {}
+
+// answers with the error's status and a body in the format the client
+// asked for, json for scripts and html for browsers
+fn return_route_error(sink: &mut dyn Write, error: &RouteError, json: bool) -> Result<()> {
+	let (status_code, status_text) = error.status();
+	let message = error.to_string();
+	let (content_type, body) = if json {
+		let body = crate::json::JsonValue::Object(vec![
+			("error".to_owned(), crate::json::JsonValue::Object(vec![
+				("status".to_owned(), crate::json::JsonValue::from(status_code as u64)),
+				("message".to_owned(), crate::json::JsonValue::from(message)),
+			])),
+		]);
+		(ContentType::application_json, body.to_json_string(false))
+	}else {
+		(ContentType::text_html, format!(
+			"<!DOCTYPE html>\n<html><head><title>{0} {1}</title></head>\n<body><h2>{0} {1}</h2><p>{2}</p></body></html>\n",
+			status_code, status_text, Escape::Html.apply(&message)
+		))
 	};
-	
-	let mut sources: &mut [&mut dyn ReadInto] = &mut [
-		&mut b"<h2>Routing Error</h2><p>".as_slice(),
-		&mut error_string.as_bytes(),
-		&mut b"</p>".as_slice()
+
+	let mut headers = vec![
+		HttpHeader::ContentType(content_type),
+		HttpHeader::ContentLength(body.len()),
 	];
-	let sources: &mut dyn ReadInto = &mut sources;
-	response.write_from_readinto(sources, sink)
-		.expect("Failed to write routeing error response to writer");
+	if let RouteError::MethodNotAllowed(allowed) = error { headers.push(HttpHeader::Allow(allowed)); }
 
-	sink.flush().unwrap();
+	let response = crate::http::HttpResponse {
+		protocol_version: "HTTP/1.1",
+		status_code,
+		status_text,
+		headers,
+		body: body.as_bytes(),
+	};
+	response.write_to_sink(sink)?;
+	return Ok(());
 }
 
 fn serve_get_index(
@@ -55,139 +129,394 @@ fn serve_get_index(
 		body: b"",
 	};
 
-	let local_addr_string = format!("{:?}", client_local_addr);
-	let peer_addr_string = format!("{:?}", client_peer_addr);
-
-	let index_html_file = GLOBALS.get_static_file("index.html")
-		.ok_or(anyhow!("Failed to fetch index.html from globals"))?;
-	let mut template = crate::http::BodyTemplate {
-		template: index_html_file.as_ref(),
-		keys: &[b"peer_addr", b"local_addr", b"hosted_files", b"hosted_playlists"],
-		values: &mut [
-			&mut local_addr_string.as_bytes(),
-			&mut peer_addr_string.as_bytes(),
-			&mut iterator_reader!(
-				filename, GLOBALS.read_file_entries().filenames.iter(),
-				[ b"<a href=\"/file/", filename.as_bytes(), b"\">", filename.as_bytes(), b"</a><br />" ]
-			),
-			&mut iterator_reader!(
-				playlist_name, GLOBALS.read_playlists().iter().map(|playlist| playlist.name.as_ref()),
-				[
-					b"<a href=\"/playlist?playlist=", playlist_name.as_bytes(),
-					b"\">", playlist_name.as_bytes(), b"</a><br />"
-				]
-			),
-		],
-	};
+	let template = GLOBALS.get_template("index.html")?;
+	let values = [
+		("title", TemplateValue::from("LocalShare")),
+		("local_addr", format!("{:?}", client_local_addr).into()),
+		("peer_addr", format!("{:?}", client_peer_addr).into()),
+		("hosted_files", GLOBALS.read_file_entries().iter().map(|entry| entry.name.as_ref()).collect::<Vec<&str>>().into()),
+		("hosted_playlists", GLOBALS.read_playlists().iter().map(|playlist| playlist.name.as_ref()).collect::<Vec<&str>>().into()),
+		("shared_directories", GLOBALS.read_shared_directories().iter().map(|directory| directory.name.as_ref()).collect::<Vec<&str>>().into()),
+	];
 
-	response.write_from_readinto(&mut template, sink)?;
+	response.write_from_readinto(&mut TemplateReader{ template: &template, values: &values }, sink)?;
 	return Ok(());
 }
 
-fn return_not_found(sink: &mut dyn Write) -> Result<()> {
+fn serve_get_favicon(sink: &mut dyn Write) -> Result<()> {
+	let favicon = GLOBALS.get_static_file("favicon.ico")
+		.ok_or(RouteError::NotFound("no favicon".to_owned()))?;
 	let response = crate::http::HttpResponse {
 		protocol_version: "HTTP/1.1",
-		status_code: 404,
-		status_text: "Not Found",
+		status_code: 200,
+		status_text: "OK",
 		headers: vec![
-			crate::http::HttpHeader::ContentType(crate::http::ContentType::text_html)
+			crate::http::HttpHeader::ContentType(crate::http::ContentType::image_x_icon),
+			crate::http::HttpHeader::TransferEncoding(crate::http::TransferEncoding::binary),
+			crate::http::HttpHeader::ContentLength(favicon.len())
 		],
-		body: b"<body><h2>Route Undefined or Unavailable - 404</h2></body>",
+		body: favicon.as_ref(),
 	};
 
-	
 	response.write_to_sink(sink)?;
 
 	return Ok(());
 }
 
-fn serve_get_favicon(sink: &mut dyn Write) -> Result<()> {
+// NOTE serves content honoring the request's Range header, falling back
+// to the full content when the header is absent or malformed. the body is
+// streamed, a file shrinking midway ends the response with an error
+fn serve_ranged_content(
+	sink: &mut dyn Write,
+	request: &crate::http::HttpRequest,
+	content: &crate::content::ContentReader,
+	content_type: ContentType,
+	disposition: ContentDisposition,
+) -> Result<()> {
+	// NOTE inline pages and images that can run scripts get no access to
+	// this origin. other types are left unsandboxed, browsers refuse to
+	// render a sandboxed pdf
+	let policy = match disposition {
+		ContentDisposition::Inline if crate::mime::runs_script(content_type.as_str()) => vec![HttpHeader::ContentSecurityPolicy("sandbox")],
+		_ => vec![],
+	};
+
+	let ranges = match request.range_header().map(ByteRange::parse_ranges) {
+		Some(Ok(ranges)) => ranges,
+		Some(Err(e)) => {
+			println!("\rWARN: ignoring range header -> {e}");
+			vec!()
+		},
+		None => vec!()
+	};
+
+	if ranges.len() == 0 {
+		let response = crate::http::HttpResponse {
+			protocol_version: "HTTP/1.1",
+			status_code: 200,
+			status_text: "OK",
+			headers: [vec![
+				HttpHeader::ContentType(content_type),
+				HttpHeader::NoSniff,
+				HttpHeader::AcceptRanges,
+				HttpHeader::ContentLength(content.len()),
+				HttpHeader::ContentDisposition(disposition),
+			], policy].concat(),
+			body: b"",
+		};
+		response.write_from_readinto(
+			&mut ClosureReader{ source: &|dest| {
+				if content.len() > 0 { content.write_range(dest, 0, content.len() - 1)?; }
+				return Ok(content.len());
+			}},
+			sink
+		)?;
+		return Ok(());
+	}
+
+	let resolved = ranges.into_iter()
+		.filter_map(|range| range.resolve(content.len()))
+		.collect::<Vec<(usize, usize)>>();
+
+	if resolved.len() == 0 {
+		let response = crate::http::HttpResponse {
+			protocol_version: "HTTP/1.1",
+			status_code: 416,
+			status_text: "Range Not Satisfiable",
+			headers: vec![
+				HttpHeader::AcceptRanges,
+				HttpHeader::ContentRange(ContentRange::Unsatisfied(content.len())),
+				HttpHeader::ContentLength(0),
+			],
+			body: b"",
+		};
+		response.write_to_sink(sink)?;
+		return Ok(());
+	}
+
+	if resolved.len() == 1 {
+		let (start, end) = resolved[0];
+		let response = crate::http::HttpResponse {
+			protocol_version: "HTTP/1.1",
+			status_code: 206,
+			status_text: "Partial Content",
+			headers: [vec![
+				HttpHeader::ContentType(content_type),
+				HttpHeader::NoSniff,
+				HttpHeader::AcceptRanges,
+				HttpHeader::ContentRange(ContentRange::Bytes{ start, end, total: content.len() }),
+				HttpHeader::ContentLength(end - start + 1),
+				HttpHeader::ContentDisposition(disposition),
+			], policy].concat(),
+			body: b"",
+		};
+		response.write_from_readinto(
+			&mut ClosureReader{ source: &|dest| {
+				content.write_range(dest, start, end)?;
+				return Ok(end - start + 1);
+			}},
+			sink
+		)?;
+		return Ok(());
+	}
+
+	// multiple ranges are sent as multipart/byteranges, the part headers are
+	// rendered up front so that the Content-Length can be computed
+	let mut part_heads = Vec::<Vec<u8>>::with_capacity(resolved.len());
+	for (start, end) in resolved.iter().copied() {
+		let mut part_head = Vec::<u8>::new();
+		write!(part_head, "\r\n--{}\r\n", crate::http::BYTERANGES_BOUNDARY)?;
+		HttpHeader::ContentType(content_type).read_into(&mut part_head)?;
+		part_head.write(b"\r\n")?;
+		HttpHeader::ContentRange(ContentRange::Bytes{ start, end, total: content.len() })
+			.read_into(&mut part_head)?;
+		part_head.write(b"\r\n\r\n")?;
+		part_heads.push(part_head);
+	}
+	let closing = format!("\r\n--{}--\r\n", crate::http::BYTERANGES_BOUNDARY);
+
+	let body_length = closing.len() + resolved.iter().zip(part_heads.iter())
+		.map(|((start, end), head)| head.len() + end - start + 1)
+		.sum::<usize>();
+
 	let response = crate::http::HttpResponse {
 		protocol_version: "HTTP/1.1",
-		status_code: 200,
-		status_text: "OK",
-		headers: vec![
-			crate::http::HttpHeader::ContentType(crate::http::ContentType::image_x_icon),
-			crate::http::HttpHeader::TransferEncoding(crate::http::TransferEncoding::binary),
-			crate::http::HttpHeader::ContentLength(GLOBALS.favicon.len())
-		],
-		body: GLOBALS.favicon.as_ref(),
+		status_code: 206,
+		status_text: "Partial Content",
+		headers: [vec![
+			HttpHeader::ContentType(ContentType::multipart_byteranges),
+			HttpHeader::NoSniff,
+			HttpHeader::AcceptRanges,
+			HttpHeader::ContentLength(body_length),
+			HttpHeader::ContentDisposition(disposition),
+		], policy].concat(),
+		body: b"",
 	};
 
-	response.write_to_sink(sink)?;
+	response.write_from_readinto(
+		&mut ClosureReader{ source: &|dest| {
+			let mut write_size = 0;
+			for ((start, end), head) in resolved.iter().zip(part_heads.iter()) {
+				write_size += dest.write(head.as_slice())?;
+				content.write_range(dest, *start, *end)?;
+				write_size += end - start + 1;
+			}
+			write_size += dest.write(closing.as_bytes())?;
+			return Ok(write_size);
+		}},
+		sink
+	)?;
 
 	return Ok(());
 }
 
 fn serve_get_file(sink: &mut dyn Write, request: &crate::http::HttpRequest) -> Result<()> {
-	// let filepath = &uri_path[6..];
-	// let (_, filepath) = match request.route.split_once('/') {
-	// 	Some(pair) => pair,
-	// 	None => {
-	// 		return_routing_error(sink, "no file path in route");
-	// 		return Ok(());
-	// 	}
-	// };
-	let filepath = &request.route["/file/".len()..];
-
-	if request.query_params == "" {
+	// NOTE a bare /file names no file
+	let filepath = match request.route.strip_prefix("/file/").map(crate::url::percent_decode) {
+		Some(Some(filepath)) => filepath,
+		Some(None) => bail!(RouteError::BadRequest("malformed percent encoding in the file path".to_owned())),
+		None => bail!(RouteError::NotFound(format!("{} does not name a file", request.route))),
+	};
+	let filepath = filepath.as_str();
+	let query = crate::url::Query::parse(request.query_params)
+		.map_err(|e| RouteError::BadRequest(e.to_string()))?;
+
+	let filename = filepath.split('/').rev().next().unwrap_or(filepath);
+	let disposition = match query.get("disposition") {
+		None | Some("attachment") => ContentDisposition::Attachment(Some(filename.into())),
+		Some("inline") => ContentDisposition::Inline,
+		Some(other) => bail!(RouteError::BadRequest(format!("disposition should be inline or attachment, not {}", other))),
+	};
+
+	if let None = query.get("source") {
 		let result = GLOBALS.get_file_entry_by_name(filepath);
 		if let Some(file) = result {
-			let response = crate::http::HttpResponse {
-				protocol_version: "HTTP/1.1",
-				status_code: 200,
-				status_text: "OK",
-				headers: vec![
-					HttpHeader::ContentType(crate::http::ContentType::text_plain),
-					HttpHeader::TransferEncoding(crate::http::TransferEncoding::binary),
-					HttpHeader::ContentLength(file.len()),
-					HttpHeader::ContentDisposition(ContentDisposition::Attachment(Some(
-						filepath.split('/').rev().next().unwrap_or(filepath)
-					)))
-				],
-				body: file.as_ref(),
+			let reader = match file.reader() {
+				Ok(reader) => reader,
+				Err(e) => {
+					println!("\rWARN: {}", e);
+					bail!(RouteError::NotFound("the file is no longer available".to_owned()));
+				}
 			};
-			response.write_to_sink(sink)?;
+			serve_ranged_content(sink, request, &reader, crate::mime::detect_content(&reader), disposition)?;
 			sink.flush()?;
 		}else {
-			return return_not_found(sink);
+			bail!(RouteError::NotFound(format!("no file is hosted as {}", filepath)));
 		}
 	}else {
-		if !request.query_params.starts_with("source=") {
-			return_routing_error(sink, "query parameter should be formatted as ?sourece=xxxxx");
-			return Ok(());
+		if query.get_all("source").count() > 1 {
+			bail!(RouteError::BadRequest("the source parameter was given more than once".to_owned()));
 		}
-		let (_key, value) = request.query_params.split_once("=").unwrap();
-		println!("\rDBG: source param -> {}", value);
-		match std::net::IpAddr::parse_ascii(value.as_bytes()) {
-			Ok(addr) => {
-				let mut peer_stream = std::net::TcpStream::connect((addr, 8000))?;
-				peer_stream.set_nonblocking(false)?;
-				{
-					let mut request = request.clone();
-					request.query_params = "";
-					request.write_to_sink(&mut peer_stream)?;
-					peer_stream.flush()?;
-					// wait for peer to respond
-					std::thread::sleep(std::time::Duration::from_millis(1000));
-				}
-				let mut buffer = Vec::<u8>::new();
-				peer_stream.read(&mut buffer)?;
-				if buffer.len() == 0 {
-					println!("\rError: failed to read any bytes from peer");
-					return_routing_error(sink, "failed to read from peer");
-					return Ok(());
-				}
-				sink.write(buffer.as_slice())?;
-				// let response = crate::http::HttpResponse::read_blocking(&mut buffer, &mut peer_stream)?;
-				// response.write_to_sink(sink)?;
-			},
-			Err(_e) => {
-				return_routing_error(sink, "failed to parse address from query parameter");
-				return Ok(());
+		let addr = GLOBALS.parse_peer_address(query.get("source").unwrap())
+			.map_err(|e| RouteError::BadRequest(e.to_string()))?;
+		proxy_peer_file(sink, request, addr, &query)?;
+	}
+
+	return Ok(());
+}
+
+// NOTE a peer that can not be reached is a bad gateway, one that does not
+// answer in time a gateway timeout
+fn peer_error(error: crate::http::client::ClientError) -> RouteError {
+	return match error.is_timeout() {
+		true => RouteError::GatewayTimeout(error.to_string()),
+		false => RouteError::BadGateway(error.to_string()),
+	};
+}
+
+// NOTE only peers that were added (not merely discovered) are proxied to, so
+// that the server can not be used to reach arbitrary hosts
+fn check_proxy_target(peers: &[crate::globals::Peer], addr: std::net::SocketAddr) -> Result<()> {
+	if !peers.iter().any(|peer| peer.addr == addr && peer.is_trusted()) {
+		bail!(RouteError::Forbidden(format!("{} is not a known peer", addr)));
+	}
+	return Ok(());
+}
+
+fn proxy_peer_file(
+	sink: &mut dyn Write,
+	request: &crate::http::HttpRequest,
+	addr: std::net::SocketAddr,
+	query: &crate::url::Query,
+) -> Result<()> {
+	check_proxy_target(&GLOBALS.read_peers(), addr)?;
+	println!("\rINFO: proxying {} to peer {}", request.route, addr);
+	return forward_to_peer(sink, request, addr, query, &Default::default());
+}
+
+// forwards a file request to a peer, passing its response on as it arrives
+fn forward_to_peer(
+	sink: &mut dyn Write,
+	request: &crate::http::HttpRequest,
+	addr: std::net::SocketAddr,
+	query: &crate::url::Query,
+	options: &crate::http::client::ClientOptions,
+) -> Result<()> {
+	// NOTE the source is dropped so the peer serves its own file, the other
+	// parameters (like disposition) and the range are passed along
+	let peer_target = match query.without("source").to_string() {
+		peer_query if peer_query == "" => request.route.to_owned(),
+		peer_query => format!("{}?{}", request.route, peer_query),
+	};
+	let headers = request.range_header().map(HttpHeader::Range).into_iter().collect::<Vec<HttpHeader>>();
+	let mut response = crate::http::client::get(addr, &peer_target, &headers, options)
+		.map_err(peer_error)?;
+
+	let status_line = format!("HTTP/1.1 {} {}", response.status_code, response.status_text);
+	let forwarded = response.headers.iter()
+		.filter(|(key, _value)| !HOP_BY_HOP_HEADERS.iter().any(|header| header.eq_ignore_ascii_case(key)))
+		.map(|(key, value)| format!("{}: {}", key, value))
+		.collect::<Vec<String>>();
+	write!(sink, "{}\r\n", status_line)?;
+	for line in forwarded.iter() { write!(sink, "{}\r\n", line)?; }
+	// NOTE a chunked body, or one that lasts until the peer closes the
+	// connection, has no length to pass on and is sent on chunked
+	let length = match response.framing() {
+		crate::http::BodyFraming::Empty => Some(0),
+		crate::http::BodyFraming::Length(length) => Some(length),
+		_ => None,
+	};
+	match length {
+		Some(length) => write!(sink, "Content-Length: {}\r\n\r\n", length)?,
+		None => write!(sink, "Transfer-Encoding: chunked\r\n\r\n")?,
+	}
+
+	// NOTE writes to the client block, so a slow client slows the reads from
+	// the peer instead of piling the file up in memory
+	let mut body = response.body();
+	let mut chunk = vec![0u8; 64 * 1024];
+	loop {
+		let count = body.read(&mut chunk)
+			.map_err(|e| peer_error(crate::http::client::ClientError::from_io(addr, e)))?;
+		if count == 0 { break; }
+		match length {
+			Some(_) => sink.write_all(&chunk[..count])?,
+			None => {
+				write!(sink, "{:x}\r\n", count)?;
+				sink.write_all(&chunk[..count])?;
+				sink.write_all(b"\r\n")?;
 			}
 		}
 	}
+	if length.is_none() { sink.write_all(b"0\r\n\r\n")?; }
+	sink.flush()?;
+
+	return Ok(());
+}
+
+// NOTE text previews stop after this many bytes
+const PREVIEW_LIMIT: usize = 512 * 1024;
+
+// a page showing a hosted file, with a player for media and numbered lines
+// for text
+fn serve_get_view(sink: &mut dyn Write, request: &crate::http::HttpRequest) -> Result<()> {
+	// NOTE a bare /view names no file
+	let filepath = match request.route.strip_prefix("/view/").map(crate::url::percent_decode) {
+		Some(Some(filepath)) => filepath,
+		Some(None) => bail!(RouteError::BadRequest("malformed percent encoding in the file path".to_owned())),
+		None => bail!(RouteError::NotFound(format!("{} does not name a file", request.route))),
+	};
+	let file = match GLOBALS.get_file_entry_by_name(&filepath) {
+		Some(file) => file,
+		None => bail!(RouteError::NotFound(format!("no file is hosted as {}", filepath))),
+	};
+	let reader = match file.reader() {
+		Ok(reader) => reader,
+		Err(e) => {
+			println!("\rWARN: {}", e);
+			bail!(RouteError::NotFound("the file is no longer available".to_owned()));
+		}
+	};
+
+	let template = GLOBALS.get_template("view.html")?;
+	return write_view(sink, &template, &filepath, &reader);
+}
+
+// renders the preview page of the file hosted as filepath
+fn write_view(
+	sink: &mut dyn Write,
+	template: &crate::http::Template,
+	filepath: &str,
+	reader: &crate::content::ContentReader,
+) -> Result<()> {
+	let mime = crate::mime::detect_content(reader).as_str();
+	let is_text = crate::mime::is_text_type(mime);
+
+	let lines = match is_text {
+		true => {
+			let head = reader.read_head(PREVIEW_LIMIT)?;
+			String::from_utf8_lossy(&head).lines().map(TemplateValue::from).collect()
+		},
+		false => Vec::new(),
+	};
+	let [is_image, is_audio, is_video, is_pdf] = ["image/", "audio/", "video/", "application/pdf"]
+		.map(|prefix| mime.starts_with(prefix));
+
+	let response = crate::http::HttpResponse {
+		protocol_version: "HTTP/1.1",
+		status_code: 200,
+		status_text: "OK",
+		headers: vec![
+			HttpHeader::ContentType(ContentType::text_html)
+		],
+		body: b"",
+	};
+
+	let values = [
+		("title", TemplateValue::from(filepath.split('/').rev().next().unwrap_or(filepath))),
+		("name", filepath.into()),
+		("mime", mime.into()),
+		("is_image", is_image.into()),
+		("is_audio", is_audio.into()),
+		("is_video", is_video.into()),
+		("is_pdf", is_pdf.into()),
+		("is_text", is_text.into()),
+		("has_preview", (is_image || is_audio || is_video || is_pdf || is_text).into()),
+		("lines", TemplateValue::List(lines)),
+		("truncated", (is_text && reader.len() > PREVIEW_LIMIT).into()),
+		("preview_limit", format_size(PREVIEW_LIMIT as u64).into()),
+	];
+	response.write_from_readinto(&mut TemplateReader{ template, values: &values }, sink)?;
 
 	return Ok(());
 }
@@ -206,7 +535,7 @@ fn serve_get_files(sink: &mut dyn Write) -> Result<()> {
 	response.write_from_readinto(
 		&mut ClosureReader{ source: &|dest| {
 			let mut write_size = 0;
-			for filename in GLOBALS.read_file_entries().filenames.clone() {
+			for filename in GLOBALS.read_file_entries().iter().map(|entry| entry.name.clone()) {
 				write_size += dest.write(filename.as_bytes())?;
 				write_size += dest.write(b"\n")?;
 			}
@@ -219,29 +548,13 @@ fn serve_get_files(sink: &mut dyn Write) -> Result<()> {
 }
 
 fn serve_get_playlist(sink: &mut dyn Write, request: &crate::http::HttpRequest) -> Result<()> {
-	let mut playlist_name_param: Option<&str> = None;
-
-	for query in request.query_params.split('&') {
-		if query.starts_with("playlist=") {
-			if playlist_name_param.is_some() {
-				return_routing_error(sink, &format!("duplicate query paramter: {}", query));
-				return Ok(());
-			}else {
-				playlist_name_param = Some(&query[9..]);
-			}
-		}
-		else {
-			return_routing_error(sink, &format!("unrecognized query parameter: {}", query));
-			return Ok(());
-		}
-	}
+	let query = crate::url::Query::parse(request.query_params)
+		.map_err(|e| RouteError::BadRequest(e.to_string()))?;
 
-	let playlist_name = match playlist_name_param {
+	let playlist_name = match query.get("playlist") {
 		Some(name) => name,
-		None => {
-			todo!();
-			// SERVE A PLAYLIST BROWSER HERE
-		}
+		// TODO serve a playlist browser here
+		None => bail!(RouteError::BadRequest("missing the playlist parameter".to_owned())),
 	};
 
 	if let Some((iter, _name)) = GLOBALS.read_playlists()
@@ -264,102 +577,242 @@ fn serve_get_playlist(sink: &mut dyn Write, request: &crate::http::HttpRequest)
 			body: b"",
 		};
 
-		let playlist_template = GLOBALS.get_static_file("playlist.html")
-				.ok_or(anyhow!("Failed to fetch playlist.html from globals"))?;
-		let mut template = crate::http::BodyTemplate {
-			template: playlist_template.as_ref(),
-			keys: &[b"playlist_name", b"playlist_songs"],
-			values: &mut [
-				&mut playlist_name.as_bytes(),
-				&mut iterator_reader!(
-					name, playlist.files.filenames.iter()
-						.map(|filename| filename.split('/').rev().next().unwrap_or(filename)),
-					[ b"\"", name.as_bytes(), b"\"," ]
-				)
-			],
-		};
-		response.write_from_readinto(&mut template, sink)?;
+		let template = GLOBALS.get_template("playlist.html")?;
+		let values = [
+			("title", TemplateValue::from(playlist_name)),
+			("playlist_name", playlist_name.into()),
+			("playlist_songs", playlist.files.iter().map(|entry| entry.name.as_ref())
+				.map(|filename| filename.split('/').rev().next().unwrap_or(filename))
+				.collect::<Vec<&str>>().into()),
+		];
+		response.write_from_readinto(&mut TemplateReader{ template: &template, values: &values }, sink)?;
 	}else {
-		return_routing_error(sink, &format!("Unable to fetch playlist {}", playlist_name));
+		bail!(RouteError::NotFound(format!("no playlist named {}", playlist_name)));
 	}
 	
 	return Ok(());
 }
 
 fn serve_get_playlist_song(sink: &mut dyn Write, request: &crate::http::HttpRequest) -> Result<()> {
-	let mut playlist_name_param: Option<&str> = None;
-	let mut song_number_param: Option<&str> = None;
-
-	for query in request.query_params.split('&') {
-		if query.starts_with("playlist=") {
-			if playlist_name_param.is_some() {
-				return_routing_error(sink, &format!("duplicate query paramter: {}", query));
-				return Ok(());
-			}else {
-				playlist_name_param = Some(&query[9..]);
-			}
-		}
-		else if query.starts_with("song_number=") {
-			if song_number_param.is_some() {
-				return_routing_error(sink, &format!("duplicate query paramter: {}", query));
-				return Ok(());
-			}else {
-				song_number_param = Some(&query[12..]);
-			}
-		}
-		else {
-			return_routing_error(sink, &format!("unrecognized query parameter: {}", query));
-			return Ok(());
-		}
-	}
+	let query = crate::url::Query::parse(request.query_params)
+		.map_err(|e| RouteError::BadRequest(e.to_string()))?;
 
-	let playlist_name = match playlist_name_param {
+	let playlist_name = match query.get("playlist") {
 		Some(name) => name,
-		None => {
-			return_routing_error(sink, "unable to parse playlist name parameter");
-			return Ok(());
-		}
+		None => bail!(RouteError::BadRequest("missing the playlist parameter".to_owned())),
 	};
 
-	let song_number = match song_number_param {
-		Some(number_string) => match number_string.parse::<u32>() {
-			Ok(number) => number,
-			Err(e) => {
-				return_routing_error(sink, &format!("failed to parse song_number parameter: {}", e));
-				return Ok(());
-			}
-		},
-		None => 0
-	};
+	let song_number = query.get_parsed::<u32>("song_number")
+		.map_err(|e| RouteError::BadRequest(e.to_string()))?
+		.unwrap_or(0);
 
 
-	let songmap = match GLOBALS.get_song_by_playlist_and_index(playlist_name, song_number) {
-		Some(songmap) => songmap,
-		None => {
-			return_routing_error(sink, &format!("Unable to find playlist name: {}", playlist_name));
-			return Ok(());
+	let song = match GLOBALS.get_song_by_playlist_and_index(playlist_name, song_number) {
+		Some(song) => song,
+		None => bail!(RouteError::NotFound(format!("no song {} in a playlist named {}", song_number, playlist_name))),
+	};
+	let reader = match song.reader() {
+		Ok(reader) => reader,
+		Err(e) => {
+			println!("\rWARN: {}", e);
+			bail!(RouteError::NotFound("the song is no longer available".to_owned()));
 		}
 	};
 
+	// NOTE anything can be dropped into a playlist directory, a page or an svg
+	// is downloaded rather than shown, so it never runs as part of this site
+	let content_type = crate::mime::detect_content(&reader);
+	let disposition = if crate::mime::runs_script(content_type.as_str()) {
+		ContentDisposition::Attachment(reader.path().file_name().map(|name| name.to_string_lossy()))
+	}else { ContentDisposition::Inline };
+	serve_ranged_content(sink, request, &reader, content_type, disposition)?;
+
+	return Ok(());
+}
+
+fn format_size(size: u64) -> String {
+	const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+	if size < 1024 { return format!("{} B", size); }
+	let mut scaled = size as f64 / 1024.0;
+	let mut unit = 0;
+	while scaled >= 1024.0 && unit + 1 < UNITS.len() {
+		scaled /= 1024.0;
+		unit += 1;
+	}
+	return format!("{:.1} {}", scaled, UNITS[unit]);
+}
+
+// formats as YYYY-MM-DD HH:MM in utc
+fn format_timestamp(time: std::time::SystemTime) -> String {
+	let seconds = match time.duration_since(std::time::UNIX_EPOCH) {
+		Ok(duration) => duration.as_secs() as i64,
+		Err(_) => return String::from("-"),
+	};
+	let (days, day_seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+	// NOTE civil from days, see howard hinnant's date algorithms
+	let shifted = days + 719468;
+	let era = shifted.div_euclid(146097);
+	let day_of_era = shifted.rem_euclid(146097);
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	return format!(
+		"{:04}-{:02}-{:02} {:02}:{:02}",
+		year, month, day, day_seconds / 3600, (day_seconds % 3600) / 60
+	);
+}
+
+fn serve_browse_roots(sink: &mut dyn Write) -> Result<()> {
+	let entries = GLOBALS.read_shared_directories().iter()
+		.map(|directory| TemplateValue::Record(vec![
+			("name", directory.name.as_ref().into()),
+			("is_dir", true.into()),
+			("size", "".into()),
+			("modified", "".into()),
+		]))
+		.collect();
+	return write_browse_listing(sink, "Shared Folders", false, entries);
+}
+
+// writes browse.html, entries are records with a name, is_dir, size and
+// modified, linked relative to the listed directory
+fn write_browse_listing(sink: &mut dyn Write, title: &str, parent: bool, entries: Vec<TemplateValue>) -> Result<()> {
 	let response = crate::http::HttpResponse {
 		protocol_version: "HTTP/1.1",
 		status_code: 200,
 		status_text: "OK",
 		headers: vec![
-			HttpHeader::ContentType(ContentType::audio_flac),
-			HttpHeader::ContentDisposition(ContentDisposition::Inline),
-			HttpHeader::ContentLength(songmap.len()),
-			HttpHeader::TransferEncoding(TransferEncoding::binary),
+			HttpHeader::ContentType(ContentType::text_html)
 		],
-		body: songmap.as_ref(),
+		body: b"",
 	};
 
-	response.write_to_sink(sink)?;
+	let template = GLOBALS.get_template("browse.html")?;
+	let values = [
+		("title", TemplateValue::from(title)),
+		("parent", parent.into()),
+		("entries", TemplateValue::List(entries)),
+	];
+	response.write_from_readinto(&mut TemplateReader{ template: &template, values: &values }, sink)?;
 
 	return Ok(());
 }
 
-fn serve_get_peers(sink: &mut dyn Write) -> Result<()> {
+// NOTE relative links in a listing need it to be addressed as a directory
+fn redirect_to_directory(sink: &mut dyn Write, request: &crate::http::HttpRequest) -> Result<()> {
+	let location = format!("{}/", request.route);
+	let response = crate::http::HttpResponse {
+		protocol_version: "HTTP/1.1",
+		status_code: 301,
+		status_text: "Moved Permanently",
+		headers: vec![
+			HttpHeader::Location(&location),
+			HttpHeader::ContentLength(0),
+		],
+		body: b"",
+	};
+	response.write_to_sink(sink)?;
+	return Ok(());
+}
+
+// lists a directory of a shared tree, or serves the file at the path
+fn serve_get_browse(sink: &mut dyn Write, request: &crate::http::HttpRequest) -> Result<()> {
+	let browse_path = match crate::url::percent_decode(request.route.trim_start_matches("/browse")) {
+		Some(path) => path,
+		None => bail!(RouteError::BadRequest("malformed percent encoding in path".to_owned())),
+	};
+	let browse_path = browse_path.trim_start_matches('/');
+	if browse_path == "" {
+		if !request.route.ends_with('/') { return redirect_to_directory(sink, request); }
+		return serve_browse_roots(sink);
+	}
+
+	let (root_name, relative_path) = browse_path.split_once('/').unwrap_or((browse_path, ""));
+
+	let directories = GLOBALS.read_shared_directories();
+	let directory = match directories.iter().find(|directory| directory.name.as_ref() == root_name) {
+		Some(directory) => directory,
+		None => bail!(RouteError::NotFound(format!("no folder named {} is shared", root_name))),
+	};
+	let disk_path = match directory.resolve(relative_path) {
+		Some(path) => path,
+		None => bail!(RouteError::NotFound(format!("{} is not shared", browse_path))),
+	};
+
+	if disk_path.is_file() {
+		// NOTE files in a shared tree are never mapped, any of them may be
+		// changing while it is served
+		let reader = match crate::content::ContentReader::open(&disk_path) {
+			Ok(reader) => reader,
+			Err(_) => bail!(RouteError::NotFound(format!("{} is not shared", browse_path))),
+		};
+		let filename = disk_path.file_name().and_then(|name| name.to_str()).unwrap_or(root_name);
+		serve_ranged_content(
+			sink, request, &reader,
+			crate::mime::detect_content(&reader),
+			ContentDisposition::Attachment(Some(filename.into()))
+		)?;
+		return Ok(());
+	}
+
+	if !request.route.ends_with('/') { return redirect_to_directory(sink, request); }
+
+	let entries = directory.list(relative_path)?.iter()
+		.map(|entry| TemplateValue::Record(vec![
+			("name", entry.name.as_str().into()),
+			("is_dir", entry.is_dir.into()),
+			("size", if entry.is_dir { String::new() } else { format_size(entry.size) }.into()),
+			("modified", entry.modified.map(format_timestamp).unwrap_or_default().into()),
+		]))
+		.collect();
+	let title = format!("{}/{}", directory.name, relative_path.trim_end_matches('/'));
+
+	return write_browse_listing(sink, &title, true, entries);
+}
+
+// the peer addresses one per line, or with what is known about each as json
+// when the request prefers it
+fn serve_get_peers(sink: &mut dyn Write, request: &crate::http::HttpRequest) -> Result<()> {
+	if request.prefers_json() {
+		let peers = GLOBALS.read_peers().iter().map(|peer| {
+			let health = &peer.health;
+			return crate::json::JsonValue::Object(vec![
+				("address".to_owned(), peer.addr.to_string().into()),
+				("name".to_owned(), peer.name.as_deref().map_or(crate::json::JsonValue::Null, Into::into)),
+				("origin".to_owned(), match peer.origin {
+					crate::globals::PeerOrigin::Added => "added",
+					crate::globals::PeerOrigin::Discovered => "discovered",
+				}.into()),
+				("reachable".to_owned(), health.is_reachable().into()),
+				("latency_ms".to_owned(), health.latency
+					.map_or(crate::json::JsonValue::Null, |latency| (latency.as_millis() as u64).into())),
+				("failures".to_owned(), (health.failures as u64).into()),
+				// NOTE seconds since the unix epoch
+				("last_success".to_owned(), health.last_success
+					.and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+					.map_or(crate::json::JsonValue::Null, |since_epoch| since_epoch.as_secs().into())),
+			]);
+		}).collect::<Vec<crate::json::JsonValue>>();
+
+		let response = crate::http::HttpResponse {
+			protocol_version: "HTTP/1.1",
+			status_code: 200,
+			status_text: "OK",
+			headers: vec![
+				HttpHeader::ContentType(ContentType::application_json)
+			],
+			body: b"",
+		};
+		let body = crate::json::JsonValue::Array(peers).to_json_string(false);
+		response.write_from_readinto(&mut body.as_bytes(), sink)?;
+		return Ok(());
+	}
+
 	let response = crate::http::HttpResponse {
 		protocol_version: "HTTP/1.1",
 		status_code: 200,
@@ -375,7 +828,7 @@ fn serve_get_peers(sink: &mut dyn Write) -> Result<()> {
 			let peer_count = peers.len();
 
 			for (iter, peer) in peers.iter().enumerate() {
-				write_size += dest.write(peer.to_string().as_bytes())?;
+				write_size += dest.write(peer.addr.to_string().as_bytes())?;
 				if iter < peer_count {
 					write_size += dest.write(b"\n")?;
 				}
@@ -389,47 +842,29 @@ fn serve_get_peers(sink: &mut dyn Write) -> Result<()> {
 	return Ok(());
 }
 
+// the names of the files peer_addr hosts
+fn fetch_peer_files(peer_addr: std::net::SocketAddr) -> Result<Vec<String>> {
+	let response = crate::http::client::get(peer_addr, "/files", &[], &crate::health::peer_request_options())?;
+	if !response.is_success() {
+		bail!("{} answered /files with {} {}", peer_addr, response.status_code, response.status_text);
+	}
+	let body = response.read_body(GLOBALS.config.request_limits.max_body_size)?;
+	return Ok(String::from_utf8(body)
+		.map_err(|_| anyhow!("the file list of {} is not valid utf-8", peer_addr))?
+		.lines()
+		.filter(|file| *file != "")
+		.map(|file| file.to_owned())
+		.collect::<Vec<String>>());
+}
+
 fn serve_get_peer_files(sink: &mut dyn Write) -> Result<()> {
 	let mut fetch_pool = crate::ThreadPool::new();
-	for peer_addr in GLOBALS.read_peers().clone().into_iter() {
+	for peer_addr in GLOBALS.reachable_peer_addresses() {
 		fetch_pool.spawn(move || {
-			let mut stream = std::net::TcpStream::connect((peer_addr, 8000))?;
-			stream.set_nonblocking(false)?;
-
-			let request = crate::http::HttpRequest {
-				protocol_version: "HTTP/1.1",
-				method: crate::http::HttpMethod::GET,
-				route: "/files",
-				query_params: "",
-				headers: vec!(),
-				body: b"",
-			};
-			request.write_to_sink(&mut stream)?;
-			stream.flush()?;
-
-			// // NOTE this may fix a problem with incomplete reads if un-comented
-			// std::thread::sleep(std::time::Duration::from_millis(10));
-
-			let mut buffer = Vec::with_capacity(16384);
-			let response = crate::http::HttpResponse::read_blocking(&mut buffer, &mut stream)?;
-
-			let (_head, body) = match unsafe{ buffer.as_slice().as_ascii_unchecked() }
-				.as_str().split_once("\r\n\r\n")
-			{
-				Some(pair) => pair,
-				None => {
-					println!(
-						"\rError: failed to split NOTE: buffer contents -> {}",
-						unsafe{ buffer.as_slice().as_ascii_unchecked() }.as_str()
-					);
-					panic!("Thread Cannot continue");
-				}
-			};
-
-			let files = body.split("\n").map(|file| file.to_owned())
-				.collect::<Vec<String>>();
-
-			return Ok((peer_addr, files));
+			let start = std::time::Instant::now();
+			let files = fetch_peer_files(peer_addr);
+			crate::health::record_peer_request(peer_addr, files.as_ref().map(|_files| start.elapsed()));
+			return files.map(|files| (peer_addr, files));
 		});
 	}
 
@@ -459,38 +894,17 @@ fn serve_get_peer_files(sink: &mut dyn Write) -> Result<()> {
 		})
 		.filter(|entry| entry.is_some())
 		.map(|entry| entry.unwrap())
-		.collect::<Vec<(std::net::IpAddr, Vec<String>)>>();
-
-	response.write_from_readinto(
-		&mut ClosureReader{ source: &mut move |dest| {
-			let mut write_size = 0;
-			write_size += dest.write(b"[")?;
-			for resp in responses.iter() {
-				let mut filename_iter = resp.1.iter();
-				// for filename in filename_iter {
-				let mut filename = filename_iter.next();
-				while filename.is_some() {
-					write_size += dest.write(b"[\"")?;
-					write_size += dest.write(resp.0.to_string().as_bytes())?;
-					write_size += dest.write(b"\",\"")?;
-					write_size += dest.write(filename.unwrap().as_bytes())?;
-					write_size += dest.write(b"\"]")?;
-					filename = filename_iter.next();
-					if filename.is_some() {
-						if filename.unwrap().as_str() == "" {
-							break;
-						}else {
-							write_size += dest.write(b",")?;
-						}
-					}
-				}
-			}
-			write_size += dest.write(b"]")?;
-
-			return Ok(write_size);
-		}},
-		sink
-	)?;
+		.collect::<Vec<(std::net::SocketAddr, Vec<String>)>>();
+
+	// NOTE a list of [peer, file name] pairs
+	let entries = responses.into_iter()
+		.flat_map(|(peer_addr, files)| files.into_iter().map(move |file| crate::json::JsonValue::Array(vec![
+			peer_addr.to_string().into(),
+			file.into(),
+		])))
+		.collect::<Vec<crate::json::JsonValue>>();
+	let body = crate::json::JsonValue::Array(entries).to_json_string(false);
+	response.write_from_readinto(&mut body.as_bytes(), sink)?;
 
 	sink.flush()?;
 	
@@ -498,34 +912,359 @@ fn serve_get_peer_files(sink: &mut dyn Write) -> Result<()> {
 }
 
 
-// TODO update this function
-fn serve_post_peers(
-	headers: &[crate::http::HttpHeader],
-	body: &[u8],
-) -> Result<()> {
+// adds the peers listed one per line in the body. NOTE added peers are
+// proxied to, so this takes the admin token like the /admin endpoints
+fn serve_post_peers(sink: &mut dyn Write, request: &crate::http::HttpRequest) -> Result<()> {
+	check_admin_token(request)?;
 
-	let body_str = body.as_ascii().unwrap().as_str();
+	let body_str = std::str::from_utf8(request.body)
+		.map_err(|_| RouteError::BadRequest("peer addresses are not valid utf-8".to_owned()))?;
 
-	for line in body_str.split('\n') {
-		if let Ok(addr) = std::net::IpAddr::from_str(line) {
+	for line in body_str.split('\n').map(|line| line.trim()) {
+		if line == "" { continue; }
+		if let Ok(addr) = GLOBALS.parse_peer_address(line) {
 			GLOBALS.push_peer(addr);
 		}else {
-			todo!();
+			println!("\rWARN: ignoring invalid peer address -> {}", line);
 		}
 	}
 
+	let response = crate::http::HttpResponse {
+		protocol_version: "HTTP/1.1",
+		status_code: 200,
+		status_text: "OK",
+		headers: vec![
+			HttpHeader::ContentLength(0)
+		],
+		body: b"",
+	};
+	response.write_to_sink(sink)?;
+
 	return Ok(());
 }
 
+// NOTE compares every byte so the time taken does not reveal how much
+// of the token matched
+fn tokens_match(given: &str, expected: &str) -> bool {
+	if given.len() != expected.len() { return false; }
+	return given.bytes().zip(expected.bytes())
+		.fold(0u8, |difference, (a, b)| difference | (a ^ b)) == 0;
+}
+
+fn check_admin_token(request: &crate::http::HttpRequest) -> Result<()> {
+	let expected_token = match &GLOBALS.config.admin_token {
+		Some(token) => token,
+		None => bail!(RouteError::Forbidden("admin endpoints are disabled, set admin_token to enable them".to_owned())),
+	};
+	match request.bearer_token() {
+		Some(token) if tokens_match(token, expected_token) => return Ok(()),
+		_ => bail!(RouteError::Unauthorized("missing or invalid bearer token".to_owned())),
+	}
+}
+
+// POST /admin/<command> with the command's arguments as the body, quoted
+// the same way as at the prompt, e.g. /admin/rename with `"old name" new`
+fn serve_post_admin(sink: &mut dyn Write, request: &crate::http::HttpRequest, command: &str) -> Result<()> {
+	check_admin_token(request)?;
+
+	let arguments = match std::str::from_utf8(request.body) {
+		Ok(arguments) => arguments.trim(),
+		Err(_) => bail!(RouteError::BadRequest("arguments are not valid utf-8".to_owned())),
+	};
+	let tokens = crate::CommandTokenIter::new(arguments).collect::<Vec<std::borrow::Cow<str>>>();
+	let arguments = tokens.iter().map(|token| token.as_ref()).collect::<Vec<&str>>();
+	println!("\rINFO: admin request {} {:?}", command, arguments);
+
+	match (command, arguments.as_slice()) {
+		("remove", [name]) => {
+			if GLOBALS.remove_entry(name) { return return_status(sink, 200, "OK", &format!("no longer hosting {}", name)); }
+			bail!(RouteError::NotFound(format!("nothing hosted as {}", name)));
+		},
+		("rename", [old_name, new_name]) => {
+			return match GLOBALS.rename_entry(old_name, new_name) {
+				Ok(true) => return_status(sink, 200, "OK", &format!("renamed {} to {}", old_name, new_name)),
+				Ok(false) => Err(RouteError::NotFound(format!("nothing hosted as {}", old_name)).into()),
+				Err(e) => Err(RouteError::Conflict(e.to_string()).into()),
+			};
+		},
+		("remove_playlist", [name]) => {
+			if GLOBALS.remove_playlist(name) { return return_status(sink, 200, "OK", &format!("removed playlist {}", name)); }
+			bail!(RouteError::NotFound(format!("no playlist named {}", name)));
+		},
+		("remove_peer", [peer]) => {
+			return match GLOBALS.find_peer(peer) {
+				Ok(addr) if GLOBALS.remove_peer(addr).is_some() => return_status(sink, 200, "OK", &format!("removed peer {}", addr)),
+				Ok(addr) => Err(RouteError::NotFound(format!("{} is not a peer", addr)).into()),
+				Err(e) => Err(RouteError::BadRequest(e.to_string()).into()),
+			};
+		},
+		("remove" | "rename" | "remove_playlist" | "remove_peer", _) => {
+			bail!(RouteError::BadRequest(format!("wrong number of arguments to {}", command)));
+		},
+		_ => bail!(RouteError::NotFound(format!("no admin command named {}", command))),
+	}
+}
+
+// picks a path in the upload directory for a client supplied filename, the
+// name is reduced to its last component and never replaces an existing file.
+// NOTE the path is reserved by creating it empty, so two uploads of the same
+// name at once can not both pick it
+fn upload_destination(upload_dir: &std::path::Path, client_filename: &str) -> Result<Option<std::path::PathBuf>> {
+	let filename = client_filename.rsplit(['/', '\\']).next().unwrap_or("").trim();
+	if filename == "" || filename == "." || filename == ".." || filename.starts_with('.') {
+		return Ok(None);
+	}
+
+	let (stem, extension) = match filename.rsplit_once('.') {
+		Some((stem, extension)) if stem != "" => (stem, Some(extension)),
+		_ => (filename, None)
+	};
+
+	let mut candidate = upload_dir.join(filename);
+	let mut counter = 1;
+	loop {
+		match std::fs::File::create_new(&candidate) {
+			Ok(_) => return Ok(Some(candidate)),
+			Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {},
+			Err(e) => bail!("failed to create {} -> {}", candidate.display(), e),
+		}
+		candidate = upload_dir.join(match extension {
+			Some(extension) => format!("{} ({}).{}", stem, counter, extension),
+			None => format!("{} ({})", stem, counter),
+		});
+		counter += 1;
+	}
+}
+
+// NOTE writes the part to a hidden temporary file first so that a broken
+// upload never shows up under the real name
+fn receive_upload_part(part_data: &mut dyn Read, destination: &std::path::Path) -> Result<()> {
+	let temporary_name = format!(
+		".{}.part",
+		destination.file_name().and_then(|name| name.to_str()).unwrap_or("upload")
+	);
+	let temporary_path = destination.with_file_name(temporary_name);
+
+	let copy_result = (|| -> Result<()> {
+		let mut file = std::fs::File::create_new(&temporary_path)?;
+		std::io::copy(part_data, &mut file)?;
+		file.sync_all()?;
+		// NOTE only replaces the empty file upload_destination reserved
+		std::fs::rename(&temporary_path, destination)?;
+		return Ok(());
+	})();
+
+	if copy_result.is_err() {
+		let _ = std::fs::remove_file(&temporary_path);
+		let _ = std::fs::remove_file(destination);
+	}
+	return copy_result;
+}
+
+fn serve_post_upload(
+	sink: &mut dyn Write,
+	request: &crate::http::HttpRequest,
+	body: &mut crate::http::BodyReader,
+) -> Result<()> {
+	let boundary = match request.headers.iter().find_map(|header| match header {
+		HttpHeader::MultipartFormData(boundary) => Some(boundary.as_ref()),
+		_ => None
+	}) {
+		Some(boundary) => boundary,
+		None => bail!(RouteError::BadRequest("upload must be sent as multipart/form-data".to_owned())),
+	};
+
+	let limit = GLOBALS.config.request_limits.max_upload_size;
+	let has_length = match body.framing() {
+		crate::http::BodyFraming::Length(length) if length > limit => {
+			bail!(RouteError::TooLarge("upload exceeds the upload size limit".to_owned()));
+		},
+		crate::http::BodyFraming::Length(_) => true,
+		_ => false,
+	};
+
+	if let Err(e) = std::fs::create_dir_all(&GLOBALS.config.upload_dir) {
+		bail!("failed to create upload directory {} -> {}", GLOBALS.config.upload_dir.display(), e);
+	}
+
+	let mut limited_body = body.take(limit as u64);
+	let mut multipart = crate::http::MultipartReader::new(&mut limited_body, boundary);
+	let mut uploaded = Vec::<String>::new();
+
+	let upload_result = (|| -> Result<()> {
+		while let Some(part) = multipart.next_part()? {
+			let filename = match part.filename {
+				Some(filename) if filename != "" => filename,
+				// NOTE non-file form fields and empty file inputs are skipped
+				_ => continue
+			};
+			let destination = match upload_destination(&GLOBALS.config.upload_dir, &filename)? {
+				Some(destination) => destination,
+				None => {
+					println!("\rWARN: rejecting upload with unusable filename -> {}", filename);
+					continue;
+				}
+			};
+
+			receive_upload_part(&mut multipart, &destination)?;
+
+			let entry_name = destination.to_string_lossy().into_owned();
+			GLOBALS.push_file_entry(&entry_name, &destination)?;
+			println!("\rINFO: received upload {}", entry_name);
+			uploaded.push(entry_name);
+		}
+		return Ok(());
+	})();
+
+	if let Err(e) = upload_result {
+		println!("\rError: upload failed after {} files -> {}", uploaded.len(), e);
+		// NOTE a body without a length is only found to be too large once the
+		// limit is used up, which the multipart parser sees as a truncated body
+		if !has_length && limited_body.limit() == 0 {
+			bail!(RouteError::TooLarge("upload exceeds the upload size limit".to_owned()));
+		}
+		bail!(RouteError::BadRequest(format!("upload failed -> {}", e)));
+	}
+
+	let response = crate::http::HttpResponse {
+		protocol_version: "HTTP/1.1",
+		status_code: 303,
+		status_text: "See Other",
+		headers: vec![
+			HttpHeader::Location("/"),
+			HttpHeader::ContentType(ContentType::text_plain),
+		],
+		body: b"",
+	};
+	response.write_from_readinto(
+		&mut iterator_reader!(name, uploaded.iter(), [ name.as_bytes(), b"\n" ]),
+		sink
+	)?;
+
+	return Ok(());
+}
+
+// answers with a plain text explanation of the status
+fn return_status(sink: &mut dyn Write, status_code: usize, status_text: &str, message: &str) -> Result<()> {
+	let response = crate::http::HttpResponse {
+		protocol_version: "HTTP/1.1",
+		status_code,
+		status_text,
+		headers: vec![
+			HttpHeader::ContentType(ContentType::text_plain),
+			HttpHeader::ContentLength(message.len()),
+		],
+		body: message.as_bytes(),
+	};
+	response.write_to_sink(sink)?;
+	return Ok(());
+}
+
+// answers a request that could not be read with the matching status, if
+// the error has one
+fn return_read_error(sink: &mut dyn Write, error: &anyhow::Error) {
+	let (status_code, status_text) = match error.downcast_ref::<crate::http::HttpReadError>()
+		.and_then(|read_error| read_error.status())
+	{
+		Some(status) => status,
+		None => return
+	};
+	let response = crate::http::HttpResponse {
+		protocol_version: "HTTP/1.1",
+		status_code,
+		status_text,
+		headers: vec![
+			HttpHeader::ContentLength(0),
+		],
+		body: b"",
+	};
+	// NOTE the client is likely still sending, so failing to answer is fine
+	let _ = response.write_to_sink(sink);
+	let _ = sink.flush();
+}
+
 pub fn handle_client(mut client: std::net::TcpStream) -> Result<()> {
 	client.set_nonblocking(false)?;
 	let client_peer_addr = client.peer_addr()?;
 	let client_local_addr = client.local_addr()?;
+	let limits = GLOBALS.config.connection_limits;
+
+	// NOTE responses go through a second handle so that the request body can
+	// still be streamed from the client while a route is answering
+	let mut client_writer = client.try_clone()?;
+
+	// bytes of pipelined requests read along with the previous request
+	let mut pipelined = Vec::<u8>::new();
+	let mut served_count = 0;
+	loop {
+		if served_count > 0 && pipelined.len() == 0 {
+			client.set_read_timeout(Some(limits.idle_timeout))?;
+			match client.peek(&mut [0u8; 1]) {
+				Ok(0) => break,
+				Ok(_) => {},
+				Err(e) => match e.kind() {
+					std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => break,
+					_ => return Err(e.into()),
+				}
+			}
+		}
+		client.set_read_timeout(Some(limits.request_timeout))?;
+		served_count += 1;
+
+		let keep_alive = serve_request(
+			&mut client, &mut client_writer, &mut pipelined,
+			served_count >= limits.max_requests,
+			client_local_addr, client_peer_addr,
+		)?;
+		if !keep_alive { break; }
+	}
 
-// 	// TODO parse Content-Length http header so that a body can be fully downloaded
-	// client.set_nonblocking(true)?;
-	let mut request_buffer = Vec::<u8>::new();
-	let request = crate::http::HttpRequest::read_blocking(&mut request_buffer, &mut client)?;
+	let _ = client_writer.shutdown(std::net::Shutdown::Both);
+	return Ok(());
+}
+
+// the methods a route answers to, for the Allow header of a 405
+fn allowed_methods(path_base: &str) -> Option<&'static str> {
+	match path_base {
+		"/" | "" | "favicon.ico" | "file" | "view" | "files" | "browse" | "playlist" | "peer_files" => Some("GET"),
+		"peers" => Some("GET, POST"),
+		"upload" | "admin" => Some("POST"),
+		_ => None,
+	}
+}
+
+// serves one request from the connection, returning whether the connection
+// should be kept open for another
+fn serve_request(
+	client: &mut std::net::TcpStream,
+	client_writer: &mut std::net::TcpStream,
+	pipelined: &mut Vec<u8>,
+	last_request: bool,
+	client_local_addr: std::net::SocketAddr,
+	client_peer_addr: std::net::SocketAddr,
+) -> Result<bool> {
+	let mut request_buffer = std::mem::take(pipelined);
+	let mut body_buffer = Vec::<u8>::new();
+	let (mut request, mut body_reader) = match crate::http::HttpRequest::read_head_blocking(
+		&mut request_buffer, client, GLOBALS.config.request_limits
+	) {
+		Ok(pair) => pair,
+		Err(e) => {
+			return_read_error(client_writer, &e);
+			return Err(e);
+		}
+	};
+
+	let is_upload = matches!(request.method, crate::http::HttpMethod::POST)
+		&& request.route == "/upload";
+	if !is_upload {
+		if let Err(e) = body_reader.read_to_end_limited(&mut body_buffer, GLOBALS.config.request_limits.max_body_size) {
+			let e = anyhow::Error::from(e);
+			return_read_error(client_writer, &e);
+			return Err(e);
+		}
+		request.body = body_buffer.as_slice();
+	}
 
 	println!(
 		"\rINFO: serving request {} {} on version {}",
@@ -534,46 +1273,211 @@ pub fn handle_client(mut client: std::net::TcpStream) -> Result<()> {
 		request.protocol_version
 	);
 
+	let keep_alive = request.wants_keep_alive() && !last_request;
+	let connection_header = match (keep_alive, request.protocol_version) {
+		(false, _) => Some("Connection: close"),
+		(true, "HTTP/1.0") => Some("Connection: keep-alive"),
+		(true, _) => None,
+	};
+
 	let mut path_iter = request.route.split("/");
 	let _ = path_iter.next();
 	let path_base = path_iter.next()
 		.unwrap_or(request.route);
 
+	let mut header_injector = crate::http::HeaderInjector::new(client_writer, connection_header);
 	let mut buffer_backing: [u8; 16384] = unsafe{ std::mem::zeroed() };
-	let mut buffer = crate::http::StreamBuffer::new(&mut buffer_backing, &mut client);
-
-	match request.method {
-		crate::http::HttpMethod::GET => {
-			match path_base {
-				"/" | "" => serve_get_index(client_local_addr, client_peer_addr, &mut buffer)?,
-				"favicon.ico" => serve_get_favicon(&mut buffer)?,
-				"file" => serve_get_file(&mut buffer, &request)?,
-				"files" => serve_get_files(&mut buffer)?,
-				"playlist" => {
-					match path_iter.next() {
-						Some("songs") => { serve_get_playlist_song(&mut buffer, &request)?; },
-						Some(_) => { return_not_found(&mut buffer)?; },
-						None => { serve_get_playlist(&mut buffer, &request)?; }
-					}
-				},
-				"peers" => { serve_get_peers(&mut buffer)?; },
-				"peer_files" => { serve_get_peer_files(&mut buffer)?; }
-				_ => return_not_found(&mut buffer)?,
-			}
+	let mut buffer = crate::http::StreamBuffer::new(&mut buffer_backing, &mut header_injector);
+
+	let route_result = match (request.method, path_base) {
+		(crate::http::HttpMethod::GET, "/" | "") => serve_get_index(client_local_addr, client_peer_addr, &mut buffer),
+		(crate::http::HttpMethod::GET, "favicon.ico") => serve_get_favicon(&mut buffer),
+		(crate::http::HttpMethod::GET, "file") => serve_get_file(&mut buffer, &request),
+		(crate::http::HttpMethod::GET, "view") => serve_get_view(&mut buffer, &request),
+		(crate::http::HttpMethod::GET, "files") => serve_get_files(&mut buffer),
+		(crate::http::HttpMethod::GET, "browse") => serve_get_browse(&mut buffer, &request),
+		(crate::http::HttpMethod::GET, "playlist") => match path_iter.next() {
+			Some("songs") => serve_get_playlist_song(&mut buffer, &request),
+			Some(_) => Err(RouteError::NotFound(format!("{} does not exist", request.route)).into()),
+			None => serve_get_playlist(&mut buffer, &request),
 		},
-		crate::http::HttpMethod::POST => {
-			match path_base {
-				"peers" => { serve_post_peers(&request.headers, &request.body)?; },
-				_ => return_not_found(&mut buffer)?,
-			}
+		(crate::http::HttpMethod::GET, "peers") => serve_get_peers(&mut buffer, &request),
+		(crate::http::HttpMethod::GET, "peer_files") => serve_get_peer_files(&mut buffer),
+		(crate::http::HttpMethod::POST, "peers") => serve_post_peers(&mut buffer, &request),
+		(crate::http::HttpMethod::POST, "upload") => serve_post_upload(&mut buffer, &request, &mut body_reader),
+		(crate::http::HttpMethod::POST, "admin") => serve_post_admin(&mut buffer, &request, path_iter.next().unwrap_or("")),
+		(_, path_base) => match allowed_methods(path_base) {
+			Some(allowed) => Err(RouteError::MethodNotAllowed(allowed).into()),
+			None => Err(RouteError::NotFound(format!("{} does not exist", request.route)).into()),
 		},
-		_ => return_not_found(&mut buffer)?
 	};
 
+	if let Err(e) = route_result {
+		// NOTE once part of a response went out, dropping the connection is
+		// the only way left to tell the client it is incomplete
+		if buffer.has_sent() { return Err(e); }
+		buffer.clear();
+
+		let route_error = match e.downcast::<RouteError>() {
+			Ok(route_error) => route_error,
+			Err(e) => {
+				println!("\rError: {} {} failed -> {}", request.method.as_str(), request.route, e);
+				RouteError::Internal
+			}
+		};
+		return_route_error(&mut buffer, &route_error, request.prefers_json())?;
+	}
 
 	buffer.flush()?;
-	client.shutdown(std::net::Shutdown::Both)?;
 
-	return Ok(());
+	// NOTE a body the route did not finish reading leaves no way to find
+	// where the next request starts
+	if !keep_alive || !body_reader.is_finished() { return Ok(false); }
+	*pipelined = body_reader.into_leftover();
+
+	return Ok(true);
 }
 
+
+#[cfg(test)]
+mod tests {
+	use super::RouteError;
+
+	#[test]
+	fn test_route_error_responses() {
+		let mut output = Vec::<u8>::new();
+		super::return_route_error(&mut output, &RouteError::NotFound("no <file> here".to_owned()), true).unwrap();
+		let output = String::from_utf8(output).unwrap();
+		let (head, body) = output.split_once("\r\n\r\n").unwrap();
+		assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+		assert!(head.contains("Content-Type: application/json"));
+		let json = crate::json::JsonValue::parse(body).unwrap();
+		let error = json.get("error").unwrap();
+		assert_eq!(error.get("status").and_then(crate::json::JsonValue::as_u64), Some(404));
+		assert_eq!(error.get("message").and_then(crate::json::JsonValue::as_str), Some("no <file> here"));
+
+		let mut output = Vec::<u8>::new();
+		super::return_route_error(&mut output, &RouteError::MethodNotAllowed("POST"), false).unwrap();
+		let output = String::from_utf8(output).unwrap();
+		assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+		assert!(output.contains("Allow: POST\r\n"));
+		assert!(output.contains("Content-Type: text/html"));
+
+		let mut output = Vec::<u8>::new();
+		super::return_route_error(&mut output, &RouteError::BadRequest("<script>".to_owned()), false).unwrap();
+		let output = String::from_utf8(output).unwrap();
+		assert!(output.contains("<p>&lt;script&gt;</p>"));
+	}
+
+	#[test]
+	fn test_routes_without_a_file() {
+		let request = |route| crate::http::HttpRequest {
+			protocol_version: "HTTP/1.1",
+			method: crate::http::HttpMethod::GET,
+			route,
+			query_params: "",
+			headers: vec![],
+			body: b"",
+		};
+		let error = super::serve_get_view(&mut Vec::<u8>::new(), &request("/view")).unwrap_err();
+		assert!(matches!(error.downcast_ref::<RouteError>(), Some(RouteError::NotFound(_))));
+		let error = super::serve_get_file(&mut Vec::<u8>::new(), &request("/file")).unwrap_err();
+		assert!(matches!(error.downcast_ref::<RouteError>(), Some(RouteError::NotFound(_))));
+		let error = super::serve_get_file(&mut Vec::<u8>::new(), &request("/file/%zz")).unwrap_err();
+		assert!(matches!(error.downcast_ref::<RouteError>(), Some(RouteError::BadRequest(_))));
+	}
+
+	#[test]
+	fn test_view() {
+		let directory = std::env::temp_dir().join(format!("localshare-view-{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		std::fs::write(directory.join("notes.txt"), "if a < b\nthen c & d\n").unwrap();
+		std::fs::write(directory.join("cat.png"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+		let template = crate::globals::parse_template(&crate::config::Config::default(), "view.html").unwrap();
+		let view = |name: &str, filepath: &str| {
+			let entry = crate::globals::FileEntry::open(name, directory.join(name), crate::content::FileAccess::Lazy).unwrap();
+			let mut output = Vec::<u8>::new();
+			super::write_view(&mut output, &template, filepath, &entry.content.reader().unwrap()).unwrap();
+			return String::from_utf8(output).unwrap();
+		};
+
+		// NOTE every line is an item of a numbered list, escaped as html
+		let page = view("notes.txt", "notes.txt");
+		assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(page.contains("<ol class=\"lines\">"));
+		assert!(page.contains("<li>if a &lt; b</li>"));
+		assert!(page.contains("<li>then c &amp; d</li>"));
+		assert!(!page.contains("<img"));
+
+		let page = view("cat.png", "pets/my cat.png");
+		assert!(page.contains("<img src=\"/file/pets/my%20cat.png?disposition=inline\" alt=\"my cat.png\">"));
+		assert!(!page.contains("<ol class=\"lines\">"));
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn test_proxy_target() {
+		let added = "192.0.2.1:8000".parse().unwrap();
+		let discovered = "192.0.2.2:8000".parse().unwrap();
+		let peers = [
+			crate::globals::Peer::added(added),
+			crate::globals::Peer { origin: crate::globals::PeerOrigin::Discovered, ..crate::globals::Peer::added(discovered) },
+		];
+		assert!(super::check_proxy_target(&peers, added).is_ok());
+		for addr in [discovered, "127.0.0.1:22".parse().unwrap()] {
+			let error = super::check_proxy_target(&peers, addr).unwrap_err();
+			assert!(matches!(error.downcast_ref::<RouteError>(), Some(RouteError::Forbidden(_))));
+		}
+	}
+
+	#[test]
+	fn test_forward_to_peer() {
+		let (addr, handle) = crate::http::client::tests::serve(vec![
+			b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-4/10\r\nContent-Length: 5\r\nConnection: keep-alive\r\n\r\nhello",
+			b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n",
+			b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nuntil close",
+		]);
+		let request = crate::http::HttpRequest::parse(b"GET /file/notes.txt?source=x HTTP/1.1\r\nRange: bytes=0-4", b"").unwrap();
+		let query = crate::url::Query::parse("source=x&disposition=inline").unwrap();
+		let options = crate::http::client::ClientOptions::default();
+		let forward = || {
+			let mut output = Vec::<u8>::new();
+			super::forward_to_peer(&mut output, &request, addr, &query, &options).unwrap();
+			return String::from_utf8(output).unwrap();
+		};
+
+		assert_eq!(forward(), "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-4/10\r\nContent-Length: 5\r\n\r\nhello");
+		// NOTE bodies without a length are passed on chunked, as they arrive
+		let output = forward();
+		assert!(output.starts_with("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"));
+		assert!(output.ends_with("\r\n0\r\n\r\n"));
+		let output = forward();
+		assert!(output.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n"));
+		assert!(output.ends_with("b\r\nuntil close\r\n0\r\n\r\n"));
+
+		let requests = handle.join().unwrap();
+		assert!(requests[0].starts_with("GET /file/notes.txt?disposition=inline HTTP/1.1\r\n"));
+		assert!(requests[0].contains("Range: bytes=0-4\r\n"));
+	}
+
+	#[test]
+	fn test_forward_to_peer_errors() {
+		let request = crate::http::HttpRequest::parse(b"GET /file/notes.txt HTTP/1.1", b"").unwrap();
+		let query = crate::url::Query::default();
+		let options = crate::http::client::ClientOptions {
+			read_timeout: std::time::Duration::from_millis(200),
+			..Default::default()
+		};
+
+		// NOTE nothing listens on a port that was just released
+		let refused = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let error = super::forward_to_peer(&mut Vec::new(), &request, refused, &query, &options).unwrap_err();
+		assert!(matches!(error.downcast_ref::<RouteError>(), Some(RouteError::BadGateway(_))));
+
+		// NOTE a listener that never accepts, connecting works but nothing answers
+		let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let error = super::forward_to_peer(&mut Vec::new(), &request, silent.local_addr().unwrap(), &query, &options).unwrap_err();
+		assert!(matches!(error.downcast_ref::<RouteError>(), Some(RouteError::GatewayTimeout(_))));
+	}
+}
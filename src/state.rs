@@ -0,0 +1,288 @@
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::json::JsonValue;
+
+
+pub const STATE_VERSION: u64 = 1;
+
+#[derive(Debug, PartialEq)]
+pub struct FileState {
+	pub name: String,
+	pub path: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FolderState {
+	pub name: String,
+	pub path: PathBuf,
+	pub include: Vec<String>,
+	pub exclude: Vec<String>,
+}
+
+// everything the user added at runtime, what gets saved between sessions
+#[derive(Debug, Default, PartialEq)]
+pub struct State {
+	pub files: Vec<FileState>,
+	pub folders: Vec<FolderState>,
+	pub playlists: Vec<PathBuf>,
+	pub peers: Vec<std::net::SocketAddr>,
+}
+
+fn path_value(path: &Path) -> JsonValue {
+	if path.to_str().is_none() {
+		println!("\rWARN: {} is not valid utf-8 and will not be saved exactly", path.display());
+	}
+	return JsonValue::from(path.to_string_lossy().into_owned());
+}
+
+// NOTE a missing list is empty, but one of the wrong type is an error, as
+// reading it as empty would drop it from the state at the next save
+fn list<'a>(value: Option<&'a JsonValue>, what: &str) -> Result<&'a [JsonValue]> {
+	return match value {
+		Some(value) => value.as_array().ok_or(anyhow!("{} should be a list", what)),
+		None => Ok(&[]),
+	};
+}
+
+fn string_list(value: Option<&JsonValue>, what: &str) -> Result<Vec<String>> {
+	return list(value, what)?.iter()
+		.map(|item| item.as_str().map(String::from).ok_or(anyhow!("{} should only hold strings", what)))
+		.collect();
+}
+
+impl State {
+	pub fn to_json(&self) -> String {
+		let files = self.files.iter().map(|file| JsonValue::Object(vec![
+			("name".to_owned(), JsonValue::from(file.name.as_str())),
+			("path".to_owned(), path_value(&file.path)),
+		])).collect();
+		let folders = self.folders.iter().map(|folder| JsonValue::Object(vec![
+			("name".to_owned(), JsonValue::from(folder.name.as_str())),
+			("path".to_owned(), path_value(&folder.path)),
+			("include".to_owned(), JsonValue::Array(folder.include.iter().map(|glob| JsonValue::from(glob.as_str())).collect())),
+			("exclude".to_owned(), JsonValue::Array(folder.exclude.iter().map(|glob| JsonValue::from(glob.as_str())).collect())),
+		])).collect();
+
+		let state = JsonValue::Object(vec![
+			("version".to_owned(), JsonValue::from(STATE_VERSION)),
+			("files".to_owned(), JsonValue::Array(files)),
+			("folders".to_owned(), JsonValue::Array(folders)),
+			("playlists".to_owned(), JsonValue::Array(self.playlists.iter().map(|path| path_value(path)).collect())),
+			("peers".to_owned(), JsonValue::Array(self.peers.iter().map(|peer| JsonValue::from(peer.to_string())).collect())),
+		]);
+		let mut json = state.to_json_string(true);
+		json.push('\n');
+		return json;
+	}
+
+	pub fn from_json(source: &str) -> Result<Self> {
+		let value = JsonValue::parse(source)?;
+		match value.get("version").and_then(JsonValue::as_u64) {
+			Some(STATE_VERSION) => {},
+			Some(version) => bail!("unsupported state version {}, this build reads version {}", version, STATE_VERSION),
+			None => bail!("state file has no version"),
+		}
+
+		let mut state = State::default();
+		for file in list(value.get("files"), "files")? {
+			let path = file.get("path").and_then(JsonValue::as_str).ok_or(anyhow!("file without a path"))?;
+			state.files.push(FileState {
+				name: file.get("name").and_then(JsonValue::as_str).unwrap_or(path).to_owned(),
+				path: PathBuf::from(path),
+			});
+		}
+		for folder in list(value.get("folders"), "folders")? {
+			let path = folder.get("path").and_then(JsonValue::as_str).ok_or(anyhow!("folder without a path"))?;
+			let name = match folder.get("name").and_then(JsonValue::as_str) {
+				Some(name) => name.to_owned(),
+				None => Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+			};
+			state.folders.push(FolderState {
+				name,
+				path: PathBuf::from(path),
+				include: string_list(folder.get("include"), "include")?,
+				exclude: string_list(folder.get("exclude"), "exclude")?,
+			});
+		}
+		for playlist in string_list(value.get("playlists"), "playlists")? {
+			state.playlists.push(PathBuf::from(playlist));
+		}
+		for peer in string_list(value.get("peers"), "peers")? {
+			state.peers.push(peer.parse().map_err(|e| anyhow!("invalid peer {} -> {}", peer, e))?);
+		}
+
+		return Ok(state);
+	}
+
+	// reads the entries.txt/playlists.txt files of earlier versions, entry
+	// lines are a path optionally followed by tab separated `=alias`,
+	// `+include` and `-exclude` fields
+	pub fn from_legacy(entries: &str, playlists: &str) -> Self {
+		let mut state = State::default();
+		for line in entries.lines() {
+			if line == "" { continue; }
+			let mut fields = line.split('\t');
+			let path = PathBuf::from(fields.next().unwrap_or(line));
+
+			let mut alias: Option<&str> = None;
+			let mut include = Vec::new();
+			let mut exclude = Vec::new();
+			for field in fields {
+				match field.split_at_checked(1) {
+					Some(("=", name)) => alias = Some(name),
+					Some(("+", glob)) => include.push(glob.to_owned()),
+					Some(("-", glob)) => exclude.push(glob.to_owned()),
+					_ => println!("\rWARN: ignoring unknown field {} of entry {}", field, path.display()),
+				}
+			}
+
+			if path.is_dir() {
+				let name = alias.map(String::from).unwrap_or_else(|| {
+					path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+				});
+				state.folders.push(FolderState { name, path, include, exclude });
+			}else {
+				let name = alias.map(String::from).unwrap_or_else(|| path.to_string_lossy().into_owned());
+				state.files.push(FileState { name, path });
+			}
+		}
+		for line in playlists.lines() {
+			if line == "" { continue; }
+			state.playlists.push(PathBuf::from(line));
+		}
+		return state;
+	}
+
+	// loads the state file, importing the legacy text files when it does
+	// not exist yet
+	pub fn load(config: &crate::config::Config) -> Result<Self> {
+		match std::fs::read_to_string(&config.state_file) {
+			Ok(source) => {
+				return State::from_json(&source)
+					.map_err(|e| anyhow!("{}: {}", config.state_file.display(), e));
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+			Err(e) => bail!("failed to read {} -> {}", config.state_file.display(), e),
+		}
+
+		let read_legacy = |path: &Path| -> String {
+			match std::fs::read(path) {
+				Ok(bytes) => {
+					// NOTE older versions wrote whatever bytes the paths had
+					if std::str::from_utf8(&bytes).is_err() {
+						println!("\rWARN: {} is not valid utf-8, invalid characters are replaced", path.display());
+					}
+					String::from_utf8_lossy(&bytes).into_owned()
+				},
+				Err(_) => String::new(),
+			}
+		};
+		let entries = read_legacy(&config.entries_file);
+		let playlists = read_legacy(&config.playlists_file);
+		if entries.is_empty() && playlists.is_empty() { return Ok(State::default()); }
+
+		println!(
+			"\rINFO: importing {} and {} into {}",
+			config.entries_file.display(), config.playlists_file.display(), config.state_file.display()
+		);
+		return Ok(State::from_legacy(&entries, &playlists));
+	}
+}
+
+// NOTE the new contents go to a temporary file in the same directory which
+// then replaces the old file in one rename, so a crash leaves either the
+// old or the new state and never half of one
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+	use std::io::Write;
+
+	let directory = match path.parent() {
+		Some(parent) if parent != Path::new("") => parent,
+		_ => Path::new("."),
+	};
+	let filename = path.file_name().ok_or(anyhow!("{} is not a file path", path.display()))?;
+	let mut temp_name = std::ffi::OsString::from(".");
+	temp_name.push(filename);
+	temp_name.push(format!(".{}.tmp", std::process::id()));
+	let temp_path = directory.join(temp_name);
+
+	let result = (|| -> Result<()> {
+		let mut file = std::fs::File::create(&temp_path)?;
+		file.write_all(contents)?;
+		file.sync_all()?;
+		std::fs::rename(&temp_path, path)?;
+		// NOTE makes the rename itself durable
+		std::fs::File::open(directory)?.sync_all()?;
+		return Ok(());
+	})();
+	if result.is_err() { let _ = std::fs::remove_file(&temp_path); }
+
+	return result;
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::{FileState, FolderState, State};
+
+	#[test]
+	fn test_state_round_trip() {
+		let state = State {
+			files: vec![
+				FileState { name: "notes.txt".into(), path: "/home/user/notes \"draft\".txt".into() },
+				FileState { name: "ünïcode.txt".into(), path: "/srv/ünïcode.txt".into() },
+			],
+			folders: vec![FolderState {
+				name: "site".into(), path: "/srv/site".into(),
+				include: vec!["*.html".into()], exclude: vec!["target".into()],
+			}],
+			playlists: vec!["/music/road trip".into()],
+			peers: vec!["192.168.1.20:8000".parse().unwrap(), "[::1]:8001".parse().unwrap()],
+		};
+		assert_eq!(State::from_json(&state.to_json()).unwrap(), state);
+
+		assert!(State::from_json("{\"version\": 99}").is_err());
+		assert!(State::from_json("{\"files\": []}").is_err());
+		assert!(State::from_json("{\"version\": 1, \"peers\": [\"nowhere\"]}").is_err());
+		assert!(State::from_json("{\"version\": 1, \"files\": {\"path\": \"a.txt\"}}").is_err());
+		assert!(State::from_json("{\"version\": 1, \"folders\": \"music\"}").is_err());
+		assert_eq!(State::from_json("{\"version\": 1}").unwrap(), State::default());
+	}
+
+	#[test]
+	fn test_legacy_import() {
+		let directory = std::env::temp_dir();
+		let entries = format!(
+			"/srv/a.txt\n/srv/b.txt\t=b\n{}\t=tmp\t+*.log\t-cache\n",
+			directory.display()
+		);
+		let state = State::from_legacy(&entries, "/music/one\n\n/music/two\n");
+
+		assert_eq!(state.files, vec![
+			FileState { name: "/srv/a.txt".into(), path: "/srv/a.txt".into() },
+			FileState { name: "b".into(), path: "/srv/b.txt".into() },
+		]);
+		assert_eq!(state.folders, vec![FolderState {
+			name: "tmp".into(), path: directory,
+			include: vec!["*.log".into()], exclude: vec!["cache".into()],
+		}]);
+		assert_eq!(state.playlists.len(), 2);
+	}
+
+	#[test]
+	fn test_write_atomically() {
+		let path = std::env::temp_dir().join(format!("localshare-state-{}.json", std::process::id()));
+		super::write_atomically(&path, b"first").unwrap();
+		super::write_atomically(&path, b"second").unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"second");
+
+		let leftovers = std::fs::read_dir(std::env::temp_dir()).unwrap()
+			.filter_map(|entry| entry.ok())
+			.filter(|entry| entry.file_name().to_string_lossy().starts_with(&format!(".localshare-state-{}", std::process::id())))
+			.count();
+		assert_eq!(leftovers, 0);
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,158 @@
+
+use anyhow::Result;
+
+
+fn decode_bytes(source: &str, plus_as_space: bool) -> Option<Vec<u8>> {
+	let bytes = source.as_bytes();
+	let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+	let mut index = 0;
+	while index < bytes.len() {
+		match bytes[index] {
+			b'%' => {
+				let hex = bytes.get(index + 1..index + 3)?;
+				if !hex.iter().all(u8::is_ascii_hexdigit) { return None; }
+				decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+				index += 3;
+			},
+			b'+' if plus_as_space => {
+				decoded.push(b' ');
+				index += 1;
+			},
+			byte => {
+				decoded.push(byte);
+				index += 1;
+			}
+		}
+	}
+	return Some(decoded);
+}
+
+fn decode(source: &str, plus_as_space: bool) -> Option<String> {
+	return String::from_utf8(decode_bytes(source, plus_as_space)?).ok();
+}
+
+// decodes the %XX escapes of a path, None when they are malformed or
+// decode to invalid utf-8. a + stays a + in paths
+pub fn percent_decode(source: &str) -> Option<String> {
+	return decode(source, false);
+}
+
+// like percent_decode, for values that are not necessarily utf-8
+pub fn percent_decode_bytes(source: &str) -> Option<Vec<u8>> {
+	return decode_bytes(source, false);
+}
+
+// escapes everything but unreserved characters, for a single path segment
+// or a query key or value. templates escape the same way with `%key:url%`
+// and `%key:path%`, which keeps the / separators
+pub fn encode_component(source: &str) -> String {
+	return crate::http::Escape::UrlComponent.apply(source);
+}
+
+// a parsed query string, keeping every pair in order so a key can be given
+// more than once
+#[derive(Debug, Default, PartialEq)]
+pub struct Query {
+	pairs: Vec<(String, String)>,
+}
+
+impl Query {
+	// NOTE a key without = gets an empty value, empty pairs (`a=1&&b=2`)
+	// are skipped
+	pub fn parse(source: &str) -> Result<Self> {
+		let mut pairs = Vec::new();
+		for pair in source.split('&').filter(|pair| *pair != "") {
+			let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+			let decode_part = |part: &str| decode(part, true)
+				.ok_or(anyhow!("malformed percent encoding in query parameter {}", pair));
+			pairs.push((decode_part(key)?, decode_part(value)?));
+		}
+		return Ok(Self { pairs });
+	}
+
+	// the first value given for key
+	pub fn get(&self, key: &str) -> Option<&str> {
+		return self.pairs.iter()
+			.find(|(pair_key, _value)| pair_key == key)
+			.map(|(_key, value)| value.as_str());
+	}
+
+	pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+		return self.pairs.iter()
+			.filter(move |(pair_key, _value)| pair_key == key)
+			.map(|(_key, value)| value.as_str());
+	}
+
+	// the first value given for key parsed as T, an error when it does not
+	// parse
+	pub fn get_parsed<T: std::str::FromStr>(&self, key: &str) -> Result<Option<T>>
+	where T::Err: std::fmt::Display {
+		return match self.get(key) {
+			Some(value) => value.parse::<T>()
+				.map(Some)
+				.map_err(|e| anyhow!("invalid value {} for query parameter {} -> {}", value, key, e)),
+			None => Ok(None),
+		};
+	}
+}
+
+impl Query {
+	// a copy without any of the values given for key
+	pub fn without(&self, key: &str) -> Self {
+		return Self {
+			pairs: self.pairs.iter().filter(|(pair_key, _value)| pair_key != key).cloned().collect(),
+		};
+	}
+}
+
+impl std::fmt::Display for Query {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for (index, (key, value)) in self.pairs.iter().enumerate() {
+			if index > 0 { write!(f, "&")?; }
+			write!(f, "{}={}", encode_component(key), encode_component(value))?;
+		}
+		return Ok(());
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::Query;
+
+	#[test]
+	fn test_percent_encoding() {
+		let names = ["/home/user/my song.flac", "ünïcode/日本.txt", "a+b&c=d?e#f%g", "100% done"];
+		for name in names {
+			assert_eq!(super::percent_decode(&crate::http::Escape::UrlPath.apply(name)).as_deref(), Some(name));
+			assert_eq!(super::percent_decode(&super::encode_component(name)).as_deref(), Some(name));
+		}
+		assert_eq!(super::encode_component("/a b/c"), "%2Fa%20b%2Fc");
+
+		assert_eq!(super::percent_decode("a+b%20c").as_deref(), Some("a+b c"));
+		assert_eq!(super::percent_decode("%zz"), None);
+		assert_eq!(super::percent_decode("%4"), None);
+		assert_eq!(super::percent_decode("%+1"), None);
+		assert_eq!(super::percent_decode("%FF"), None);
+	}
+
+	#[test]
+	fn test_query() {
+		let query = Query::parse("playlist=road+trip&song_number=3&tag=a&tag=b%26c&flag&&empty=").unwrap();
+		assert_eq!(query.get("playlist"), Some("road trip"));
+		assert_eq!(query.get_parsed::<u32>("song_number").unwrap(), Some(3));
+		assert_eq!(query.get_parsed::<u32>("missing").unwrap(), None);
+		assert!(query.get_parsed::<u32>("playlist").is_err());
+		assert_eq!(query.get_all("tag").collect::<Vec<&str>>(), ["a", "b&c"]);
+		assert_eq!(query.get("flag"), Some(""));
+		assert_eq!(query.get("empty"), Some(""));
+		assert_eq!(query.without("tag").to_string(), "playlist=road%20trip&song_number=3&flag=&empty=");
+
+		assert!(Query::parse("name=%E0%A4").is_err());
+		assert_eq!(Query::parse("").unwrap(), Query::default());
+
+		let parsed = Query::parse("playlist=rock+%26+roll/1&song_number=2").unwrap();
+		assert_eq!(parsed.to_string(), "playlist=rock%20%26%20roll%2F1&song_number=2");
+		assert_eq!(Query::parse(&parsed.to_string()).unwrap(), parsed);
+	}
+}
@@ -0,0 +1,404 @@
+
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	path::PathBuf,
+	sync::Arc,
+	time::{Duration, Instant, SystemTime},
+};
+
+use crate::globals::GLOBALS;
+
+
+// NOTE how often inotify events are collected, a burst of them (like a
+// playlist being downloaded) turns into a single rescan per tick
+const WATCH_TICK: Duration = Duration::from_millis(500);
+// NOTE a change touching more files than this is summarized
+const MAX_LOGGED_CHANGES: usize = 20;
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum RootKind {
+	Playlist,
+	Folder,
+}
+
+impl RootKind {
+	fn as_str(&self) -> &'static str {
+		match self { RootKind::Playlist => "playlist", RootKind::Folder => "folder" }
+	}
+}
+
+// a watched directory, identified by its kind and path since names can
+// change while it is watched
+type RootKey = (RootKind, PathBuf);
+
+struct Root {
+	kind: RootKind,
+	name: Arc<str>,
+	path: PathBuf,
+}
+
+impl Root {
+	fn key(&self) -> RootKey { return (self.kind, self.path.clone()); }
+}
+
+fn current_roots() -> Vec<Root> {
+	let mut roots = Vec::new();
+	for playlist in GLOBALS.read_playlists().iter() {
+		roots.push(Root { kind: RootKind::Playlist, name: playlist.name.clone(), path: PathBuf::from(playlist.directory.as_ref()) });
+	}
+	for directory in GLOBALS.read_shared_directories().iter() {
+		roots.push(Root { kind: RootKind::Folder, name: directory.name.clone(), path: directory.root.clone() });
+	}
+	return roots;
+}
+
+// size and modification time of every file under a root, by path
+type Listing = BTreeMap<String, (u64, Option<SystemTime>)>;
+
+// lists a root, along with the directories to watch for it
+fn scan(root: &Root) -> anyhow::Result<(Listing, Vec<PathBuf>)> {
+	let mut listing = Listing::new();
+	match root.kind {
+		RootKind::Playlist => {
+			for path in crate::globals::directory_files(&root.path)? {
+				let metadata = match std::fs::metadata(&path) { Ok(metadata) => metadata, Err(_) => continue };
+				listing.insert(path.to_string_lossy().into_owned(), (metadata.len(), metadata.modified().ok()));
+			}
+			return Ok((listing, vec![root.path.clone()]));
+		},
+		RootKind::Folder => {
+			let directories = GLOBALS.read_shared_directories();
+			let directory = directories.iter().find(|directory| directory.root == root.path)
+				.ok_or(anyhow!("{} is no longer shared", root.path.display()))?;
+			if !directory.root.is_dir() { bail!("{} is not a directory", directory.root.display()); }
+
+			let (files, watched) = directory.walk();
+			for (relative_path, entry) in files {
+				listing.insert(relative_path, (entry.size, entry.modified));
+			}
+			return Ok((listing, watched));
+		},
+	}
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Changes {
+	added: Vec<String>,
+	removed: Vec<String>,
+	changed: Vec<String>,
+}
+
+impl Changes {
+	fn between(old: &Listing, new: &Listing) -> Self {
+		let mut changes = Changes::default();
+		for (path, metadata) in new.iter() {
+			match old.get(path) {
+				None => changes.added.push(path.clone()),
+				Some(old_metadata) if old_metadata != metadata => changes.changed.push(path.clone()),
+				Some(_) => {},
+			}
+		}
+		changes.removed = old.keys().filter(|path| !new.contains_key(*path)).cloned().collect();
+		return changes;
+	}
+
+	fn is_empty(&self) -> bool {
+		return self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty();
+	}
+
+	fn log(&self, root: &Root) {
+		let lines = self.added.iter().map(|path| ('+', path))
+			.chain(self.removed.iter().map(|path| ('-', path)))
+			.chain(self.changed.iter().map(|path| ('~', path)))
+			.collect::<Vec<(char, &String)>>();
+		for (sign, path) in lines.iter().take(MAX_LOGGED_CHANGES) {
+			println!("\rINFO: {} {}: {} {}", root.kind.as_str(), root.name, sign, path);
+		}
+		if lines.len() > MAX_LOGGED_CHANGES {
+			println!(
+				"\rINFO: {} {}: and {} more changes ({} added, {} removed, {} changed in total)",
+				root.kind.as_str(), root.name, lines.len() - MAX_LOGGED_CHANGES,
+				self.added.len(), self.removed.len(), self.changed.len()
+			);
+		}
+	}
+}
+
+// playlists are loaded snapshots and have to be refreshed, shared folders
+// are read from disk anyway so only their changes are logged
+fn apply(root: &Root, listing: &Listing) {
+	if root.kind == RootKind::Playlist {
+		let paths = listing.keys().map(PathBuf::from).collect::<Vec<PathBuf>>();
+		GLOBALS.refresh_playlist(&root.name, &paths);
+	}
+}
+
+// keeps the playlists (and the logged view of shared folders) in step with
+// the disk, woken by inotify where available and polling every interval
+// otherwise
+pub fn watch_directories(interval: Duration) -> anyhow::Result<()> {
+	let mut inotify = match inotify::Inotify::new() {
+		Ok(inotify) => Some(inotify),
+		Err(e) => {
+			println!("\rWARN: inotify is unavailable, polling for changes every {}s -> {}", interval.as_secs(), e);
+			None
+		}
+	};
+
+	let mut listings = HashMap::<RootKey, Listing>::new();
+	// NOTE one directory can belong to several roots
+	let mut watches = HashMap::<i32, HashSet<RootKey>>::new();
+	// NOTE roots that could not (all) be watched are polled instead
+	let mut polled = HashSet::<RootKey>::new();
+	let mut failing = HashSet::<RootKey>::new();
+	let mut last_poll = Instant::now();
+
+	loop {
+		std::thread::sleep(WATCH_TICK);
+		let roots = current_roots();
+		let keys = roots.iter().map(Root::key).collect::<HashSet<RootKey>>();
+
+		listings.retain(|key, _listing| keys.contains(key));
+		polled.retain(|key| keys.contains(key));
+		failing.retain(|key| keys.contains(key));
+		watches.retain(|wd, watchers| {
+			watchers.retain(|key| keys.contains(key));
+			if !watchers.is_empty() { return true; }
+			if let Some(inotify) = &inotify { inotify.remove_watch(*wd); }
+			return false;
+		});
+
+		let mut dirty = HashSet::<RootKey>::new();
+		if let Some(inotify) = &mut inotify {
+			for event in inotify.read_events() {
+				match event {
+					inotify::Event::Overflow => dirty.extend(keys.iter().cloned()),
+					inotify::Event::Changed(wd) => if let Some(watchers) = watches.get(&wd) { dirty.extend(watchers.iter().cloned()); },
+					inotify::Event::Removed(wd) => if let Some(watchers) = watches.remove(&wd) { dirty.extend(watchers); },
+				}
+			}
+		}
+		if last_poll.elapsed() >= interval {
+			last_poll = Instant::now();
+			if inotify.is_none() { dirty.extend(keys.iter().cloned()); }
+			else { dirty.extend(polled.iter().cloned()); }
+		}
+
+		for root in roots.iter() {
+			let key = root.key();
+			let is_new = !listings.contains_key(&key);
+			if !is_new && !dirty.contains(&key) { continue; }
+
+			let (listing, directories) = match scan(root) {
+				Ok(scanned) => scanned,
+				Err(e) => {
+					if failing.insert(key.clone()) {
+						println!("\rWARN: unable to rescan {} {} -> {}", root.kind.as_str(), root.name, e);
+					}
+					// NOTE keeps checking until it comes back
+					polled.insert(key);
+					continue;
+				}
+			};
+			failing.remove(&key);
+
+			if let Some(inotify) = &inotify {
+				let mut all_watched = true;
+				for directory in directories.iter() {
+					match inotify.add_watch(directory) {
+						Ok(wd) => { watches.entry(wd).or_default().insert(key.clone()); },
+						Err(e) => {
+							if all_watched && !polled.contains(&key) {
+								println!("\rWARN: unable to watch {}, polling it instead -> {}", directory.display(), e);
+							}
+							all_watched = false;
+						}
+					}
+				}
+				if all_watched { polled.remove(&key); } else { polled.insert(key.clone()); }
+			}
+
+			// NOTE the first scan only sets the baseline, the playlist was just
+			// loaded but may have missed files created since
+			let changes = match listings.get(&key) {
+				Some(old) => Changes::between(old, &listing),
+				None => Changes::default(),
+			};
+			if is_new || !changes.added.is_empty() || !changes.removed.is_empty() { apply(root, &listing); }
+			if !changes.is_empty() { changes.log(root); }
+			listings.insert(key, listing);
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+	use std::{io::Read, os::fd::FromRawFd, os::unix::ffi::OsStrExt};
+
+	use anyhow::Result;
+
+	// NOTE declared by hand, the crate does not depend on libc
+	unsafe extern "C" {
+		fn inotify_init1(flags: i32) -> i32;
+		fn inotify_add_watch(fd: i32, pathname: *const std::ffi::c_char, mask: u32) -> i32;
+		fn inotify_rm_watch(fd: i32, wd: i32) -> i32;
+	}
+
+	const IN_NONBLOCK: i32 = 0o4000;
+	const IN_CLOEXEC: i32 = 0o2000000;
+
+	const IN_CLOSE_WRITE: u32 = 0x8;
+	const IN_MOVED_FROM: u32 = 0x40;
+	const IN_MOVED_TO: u32 = 0x80;
+	const IN_CREATE: u32 = 0x100;
+	const IN_DELETE: u32 = 0x200;
+	const IN_DELETE_SELF: u32 = 0x400;
+	const IN_MOVE_SELF: u32 = 0x800;
+	const IN_Q_OVERFLOW: u32 = 0x4000;
+	const IN_IGNORED: u32 = 0x8000;
+
+	const WATCH_MASK: u32 = IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO
+		| IN_CREATE | IN_DELETE | IN_DELETE_SELF | IN_MOVE_SELF;
+	// NOTE wd, mask, cookie and the length of the name that follows
+	const EVENT_HEADER_SIZE: usize = 16;
+
+	#[derive(Debug, PartialEq)]
+	pub enum Event {
+		Changed(i32),
+		// the watch is gone, along with its directory
+		Removed(i32),
+		// events were dropped, everything has to be rescanned
+		Overflow,
+	}
+
+	pub struct Inotify {
+		fd: i32,
+		// NOTE owns the descriptor, closing it on drop
+		file: std::fs::File,
+	}
+
+	impl Inotify {
+		pub fn new() -> Result<Self> {
+			let fd = unsafe{ inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+			if fd < 0 { return Err(std::io::Error::last_os_error().into()); }
+			return Ok(Self { fd, file: unsafe{ std::fs::File::from_raw_fd(fd) } });
+		}
+
+		// NOTE watching a directory twice returns the same descriptor
+		pub fn add_watch(&self, path: &std::path::Path) -> Result<i32> {
+			let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+			let wd = unsafe{ inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK) };
+			if wd < 0 { return Err(std::io::Error::last_os_error().into()); }
+			return Ok(wd);
+		}
+
+		pub fn remove_watch(&self, wd: i32) {
+			unsafe{ inotify_rm_watch(self.fd, wd) };
+		}
+
+		// everything that happened since the last call, without blocking
+		pub fn read_events(&mut self) -> Vec<Event> {
+			let mut events = Vec::new();
+			let mut buffer = [0u8; 64 * 1024];
+			loop {
+				let length = match self.file.read(&mut buffer) {
+					Ok(0) => break,
+					Ok(length) => length,
+					Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+					Err(_) => break,
+				};
+				events.extend(parse_events(&buffer[..length]));
+			}
+			return events;
+		}
+	}
+
+	pub fn parse_events(buffer: &[u8]) -> Vec<Event> {
+		let mut events = Vec::new();
+		let mut offset = 0;
+		while offset + EVENT_HEADER_SIZE <= buffer.len() {
+			let field = |index: usize| -> [u8; 4] {
+				return buffer[offset + index * 4..offset + index * 4 + 4].try_into().unwrap();
+			};
+			let wd = i32::from_ne_bytes(field(0));
+			let mask = u32::from_ne_bytes(field(1));
+			let name_length = u32::from_ne_bytes(field(3)) as usize;
+			offset += EVENT_HEADER_SIZE + name_length;
+
+			if mask & IN_Q_OVERFLOW != 0 { events.push(Event::Overflow); }
+			else if mask & IN_IGNORED != 0 { events.push(Event::Removed(wd)); }
+			else { events.push(Event::Changed(wd)); }
+		}
+		return events;
+	}
+}
+
+// NOTE without inotify every root is polled
+#[cfg(not(target_os = "linux"))]
+mod inotify {
+	use anyhow::Result;
+
+	pub enum Event {
+		Changed(i32),
+		Removed(i32),
+		Overflow,
+	}
+
+	pub struct Inotify;
+
+	impl Inotify {
+		pub fn new() -> Result<Self> { bail!("inotify is only available on linux"); }
+		pub fn add_watch(&self, _path: &std::path::Path) -> Result<i32> { bail!("inotify is only available on linux"); }
+		pub fn remove_watch(&self, _wd: i32) {}
+		pub fn read_events(&mut self) -> Vec<Event> { return Vec::new(); }
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::{Changes, Listing};
+
+	#[test]
+	fn test_listing_changes() {
+		let time = |seconds: u64| Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds));
+		let old = Listing::from([
+			("a.flac".to_owned(), (10, time(1))),
+			("b.flac".to_owned(), (20, time(1))),
+			("c.flac".to_owned(), (30, time(1))),
+		]);
+		let new = Listing::from([
+			("a.flac".to_owned(), (10, time(1))),
+			("c.flac".to_owned(), (30, time(2))),
+			("d.flac".to_owned(), (40, time(2))),
+		]);
+
+		assert_eq!(Changes::between(&old, &new), Changes {
+			added: vec!["d.flac".to_owned()],
+			removed: vec!["b.flac".to_owned()],
+			changed: vec!["c.flac".to_owned()],
+		});
+		assert!(Changes::between(&new, &new).is_empty());
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_inotify_events() {
+		use super::inotify::{Event, Inotify};
+
+		let directory = std::env::temp_dir().join(format!("localshare-watch-{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		let mut inotify = Inotify::new().unwrap();
+		let wd = inotify.add_watch(&directory).unwrap();
+		assert_eq!(inotify.add_watch(&directory).unwrap(), wd);
+		assert!(inotify.read_events().is_empty());
+
+		std::fs::write(directory.join("song.flac"), b"flac").unwrap();
+		let events = inotify.read_events();
+		assert!(!events.is_empty());
+		assert!(events.iter().all(|event| *event == Event::Changed(wd)));
+
+		std::fs::remove_dir_all(&directory).unwrap();
+		assert!(inotify.read_events().contains(&Event::Removed(wd)));
+	}
+}